@@ -1,11 +1,45 @@
-use std::fmt::Display;
+use std::{fmt::Display, time::{Duration, Instant}};
 
 use aes_gcm_siv::Aes256GcmSiv; // Or `Aes128GcmSiv`
-use aes_gcm_siv::aead::{Aead, NewAead, generic_array::GenericArray};
+use aes_gcm_siv::aead::{Aead, NewAead, Payload, generic_array::GenericArray};
 use num::Num;
 use rand_core::OsRng;
-use rsa::{BigUint, PaddingScheme, PublicKey, PublicKeyParts, RsaPrivateKey, RsaPublicKey, errors::Error};
+use rsa::{BigUint, Hash, PaddingScheme, PublicKey, PublicKeyParts, RsaPrivateKey, RsaPublicKey, errors::Error, pkcs8::{self, FromPrivateKey, ToPrivateKey}};
 use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256, Sha512};
+use zeroize::Zeroizing;
+
+mod replay_window;
+pub use replay_window::ReplayWindow;
+mod key_exchange;
+pub use key_exchange::{EphemeralKeyExchange, KeyExchangeError};
+mod noise_handshake;
+pub use noise_handshake::{NoiseHandshake, NoiseHandshakeError};
+mod padding;
+pub use padding::{PADDING_BUCKET_SIZE, pad, unpad};
+mod double_ratchet;
+pub use double_ratchet::{DoubleRatchet, RatchetError, RatchetHandshake, RatchetHeader};
+
+/// Length of the nonce which is prepended to every symmetrically encrypted message
+pub const NONCE_LENGTH: usize = 12;
+/// Length of the authentication tag appended to the ciphertext
+pub const TAG_LENGTH: usize = 16;
+/// Length of the random part of the nonce, the rest is a message counter
+const NONCE_PREFIX_LENGTH: usize = 4;
+/// Amount of bytes encrypted with one key, after which the session should be rekeyed
+const REKEY_AFTER_BYTES: u64 = 256 * 1024 * 1024;
+/// Time after which the session should be rekeyed
+const REKEY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// A 256 bit secret, which is wiped from memory when it's dropped
+pub type SecretKey = Zeroizing<[u8; 32]>;
+
+/// Copy the secret into a container which wipes it when dropped. Panics if it isn't 32 bytes long.
+fn secret_key(secret: &[u8]) -> SecretKey {
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(secret);
+    key
+}
 
 pub struct AsymmetricEncryption{
     public_key: RsaPublicKey,
@@ -24,6 +58,28 @@ impl AsymmetricEncryption {
         }
     }
 
+    /// Load the private key from PKCS#8 PEM, which is encrypted if a passphrase is given
+    pub fn from_pem(pem: &str, passphrase: Option<&str>) -> Result<AsymmetricEncryption, pkcs8::Error> {
+        let secret_key = match passphrase {
+            Some(passphrase) => RsaPrivateKey::from_pkcs8_encrypted_pem(pem, passphrase)?,
+            None => RsaPrivateKey::from_pkcs8_pem(pem)?
+        };
+        let public_key = RsaPublicKey::from(&secret_key);
+
+        Ok(AsymmetricEncryption {
+            secret_key,
+            public_key,
+        })
+    }
+
+    /// Export the private key as PKCS#8 PEM, which is encrypted if a passphrase is given
+    pub fn to_pem(&self, passphrase: Option<&str>) -> Result<Zeroizing<String>, pkcs8::Error> {
+        match passphrase {
+            Some(passphrase) => self.secret_key.to_pkcs8_encrypted_pem(&mut OsRng, passphrase),
+            None => self.secret_key.to_pkcs8_pem()
+        }
+    }
+
     pub fn get_public_key(&self) -> NetworkedPublicKey {
         NetworkedPublicKey {
             n: self.public_key.n().to_str_radix(36),
@@ -31,13 +87,29 @@ impl AsymmetricEncryption {
         }
     }
 
-    pub fn decrypt(&self, data: &[u8]) -> Vec<u8> {
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
         let padding = PaddingScheme::new_oaep::<sha2::Sha256>();
-        let dec_data = self.secret_key.decrypt(padding, &data).expect("Failed to decrypt");
-        dec_data
+        self.secret_key.decrypt(padding, data)
+    }
+
+    /// Sign the SHA-256 hash of the data with the private key
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let digest = Sha256::digest(data);
+        let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+        self.secret_key.sign(padding, &digest).expect("Failed to sign")
+    }
+
+    /// Prove that we own the private key by signing a challenge sent by the server
+    pub fn sign_challenge(&self, challenge: &[u8]) -> Vec<u8> {
+        self.sign(&[CHALLENGE_CONTEXT, challenge].concat())
     }
 }
 
+/// Prepended to signed announce challenges, so the signature can't be reused elsewhere
+const CHALLENGE_CONTEXT: &[u8] = b"p2pthing announce challenge";
+/// Prepended to the hashed keys when calculating a safety number
+const SAFETY_NUMBER_CONTEXT: &[u8] = b"p2pthing safety number";
+
 /// A struct which only contains the public key part of the encryption key.
 /// Therefore being safe to advertise.
 #[derive(Serialize, Debug, Deserialize, Clone, Eq, PartialEq, Hash)]
@@ -48,58 +120,318 @@ pub struct NetworkedPublicKey {
 
 impl NetworkedPublicKey {
     pub fn recreate_my_public_key(&self) -> Result<RsaPublicKey, Error> {
-        let n = BigUint::from_str_radix(&self.n, 36).unwrap();
-        let e = BigUint::from_str_radix(&self.e, 36).unwrap();
+        // The key is received from the network, so it can be anything
+        let n = BigUint::from_str_radix(&self.n, 36).map_err(|_| Error::InvalidModulus)?;
+        let e = BigUint::from_str_radix(&self.e, 36).map_err(|_| Error::InvalidExponent)?;
         RsaPublicKey::new(n, e)
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
-        let public_key = self.recreate_my_public_key().unwrap();
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let public_key = self.recreate_my_public_key()?;
         let padding = PaddingScheme::new_oaep::<sha2::Sha256>();
-        let enc_data = public_key.encrypt(&mut OsRng, padding, &data[..]).expect("Failed to encrypt");
-        enc_data
+        public_key.encrypt(&mut OsRng, padding, data)
+    }
+
+    /// SHA-256 hash of the key, which identifies it
+    fn digest(&self) -> Vec<u8> {
+        Sha256::new()
+        .chain(self.n.as_bytes())
+        .chain(b":")
+        .chain(self.e.as_bytes())
+        .finalize()
+        .to_vec()
+    }
+
+    /// Human readable fingerprint of the key, e.g. `3f2a 9c01 ...`
+    pub fn fingerprint(&self) -> String {
+        self.digest().chunks(2)
+        .map(|c| c.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+
+    /// Check whether the fingerprint belongs to this key, ignoring the case and the separators
+    pub fn matches_fingerprint(&self, fingerprint: &str) -> bool {
+        let normalize = |f: &str| f.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase();
+        normalize(fingerprint) == normalize(&self.fingerprint())
+    }
+
+    /// A number derived from both keys, which is the same on both sides.
+    /// Users can compare it out of band to make sure nobody is impersonating the other.
+    pub fn safety_number(&self, other: &NetworkedPublicKey) -> String {
+        let mut digests = [self.digest(), other.digest()];
+        digests.sort();
+        let hash = Sha512::new()
+        .chain(SAFETY_NUMBER_CONTEXT)
+        .chain(&digests[0])
+        .chain(&digests[1])
+        .finalize();
+
+        // Twelve groups of five digits
+        hash.chunks_exact(5)
+        .map(|c| c.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64) % 100000)
+        .map(|n| format!("{:05}", n))
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+
+    /// Check whether the challenge was signed by the owner of this key
+    pub fn verify_challenge(&self, challenge: &[u8], signature: &[u8]) -> bool {
+        self.verify(&[CHALLENGE_CONTEXT, challenge].concat(), signature)
+    }
+
+    /// Check whether the signature was created by the owner of this key
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let public_key = match self.recreate_my_public_key() {
+            Ok(public_key) => public_key,
+            Err(_) => return false
+        };
+        let digest = Sha256::digest(data);
+        let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+        public_key.verify(padding, &digest, signature).is_ok()
     }
 }
 
 impl Display for NetworkedPublicKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", &self.n[..10])
+        write!(f, "{}", self.n.get(..10).unwrap_or(&self.n))
+    }
+}
+
+#[derive(Debug)]
+pub enum DecryptionError {
+    /// The message is too short to contain a nonce
+    MissingNonce,
+    /// The nonce was generated by us, so the message was reflected back
+    ReflectedMessage,
+    /// The message couldn't be authenticated
+    InvalidCiphertext,
+    /// A message with the same nonce has already been received
+    ReplayedMessage,
+}
+
+impl Display for DecryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecryptionError::MissingNonce => f.write_str("Message is too short to contain a nonce."),
+            DecryptionError::ReflectedMessage => f.write_str("Received a message which was encrypted by us."),
+            DecryptionError::InvalidCiphertext => f.write_str("Failed to authenticate the message."),
+            DecryptionError::ReplayedMessage => f.write_str("Received a message with an already used nonce."),
+        }
     }
 }
 
+/// Symmetric encryption where every message gets a unique nonce.
+///
+/// The nonce consists of a random prefix, which is different for each side of the
+/// connection, and a counter. It is sent in front of the ciphertext, so the receiver
+/// can reject nonces it has already seen.
+///
+/// Only the raw keys are stored, and they are wiped when dropped. The AES key schedule
+/// doesn't support zeroizing, so the cipher is only created for the duration of each call.
 pub struct SymmetricEncryption {
-    pub secret: Vec<u8>,
-    sym_key: Aes256GcmSiv
+    send_key: SecretKey,
+    recv_key: SecretKey,
+    nonce_prefix: [u8; NONCE_PREFIX_LENGTH],
+    next_nonce: u64,
+    replay_window: ReplayWindow,
+    /// Keys agreed on in a rekey we answered, used once the other side starts sending with them
+    staged_keys: Option<(SecretKey, SecretKey)>,
+    /// The receiving key before the last rekey, for messages which were in flight during the switch
+    previous_recv_key: Option<SecretKey>,
+    /// Amount of bytes encrypted with the current sending key
+    encrypted_bytes: u64,
+    /// When the current keys started being used
+    keys_created: Instant,
 }
 
 impl SymmetricEncryption {
     pub fn new() -> SymmetricEncryption {
-        let secret = (0..32).map(|_| { rand::random::<u8>() }).collect::<Vec<_>>();
-        let secret_arr= GenericArray::from_slice(&secret[..]);
-        let sym_key = Aes256GcmSiv::new(secret_arr);
-        SymmetricEncryption {
-            secret,
-            sym_key
-        }
+        let secret = Zeroizing::new(rand::random::<[u8; 32]>());
+        SymmetricEncryption::new_from_secret(&secret[..])
     }
 
     pub fn new_from_secret(secret: &[u8]) -> SymmetricEncryption {
-        let secret_arr= GenericArray::from_slice(&secret[..]);
-        let sym_key = Aes256GcmSiv::new(secret_arr);
+        SymmetricEncryption::new_from_split(secret, secret)
+    }
+
+    /// Use a different key for each direction, like the ones created by a Noise handshake
+    pub fn new_from_split(send_secret: &[u8], recv_secret: &[u8]) -> SymmetricEncryption {
         SymmetricEncryption {
-            secret: secret.to_vec(),
-            sym_key
+            send_key: secret_key(send_secret),
+            recv_key: secret_key(recv_secret),
+            nonce_prefix: rand::random(),
+            next_nonce: 0,
+            replay_window: ReplayWindow::new(),
+            staged_keys: None,
+            previous_recv_key: None,
+            encrypted_bytes: 0,
+            keys_created: Instant::now(),
         }
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
-        let nonce = GenericArray::from_slice(b"123456789123".as_ref());
-        self.sym_key.encrypt(nonce, data).unwrap()
+    /// Whether the keys have been used for too long, or encrypted too much data
+    pub fn needs_rekey(&self) -> bool {
+        self.encrypted_bytes >= REKEY_AFTER_BYTES || self.keys_created.elapsed() >= REKEY_INTERVAL
+    }
+
+    /// Whether we answered a rekey, but the other side hasn't started using the new keys yet
+    pub fn is_rekeying(&self) -> bool {
+        self.staged_keys.is_some()
+    }
+
+    /// Switch to new keys right away. Used by the side which started the rekey, once it was answered.
+    pub fn rekey(&mut self, send_secret: SecretKey, recv_secret: SecretKey) {
+        self.switch_keys(send_secret, recv_secret);
+    }
+
+    /// Keep sending with the current keys until the other side proves it switched to the new ones.
+    /// Used by the side which answered the rekey, since its answer might still be in flight.
+    pub fn stage_rekey(&mut self, send_secret: SecretKey, recv_secret: SecretKey) {
+        self.staged_keys = Some((send_secret, recv_secret));
+    }
+
+    fn switch_keys(&mut self, send_key: SecretKey, recv_key: SecretKey) {
+        self.send_key = send_key;
+        self.previous_recv_key = Some(std::mem::replace(&mut self.recv_key, recv_key));
+        self.staged_keys = None;
+        self.encrypted_bytes = 0;
+        self.keys_created = Instant::now();
+    }
+
+    /// Encrypt the data with the next nonce. The returned data is the nonce followed by the ciphertext.
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        self.encrypt_with_aad(data, &[])
+    }
+
+    /// Encrypt the data, also authenticating the associated data which is sent alongside it
+    pub fn encrypt_with_aad(&mut self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        nonce[..NONCE_PREFIX_LENGTH].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LENGTH..].copy_from_slice(&self.next_nonce.to_be_bytes());
+        self.next_nonce = self.next_nonce.checked_add(1).expect("Ran out of nonces");
+
+        let encrypted = cipher(&self.send_key).encrypt(GenericArray::from_slice(&nonce), Payload {msg: data, aad}).unwrap();
+        self.encrypted_bytes += data.len() as u64;
+        [&nonce[..], &encrypted[..]].concat()
+    }
+
+    /// Decrypt data created by `encrypt` on the other side, rejecting replayed nonces.
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        self.decrypt_with_aad(data, &[])
     }
 
-    pub fn decrypt(&self, data: &[u8]) -> Vec<u8> {
-        let nonce = GenericArray::from_slice(b"123456789123".as_ref());
-        self.sym_key.decrypt(nonce, data).unwrap()
+    /// Decrypt data created by `encrypt_with_aad`, checking that the associated data wasn't modified
+    pub fn decrypt_with_aad(&mut self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        if data.len() < NONCE_LENGTH {
+            return Err(DecryptionError::MissingNonce);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        if nonce[..NONCE_PREFIX_LENGTH] == self.nonce_prefix {
+            return Err(DecryptionError::ReflectedMessage);
+        }
+        let mut counter = [0u8; 8];
+        counter.copy_from_slice(&nonce[NONCE_PREFIX_LENGTH..]);
+        let counter = u64::from_be_bytes(counter);
+        if !self.replay_window.check(counter) {
+            return Err(DecryptionError::ReplayedMessage);
+        }
+
+        let nonce = GenericArray::from_slice(nonce);
+        let payload = || Payload {msg: ciphertext, aad};
+        let decrypted = match cipher(&self.recv_key).decrypt(nonce, payload()) {
+            Ok(decrypted) => decrypted,
+            Err(_) => {
+                // The other side might have switched to the staged keys, or sent this before switching
+                let staged = self.staged_keys.as_ref().and_then(|(_, recv_key)| cipher(recv_key).decrypt(nonce, payload()).ok());
+                match staged {
+                    Some(decrypted) => {
+                        let (send_key, recv_key) = self.staged_keys.take().unwrap();
+                        self.switch_keys(send_key, recv_key);
+                        decrypted
+                    }
+                    None => self.previous_recv_key.as_ref()
+                        .and_then(|recv_key| cipher(recv_key).decrypt(nonce, payload()).ok())
+                        .ok_or(DecryptionError::InvalidCiphertext)?
+                }
+            }
+        };
+        // Only remember the nonce once the message has been authenticated
+        self.replay_window.update(counter);
+        Ok(decrypted)
     }
 }
 
+fn cipher(key: &SecretKey) -> Aes256GcmSiv {
+    Aes256GcmSiv::new(GenericArray::from_slice(&key[..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Both sides of a connection, and the keys of a rekey started by the first one
+    fn rekeyed() -> (SymmetricEncryption, SymmetricEncryption, (SecretKey, SecretKey), (SecretKey, SecretKey)) {
+        let secret = [7u8; 32];
+        let (initiator, responder) = (EphemeralKeyExchange::new(), EphemeralKeyExchange::new());
+        let (initiator_public, responder_public) = (initiator.public_key(), responder.public_key());
+        (
+            SymmetricEncryption::new_from_secret(&secret),
+            SymmetricEncryption::new_from_secret(&secret),
+            initiator.derive_rekey(&responder_public, true).unwrap(),
+            responder.derive_rekey(&initiator_public, false).unwrap()
+        )
+    }
+
+    #[test]
+    fn the_responder_switches_once_the_new_keys_are_used() {
+        let (mut initiator, mut responder, (send, recv), (staged_send, staged_recv)) = rekeyed();
+        responder.stage_rekey(staged_send, staged_recv);
+        assert!(responder.is_rekeying());
+        // Until the initiator has switched, the responder keeps using the old keys
+        let before = responder.encrypt(b"before");
+        assert_eq!(initiator.decrypt(&before).unwrap(), b"before");
+
+        initiator.rekey(send, recv);
+        assert_eq!(responder.decrypt(&initiator.encrypt(b"new")).unwrap(), b"new");
+        assert!(!responder.is_rekeying());
+        assert_eq!(initiator.decrypt(&responder.encrypt(b"answer")).unwrap(), b"answer");
+    }
+
+    #[test]
+    fn messages_in_flight_during_the_switch_still_decrypt() {
+        let (mut initiator, mut responder, (send, recv), (staged_send, staged_recv)) = rekeyed();
+        let from_initiator = initiator.encrypt(b"old from the initiator");
+        let from_responder = responder.encrypt(b"old from the responder");
+        responder.stage_rekey(staged_send, staged_recv);
+        initiator.rekey(send, recv);
+        assert_eq!(responder.decrypt(&initiator.encrypt(b"new")).unwrap(), b"new");
+
+        // Both arrive after their receiver has switched to the new keys
+        assert_eq!(initiator.decrypt(&from_responder).unwrap(), b"old from the responder");
+        assert_eq!(responder.decrypt(&from_initiator).unwrap(), b"old from the initiator");
+        // But they can't be replayed
+        assert!(matches!(responder.decrypt(&from_initiator), Err(DecryptionError::ReplayedMessage)));
+    }
+
+    #[test]
+    fn forgets_the_keys_of_older_rekeys() {
+        let (mut initiator, mut responder, (send, recv), (staged_send, staged_recv)) = rekeyed();
+        let oldest = initiator.encrypt(b"oldest");
+        initiator.rekey(send, recv);
+        responder.rekey(staged_send, staged_recv);
+
+        let (second, answer) = (EphemeralKeyExchange::new(), EphemeralKeyExchange::new());
+        let (second_public, answer_public) = (second.public_key(), answer.public_key());
+        let (send, recv) = second.derive_rekey(&answer_public, true).unwrap();
+        initiator.rekey(send, recv);
+        let (send, recv) = answer.derive_rekey(&second_public, false).unwrap();
+        responder.rekey(send, recv);
+
+        assert!(matches!(responder.decrypt(&oldest), Err(DecryptionError::InvalidCiphertext)));
+        assert_eq!(responder.decrypt(&initiator.encrypt(b"current")).unwrap(), b"current");
+    }
+}
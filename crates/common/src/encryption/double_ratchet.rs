@@ -0,0 +1,452 @@
+use std::{collections::VecDeque, convert::TryInto, fmt::Display};
+
+use aes_gcm_siv::Aes256GcmSiv;
+use aes_gcm_siv::aead::{Aead, NewAead, Payload, generic_array::GenericArray};
+use hkdf::Hkdf;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use zeroize::Zeroizing;
+
+use super::{NONCE_LENGTH, SecretKey, secret_key};
+
+/// Used when deriving the root key from the ratchet handshake
+const ROOT_KEY_INFO: &[u8] = b"p2pthing ratchet root key";
+/// Used when deriving the responder's first sending chain from the root key
+const RESPONDER_CHAIN_INFO: &[u8] = b"p2pthing ratchet responder chain";
+/// Used when mixing a new Diffie-Hellman output into the root key
+const ROOT_RATCHET_INFO: &[u8] = b"p2pthing ratchet root ratchet";
+const MESSAGE_KEY_INFO: &[u8] = b"p2pthing ratchet message key";
+const CHAIN_KEY_INFO: &[u8] = b"p2pthing ratchet chain key";
+/// Maximum amount of message keys skipped in a single chain, and stored for out of order messages
+const MAX_SKIPPED_KEYS: usize = 1000;
+
+/// The message key of a message which hasn't arrived yet, by the sender's ratchet key and message index
+type SkippedKey = (([u8; 32], u32), SecretKey);
+
+#[derive(Debug)]
+pub enum RatchetError {
+    /// The ratchet key isn't a valid X25519 public key
+    InvalidKey,
+    /// The ratchet key results in a shared secret of all zeroes
+    WeakKey,
+    /// The message is too far ahead of the last received one
+    TooManySkippedMessages,
+    /// The message couldn't be authenticated
+    InvalidCiphertext,
+}
+
+impl Display for RatchetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RatchetError::InvalidKey => f.write_str("Received an invalid ratchet key."),
+            RatchetError::WeakKey => f.write_str("The ratchet key results in a weak shared secret."),
+            RatchetError::TooManySkippedMessages => f.write_str("Too many messages have been skipped."),
+            RatchetError::InvalidCiphertext => f.write_str("The message couldn't be decrypted."),
+        }
+    }
+}
+
+/// Sent in the clear alongside every ratchet encrypted message, and authenticated as associated data
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RatchetHeader {
+    /// The sender's current ratchet public key
+    pub ratchet_key: Vec<u8>,
+    /// Amount of messages sent in the sender's previous sending chain
+    pub previous_chain_length: u32,
+    /// Index of the message in the current sending chain
+    pub index: u32,
+}
+
+/// Starts a double ratchet session by exchanging a pair of X25519 keys.
+///
+/// The keys are sent over the already authenticated transport, so they don't have to be signed.
+pub struct RatchetHandshake {
+    secret: StaticSecret,
+    public_key: PublicKey,
+}
+
+impl Default for RatchetHandshake {
+    fn default() -> Self {
+        RatchetHandshake::new()
+    }
+}
+
+impl RatchetHandshake {
+    pub fn new() -> RatchetHandshake {
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let public_key = PublicKey::from(&secret);
+        RatchetHandshake {
+            secret,
+            public_key,
+        }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.as_bytes().to_vec()
+    }
+
+    /// Finish the handshake. Both sides have to agree on who the initiator is.
+    pub fn complete(self, their_key: &[u8], initiator: bool) -> Result<DoubleRatchet, RatchetError> {
+        let their_key = parse_key(their_key)?;
+        let shared_secret = diffie_hellman(&self.secret, &their_key)?;
+
+        // Sort the keys so both sides end up with the same salt
+        let mut keys = [&self.public_key.as_bytes()[..], &their_key.as_bytes()[..]];
+        keys.sort();
+        let salt = keys.concat();
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt[..]), shared_secret.as_bytes());
+        let mut root_key = Zeroizing::new([0u8; 32]);
+        hkdf.expand(ROOT_KEY_INFO, &mut root_key[..]).expect("32 bytes is a valid length for HKDF-SHA256");
+        let mut responder_chain = Zeroizing::new([0u8; 32]);
+        hkdf.expand(RESPONDER_CHAIN_INFO, &mut responder_chain[..]).expect("32 bytes is a valid length for HKDF-SHA256");
+
+        let mut ratchet = DoubleRatchet {
+            secret: self.secret,
+            public_key: self.public_key,
+            remote_key: their_key,
+            root_key,
+            send_chain: None,
+            recv_chain: None,
+            send_index: 0,
+            recv_index: 0,
+            previous_chain_length: 0,
+            skipped_keys: VecDeque::new(),
+        };
+        match initiator {
+            // The initiator steps the ratchet right away, so the responder does a DH ratchet step on its first message
+            true => {
+                ratchet.recv_chain = Some(responder_chain);
+                ratchet.step_send_ratchet()?;
+            }
+            // Until the initiator's first message arrives, the responder sends with a chain derived from the handshake
+            false => ratchet.send_chain = Some(responder_chain),
+        }
+        Ok(ratchet)
+    }
+}
+
+/// A Signal style double ratchet, which encrypts every message with its own key.
+///
+/// The chain keys are stepped forward after every message, and a new Diffie-Hellman exchange is mixed
+/// into the root key whenever the other side answers. So a leaked key only exposes a few messages,
+/// instead of the whole conversation.
+///
+/// Every secret is wiped from memory when it's dropped, including the state thrown away after a ratchet step.
+pub struct DoubleRatchet {
+    secret: StaticSecret,
+    public_key: PublicKey,
+    remote_key: PublicKey,
+    root_key: SecretKey,
+    send_chain: Option<SecretKey>,
+    recv_chain: Option<SecretKey>,
+    send_index: u32,
+    recv_index: u32,
+    previous_chain_length: u32,
+    /// Message keys of messages which haven't arrived yet, oldest first
+    skipped_keys: VecDeque<SkippedKey>,
+}
+
+/// The changes a received message makes to the ratchet, which are only applied once the message has been authenticated.
+/// Only the receiving chain which is being advanced is copied, and the sending side if the message starts a new ratchet step.
+struct StagedReceive {
+    /// Keys of the messages skipped on the way, oldest first
+    skipped_keys: Vec<SkippedKey>,
+    recv_chain: SecretKey,
+    recv_index: u32,
+    ratchet_step: Option<RatchetStep>,
+}
+
+/// A Diffie-Hellman ratchet step, for a message with a new ratchet key
+struct RatchetStep {
+    remote_key: PublicKey,
+    root_key: SecretKey,
+    secret: StaticSecret,
+    public_key: PublicKey,
+    send_chain: SecretKey,
+}
+
+impl DoubleRatchet {
+    pub fn encrypt(&mut self, data: &[u8]) -> (RatchetHeader, Vec<u8>) {
+        let chain = self.send_chain.as_ref().expect("The sending chain is always set after the handshake");
+        let (chain, message_key) = step_chain(chain);
+        self.send_chain = Some(chain);
+
+        let header = RatchetHeader {
+            ratchet_key: self.public_key.as_bytes().to_vec(),
+            previous_chain_length: self.previous_chain_length,
+            index: self.send_index,
+        };
+        self.send_index += 1;
+
+        let aad = bincode::serialize(&header).unwrap();
+        let cipher = Aes256GcmSiv::new(GenericArray::from_slice(&message_key[..]));
+        // Every message key is only used once, so the nonce can be constant
+        let encrypted = cipher.encrypt(GenericArray::from_slice(&[0u8; NONCE_LENGTH]), Payload {msg: data, aad: &aad[..]}).unwrap();
+        (header, encrypted)
+    }
+
+    /// Decrypt a message, only changing the state if it was authenticated
+    pub fn decrypt(&mut self, header: &RatchetHeader, data: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        let ratchet_key = parse_key(&header.ratchet_key)?;
+
+        let id = (*ratchet_key.as_bytes(), header.index);
+        if let Some(i) = self.skipped_keys.iter().position(|(skipped, _)| *skipped == id) {
+            let decrypted = open(&self.skipped_keys[i].1, header, data)?;
+            self.skipped_keys.remove(i);
+            return Ok(decrypted);
+        }
+
+        let (staged, message_key) = self.stage_receive(ratchet_key, header)?;
+        let decrypted = open(&message_key, header, data)?;
+        self.apply(staged);
+        Ok(decrypted)
+    }
+
+    /// Find the message key of a message which hasn't been skipped, without changing the state
+    fn stage_receive(&self, ratchet_key: PublicKey, header: &RatchetHeader) -> Result<(StagedReceive, SecretKey), RatchetError> {
+        let mut skipped_keys = vec![];
+        let (chain, index, ratchet_step) = match ratchet_key == self.remote_key {
+            true => (self.recv_chain.clone(), self.recv_index, None),
+            false => {
+                // The rest of the previous receiving chain might still arrive
+                if let Some(chain) = &self.recv_chain {
+                    skip_keys(&self.remote_key, chain.clone(), self.recv_index, header.previous_chain_length, &mut skipped_keys)?;
+                }
+                let (root_key, recv_chain) = step_root(&self.root_key, &diffie_hellman(&self.secret, &ratchet_key)?);
+                let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+                let (root_key, send_chain) = step_root(&root_key, &diffie_hellman(&secret, &ratchet_key)?);
+                let step = RatchetStep {
+                    remote_key: ratchet_key,
+                    root_key,
+                    public_key: PublicKey::from(&secret),
+                    secret,
+                    send_chain,
+                };
+                (Some(recv_chain), 0, Some(step))
+            }
+        };
+        // The key of an earlier message is either stored, or the message has already been received
+        let chain = chain.ok_or(RatchetError::InvalidCiphertext)?;
+        if header.index < index {
+            return Err(RatchetError::InvalidCiphertext);
+        }
+
+        let chain = skip_keys(&ratchet_key, chain, index, header.index, &mut skipped_keys)?;
+        let (recv_chain, message_key) = step_chain(&chain);
+        let staged = StagedReceive {
+            skipped_keys,
+            recv_chain,
+            recv_index: header.index + 1,
+            ratchet_step,
+        };
+        Ok((staged, message_key))
+    }
+
+    fn apply(&mut self, staged: StagedReceive) {
+        if let Some(step) = staged.ratchet_step {
+            self.remote_key = step.remote_key;
+            self.root_key = step.root_key;
+            self.secret = step.secret;
+            self.public_key = step.public_key;
+            self.send_chain = Some(step.send_chain);
+            self.previous_chain_length = self.send_index;
+            self.send_index = 0;
+        }
+        self.recv_chain = Some(staged.recv_chain);
+        self.recv_index = staged.recv_index;
+
+        // Forget the oldest keys, the messages will most likely never arrive
+        self.skipped_keys.extend(staged.skipped_keys);
+        while self.skipped_keys.len() > MAX_SKIPPED_KEYS {
+            self.skipped_keys.pop_front();
+        }
+    }
+
+    /// Generate a new ratchet key and start a new sending chain with it
+    fn step_send_ratchet(&mut self) -> Result<(), RatchetError> {
+        self.secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        self.public_key = PublicKey::from(&self.secret);
+        let shared_secret = diffie_hellman(&self.secret, &self.remote_key)?;
+        let (root_key, chain) = step_root(&self.root_key, &shared_secret);
+        self.root_key = root_key;
+        self.send_chain = Some(chain);
+        self.previous_chain_length = self.send_index;
+        self.send_index = 0;
+        Ok(())
+    }
+}
+
+/// Store the message keys of the chain from the index up until another one, returning the chain at that point
+fn skip_keys(ratchet_key: &PublicKey, mut chain: SecretKey, index: u32, until: u32, skipped_keys: &mut Vec<SkippedKey>) -> Result<SecretKey, RatchetError> {
+    if until.saturating_sub(index) as usize > MAX_SKIPPED_KEYS {
+        return Err(RatchetError::TooManySkippedMessages);
+    }
+    for index in index..until {
+        let (next_chain, message_key) = step_chain(&chain);
+        skipped_keys.push(((*ratchet_key.as_bytes(), index), message_key));
+        chain = next_chain;
+    }
+    Ok(chain)
+}
+
+/// Decrypt a message with its message key, authenticating the header as well
+fn open(message_key: &SecretKey, header: &RatchetHeader, data: &[u8]) -> Result<Vec<u8>, RatchetError> {
+    let aad = bincode::serialize(header).unwrap();
+    let cipher = Aes256GcmSiv::new(GenericArray::from_slice(&message_key[..]));
+    cipher.decrypt(GenericArray::from_slice(&[0u8; NONCE_LENGTH]), Payload {msg: data, aad: &aad[..]})
+        .map_err(|_| RatchetError::InvalidCiphertext)
+}
+
+fn parse_key(key: &[u8]) -> Result<PublicKey, RatchetError> {
+    let key: [u8; 32] = key.try_into().map_err(|_| RatchetError::InvalidKey)?;
+    Ok(PublicKey::from(key))
+}
+
+fn diffie_hellman(secret: &StaticSecret, their_key: &PublicKey) -> Result<SharedSecret, RatchetError> {
+    let shared_secret = secret.diffie_hellman(their_key);
+    if shared_secret.as_bytes().iter().all(|b| *b == 0) {
+        return Err(RatchetError::WeakKey);
+    }
+    Ok(shared_secret)
+}
+
+/// Mix a Diffie-Hellman output into the root key, returning the new root key and a new chain key
+fn step_root(root_key: &SecretKey, shared_secret: &SharedSecret) -> (SecretKey, SecretKey) {
+    let hkdf = Hkdf::<Sha256>::new(Some(&root_key[..]), shared_secret.as_bytes());
+    let mut output = Zeroizing::new([0u8; 64]);
+    hkdf.expand(ROOT_RATCHET_INFO, &mut output[..]).expect("64 bytes is a valid length for HKDF-SHA256");
+    (secret_key(&output[..32]), secret_key(&output[32..]))
+}
+
+/// Step a chain forward, returning the next chain key and the message key
+fn step_chain(chain: &SecretKey) -> (SecretKey, SecretKey) {
+    let hkdf = Hkdf::<Sha256>::from_prk(&chain[..]).expect("32 bytes is a valid PRK length for HKDF-SHA256");
+    let mut next_chain = Zeroizing::new([0u8; 32]);
+    hkdf.expand(CHAIN_KEY_INFO, &mut next_chain[..]).expect("32 bytes is a valid length for HKDF-SHA256");
+    let mut message_key = Zeroizing::new([0u8; 32]);
+    hkdf.expand(MESSAGE_KEY_INFO, &mut message_key[..]).expect("32 bytes is a valid length for HKDF-SHA256");
+    (next_chain, message_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session between an initiator and a responder
+    fn session() -> (DoubleRatchet, DoubleRatchet) {
+        let (alice, bob) = (RatchetHandshake::new(), RatchetHandshake::new());
+        let (alice_key, bob_key) = (alice.public_key(), bob.public_key());
+        (alice.complete(&bob_key, true).unwrap(), bob.complete(&alice_key, false).unwrap())
+    }
+
+    fn send(ratchet: &mut DoubleRatchet, count: usize) -> Vec<(RatchetHeader, Vec<u8>)> {
+        (0..count).map(|i| ratchet.encrypt(&i.to_be_bytes())).collect()
+    }
+
+    fn receive(ratchet: &mut DoubleRatchet, message: &(RatchetHeader, Vec<u8>)) -> Result<Vec<u8>, RatchetError> {
+        ratchet.decrypt(&message.0, &message.1)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut alice, mut bob) = session();
+        for turn in 0..3 {
+            let (header, encrypted) = alice.encrypt(b"to bob");
+            assert_eq!(bob.decrypt(&header, &encrypted).unwrap(), b"to bob");
+            let (header, encrypted) = bob.encrypt(b"to alice");
+            assert_eq!(alice.decrypt(&header, &encrypted).unwrap(), b"to alice");
+            // Every answer starts a new ratchet step
+            assert_eq!(header.index, 0, "turn {}", turn);
+        }
+    }
+
+    #[test]
+    fn the_responder_can_send_first() {
+        let (mut alice, mut bob) = session();
+        let (header, encrypted) = bob.encrypt(b"to alice");
+        assert_eq!(alice.decrypt(&header, &encrypted).unwrap(), b"to alice");
+        let (header, encrypted) = alice.encrypt(b"to bob");
+        assert_eq!(bob.decrypt(&header, &encrypted).unwrap(), b"to bob");
+    }
+
+    #[test]
+    fn decrypts_messages_out_of_order() {
+        let (mut alice, mut bob) = session();
+        let first_chain = send(&mut alice, 3);
+        assert_eq!(receive(&mut bob, &first_chain[2]).unwrap(), 2usize.to_be_bytes());
+
+        // The answer makes alice start a new chain, while the first one is still being delivered
+        let answer = bob.encrypt(b"answer");
+        receive(&mut alice, &answer).unwrap();
+        let second_chain = send(&mut alice, 2);
+        assert_eq!(receive(&mut bob, &second_chain[1]).unwrap(), 1usize.to_be_bytes());
+        assert_eq!(receive(&mut bob, &first_chain[0]).unwrap(), 0usize.to_be_bytes());
+        assert_eq!(receive(&mut bob, &second_chain[0]).unwrap(), 0usize.to_be_bytes());
+        assert_eq!(receive(&mut bob, &first_chain[1]).unwrap(), 1usize.to_be_bytes());
+
+        // Every message key is only used once
+        assert!(matches!(receive(&mut bob, &first_chain[1]), Err(RatchetError::InvalidCiphertext)));
+        assert!(matches!(receive(&mut bob, &second_chain[1]), Err(RatchetError::InvalidCiphertext)));
+    }
+
+    #[test]
+    fn limits_the_skipped_messages() {
+        let (mut alice, mut bob) = session();
+        let messages = send(&mut alice, MAX_SKIPPED_KEYS + 2);
+        assert!(matches!(receive(&mut bob, &messages[MAX_SKIPPED_KEYS + 1]), Err(RatchetError::TooManySkippedMessages)));
+        receive(&mut bob, &messages[MAX_SKIPPED_KEYS]).unwrap();
+        receive(&mut bob, &messages[0]).unwrap();
+    }
+
+    #[test]
+    fn forgets_the_oldest_skipped_keys() {
+        let (mut alice, mut bob) = session();
+        let skipped = MAX_SKIPPED_KEYS * 3 / 5;
+        let first_chain = send(&mut alice, skipped + 1);
+        receive(&mut bob, &first_chain[skipped]).unwrap();
+        receive(&mut alice, &bob.encrypt(b"answer")).unwrap();
+        let second_chain = send(&mut alice, skipped + 1);
+        receive(&mut bob, &second_chain[skipped]).unwrap();
+
+        // The keys of the first chain are older, even though they have the same indexes
+        let forgotten = 2 * skipped - MAX_SKIPPED_KEYS;
+        assert_eq!(bob.skipped_keys.len(), MAX_SKIPPED_KEYS);
+        assert!(receive(&mut bob, &first_chain[forgotten - 1]).is_err());
+        receive(&mut bob, &first_chain[forgotten]).unwrap();
+        receive(&mut bob, &second_chain[0]).unwrap();
+    }
+
+    #[test]
+    fn rejects_tampered_messages_without_changing_the_state() {
+        let (mut alice, mut bob) = session();
+        let messages = send(&mut alice, 2);
+
+        let (mut header, mut encrypted) = messages[1].clone();
+        encrypted[0] ^= 1;
+        assert!(matches!(bob.decrypt(&header, &encrypted), Err(RatchetError::InvalidCiphertext)));
+        // The header is authenticated too
+        encrypted[0] ^= 1;
+        header.previous_chain_length += 1;
+        assert!(matches!(bob.decrypt(&header, &encrypted), Err(RatchetError::InvalidCiphertext)));
+        assert!(bob.skipped_keys.is_empty());
+
+        // A forged ratchet key doesn't start a new ratchet step
+        let mut forged = messages[0].clone();
+        forged.0.ratchet_key = RatchetHandshake::new().public_key();
+        assert!(receive(&mut bob, &forged).is_err());
+
+        receive(&mut bob, &messages[0]).unwrap();
+        receive(&mut bob, &messages[1]).unwrap();
+    }
+
+    #[test]
+    fn rejects_low_order_ratchet_keys() {
+        let (mut alice, mut bob) = session();
+        let (mut header, encrypted) = alice.encrypt(b"to bob");
+        header.ratchet_key = vec![0u8; 32];
+        assert!(matches!(bob.decrypt(&header, &encrypted), Err(RatchetError::WeakKey)));
+        assert!(matches!(RatchetHandshake::new().complete(&[0u8; 32], true), Err(RatchetError::WeakKey)));
+    }
+}
@@ -0,0 +1,196 @@
+use std::{convert::TryInto, fmt::Display};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use zeroize::Zeroizing;
+
+use super::{AsymmetricEncryption, NetworkedPublicKey, SecretKey, SymmetricEncryption};
+
+/// Prepended to the signed ephemeral keys, so the signature can't be reused elsewhere
+const SIGNATURE_CONTEXT: &[u8] = b"p2pthing key exchange";
+/// Used when expanding the shared secret into the session key
+const SESSION_KEY_INFO: &[u8] = b"p2pthing session key";
+/// Used when expanding the shared secret of a rekey into the key of the side which started it
+const REKEY_INITIATOR_INFO: &[u8] = b"p2pthing rekey initiator";
+/// Used when expanding the shared secret of a rekey into the key of the side which answered it
+const REKEY_RESPONDER_INFO: &[u8] = b"p2pthing rekey responder";
+
+#[derive(Debug)]
+pub enum KeyExchangeError {
+    /// The ephemeral key isn't a valid X25519 public key
+    InvalidKey,
+    /// The ephemeral key wasn't signed by the expected identity
+    InvalidSignature,
+    /// The ephemeral key results in a shared secret of all zeroes
+    WeakKey,
+}
+
+impl Display for KeyExchangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyExchangeError::InvalidKey => f.write_str("Received an invalid ephemeral key."),
+            KeyExchangeError::InvalidSignature => f.write_str("The ephemeral key's signature is invalid."),
+            KeyExchangeError::WeakKey => f.write_str("The ephemeral key results in a weak shared secret."),
+        }
+    }
+}
+
+/// One side of an X25519 Diffie-Hellman key exchange.
+///
+/// The ephemeral keys are signed with the long-term identity key, but the session key
+/// only depends on the ephemeral secrets, which are thrown away once the key is derived.
+/// So a leaked identity key can't be used to decrypt previously recorded sessions.
+pub struct EphemeralKeyExchange {
+    secret: StaticSecret,
+    public_key: PublicKey,
+}
+
+impl EphemeralKeyExchange {
+    pub fn new() -> EphemeralKeyExchange {
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let public_key = PublicKey::from(&secret);
+        EphemeralKeyExchange {
+            secret,
+            public_key,
+        }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.as_bytes().to_vec()
+    }
+
+    /// Sign our ephemeral key with the identity key. When responding, the other side's
+    /// ephemeral key (or challenge) is signed as well, so the signature can't be replayed.
+    pub fn sign(&self, identity: &AsymmetricEncryption, other_key: Option<&[u8]>) -> Vec<u8> {
+        identity.sign(&signed_data(self.public_key.as_bytes(), other_key))
+    }
+
+    /// Check that the ephemeral key has been signed by the owner of the identity key
+    pub fn verify(identity: &NetworkedPublicKey, ephemeral_key: &[u8], other_key: Option<&[u8]>, signature: &[u8]) -> Result<(), KeyExchangeError> {
+        match identity.verify(&signed_data(ephemeral_key, other_key), signature) {
+            true => Ok(()),
+            false => Err(KeyExchangeError::InvalidSignature)
+        }
+    }
+
+    /// Finish the key exchange, consuming the ephemeral secret
+    pub fn derive(self, their_key: &[u8]) -> Result<SymmetricEncryption, KeyExchangeError> {
+        let hkdf = self.expand(their_key)?;
+        let mut session_key = Zeroizing::new([0u8; 32]);
+        hkdf.expand(SESSION_KEY_INFO, &mut session_key[..]).expect("32 bytes is a valid length for HKDF-SHA256");
+        Ok(SymmetricEncryption::new_from_secret(&session_key[..]))
+    }
+
+    /// Finish a rekey of an existing session, returning the new sending and receiving secrets.
+    /// Each direction gets its own key, depending on which side started the rekey.
+    pub fn derive_rekey(self, their_key: &[u8], initiator: bool) -> Result<(SecretKey, SecretKey), KeyExchangeError> {
+        let hkdf = self.expand(their_key)?;
+        let mut initiator_key = Zeroizing::new([0u8; 32]);
+        hkdf.expand(REKEY_INITIATOR_INFO, &mut initiator_key[..]).expect("32 bytes is a valid length for HKDF-SHA256");
+        let mut responder_key = Zeroizing::new([0u8; 32]);
+        hkdf.expand(REKEY_RESPONDER_INFO, &mut responder_key[..]).expect("32 bytes is a valid length for HKDF-SHA256");
+        match initiator {
+            true => Ok((initiator_key, responder_key)),
+            false => Ok((responder_key, initiator_key)),
+        }
+    }
+
+    fn expand(&self, their_key: &[u8]) -> Result<Hkdf<Sha256>, KeyExchangeError> {
+        let their_key: [u8; 32] = their_key.try_into().map_err(|_| KeyExchangeError::InvalidKey)?;
+        let their_key = PublicKey::from(their_key);
+
+        let shared_secret = self.secret.diffie_hellman(&their_key);
+        if shared_secret.as_bytes().iter().all(|b| *b == 0) {
+            return Err(KeyExchangeError::WeakKey);
+        }
+
+        // Sort the keys so both sides end up with the same salt
+        let mut keys = [&self.public_key.as_bytes()[..], &their_key.as_bytes()[..]];
+        keys.sort();
+        let salt = keys.concat();
+
+        Ok(Hkdf::<Sha256>::new(Some(&salt[..]), shared_secret.as_bytes()))
+    }
+}
+
+impl Default for EphemeralKeyExchange {
+    fn default() -> Self {
+        EphemeralKeyExchange::new()
+    }
+}
+
+fn signed_data(ephemeral_key: &[u8], other_key: Option<&[u8]>) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, ephemeral_key, other_key.unwrap_or(&[])].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_derive_the_same_key() {
+        let (ours, theirs) = (EphemeralKeyExchange::new(), EphemeralKeyExchange::new());
+        let (our_public, their_public) = (ours.public_key(), theirs.public_key());
+        let mut our_key = ours.derive(&their_public).unwrap();
+        let mut their_key = theirs.derive(&our_public).unwrap();
+        let encrypted = our_key.encrypt(b"hello");
+        assert_eq!(their_key.decrypt(&encrypted).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn rekeys_in_both_directions() {
+        let (initiator, responder) = (EphemeralKeyExchange::new(), EphemeralKeyExchange::new());
+        let (initiator_public, responder_public) = (initiator.public_key(), responder.public_key());
+        let (initiator_send, initiator_recv) = initiator.derive_rekey(&responder_public, true).unwrap();
+        let (responder_send, responder_recv) = responder.derive_rekey(&initiator_public, false).unwrap();
+        assert_eq!(*initiator_send, *responder_recv);
+        assert_eq!(*initiator_recv, *responder_send);
+        assert_ne!(*initiator_send, *initiator_recv);
+    }
+
+    #[test]
+    fn accepts_a_signed_key() {
+        let identity = AsymmetricEncryption::new();
+        let exchange = EphemeralKeyExchange::new();
+        let signature = exchange.sign(&identity, Some(b"their key"));
+        assert!(EphemeralKeyExchange::verify(&identity.get_public_key(), &exchange.public_key(), Some(b"their key"), &signature).is_ok());
+    }
+
+    #[test]
+    fn rejects_the_wrong_signer() {
+        let (identity, impostor) = (AsymmetricEncryption::new(), AsymmetricEncryption::new());
+        let exchange = EphemeralKeyExchange::new();
+        let signature = exchange.sign(&impostor, None);
+        let result = EphemeralKeyExchange::verify(&identity.get_public_key(), &exchange.public_key(), None, &signature);
+        assert!(matches!(result, Err(KeyExchangeError::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_a_signature_for_another_peer_key() {
+        let identity = AsymmetricEncryption::new();
+        let exchange = EphemeralKeyExchange::new();
+        // A response recorded in another key exchange can't be replayed
+        let signature = exchange.sign(&identity, Some(&EphemeralKeyExchange::new().public_key()));
+        let result = EphemeralKeyExchange::verify(&identity.get_public_key(), &exchange.public_key(), Some(&EphemeralKeyExchange::new().public_key()), &signature);
+        assert!(matches!(result, Err(KeyExchangeError::InvalidSignature)));
+        let result = EphemeralKeyExchange::verify(&identity.get_public_key(), &exchange.public_key(), None, &signature);
+        assert!(matches!(result, Err(KeyExchangeError::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_low_order_points() {
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        for point in [[0u8; 32], one].iter() {
+            assert!(matches!(EphemeralKeyExchange::new().derive(point), Err(KeyExchangeError::WeakKey)));
+            assert!(matches!(EphemeralKeyExchange::new().derive_rekey(point, true), Err(KeyExchangeError::WeakKey)));
+        }
+    }
+
+    #[test]
+    fn rejects_keys_of_the_wrong_length() {
+        assert!(matches!(EphemeralKeyExchange::new().derive(&[9u8; 31]), Err(KeyExchangeError::InvalidKey)));
+    }
+}
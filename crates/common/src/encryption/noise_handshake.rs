@@ -0,0 +1,231 @@
+use std::fmt::Display;
+
+use serde::{Serialize, Deserialize};
+use snow::{Builder, HandshakeState};
+use zeroize::Zeroizing;
+
+use super::{AsymmetricEncryption, NetworkedPublicKey, SymmetricEncryption};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+/// Prepended to the signed Noise static key, so the signature can't be reused elsewhere
+const SIGNATURE_CONTEXT: &[u8] = b"p2pthing noise static key";
+/// Maximum size of a Noise message
+const MAX_MESSAGE_LENGTH: usize = 65535;
+
+#[derive(Debug)]
+pub enum NoiseHandshakeError {
+    Noise(snow::Error),
+    /// The payload proving the peer's identity couldn't be read
+    InvalidPayload,
+    /// The Noise static key wasn't signed by the expected identity
+    InvalidSignature,
+    /// Tried finishing the handshake before every message was exchanged
+    Unfinished,
+}
+
+impl Display for NoiseHandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NoiseHandshakeError::Noise(e) => Display::fmt(e, f),
+            NoiseHandshakeError::InvalidPayload => f.write_str("Received an invalid handshake payload."),
+            NoiseHandshakeError::InvalidSignature => f.write_str("The Noise static key's signature is invalid."),
+            NoiseHandshakeError::Unfinished => f.write_str("The handshake hasn't been finished yet."),
+        }
+    }
+}
+
+impl From<snow::Error> for NoiseHandshakeError {
+    fn from(e: snow::Error) -> Self {
+        NoiseHandshakeError::Noise(e)
+    }
+}
+
+/// Sent inside the encrypted handshake messages, binding the Noise static key to the identity key
+#[derive(Serialize, Deserialize)]
+struct IdentityPayload {
+    public_key: NetworkedPublicKey,
+    signature: Vec<u8>,
+}
+
+/// A Noise XX handshake between two peers.
+///
+/// XX authenticates both sides and only sends the static keys encrypted, so the identities
+/// are hidden from passive observers. The Noise static keys are generated for every handshake,
+/// and are tied to the long-term identity by signing them.
+pub struct NoiseHandshake {
+    state: HandshakeState,
+    static_key: Vec<u8>,
+    /// Amount of handshake messages written and read so far
+    messages: usize,
+    remote_verified: bool,
+}
+
+impl NoiseHandshake {
+    pub fn new_initiator() -> Result<NoiseHandshake, NoiseHandshakeError> {
+        NoiseHandshake::new(true)
+    }
+
+    pub fn new_responder() -> Result<NoiseHandshake, NoiseHandshakeError> {
+        NoiseHandshake::new(false)
+    }
+
+    fn new(initiator: bool) -> Result<NoiseHandshake, NoiseHandshakeError> {
+        let builder = Builder::new(NOISE_PARAMS.parse()?);
+        let keypair = builder.generate_keypair()?;
+        let private_key = Zeroizing::new(keypair.private);
+        let builder = builder.local_private_key(&private_key[..]);
+        let state = match initiator {
+            true => builder.build_initiator()?,
+            false => builder.build_responder()?,
+        };
+        Ok(NoiseHandshake {
+            state,
+            static_key: keypair.public,
+            messages: 0,
+            remote_verified: false,
+        })
+    }
+
+    pub fn is_my_turn(&self) -> bool {
+        !self.state.is_handshake_finished() && self.state.is_my_turn()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_handshake_finished()
+    }
+
+    /// Create the next handshake message. Every message except the first one carries our identity.
+    pub fn write_message(&mut self, identity: &AsymmetricEncryption) -> Result<Vec<u8>, NoiseHandshakeError> {
+        let payload = match self.messages {
+            // The first message isn't encrypted, so it can't contain the identity
+            0 => vec![],
+            _ => {
+                let payload = IdentityPayload {
+                    public_key: identity.get_public_key(),
+                    signature: identity.sign(&[SIGNATURE_CONTEXT, &self.static_key[..]].concat()),
+                };
+                bincode::serialize(&payload).unwrap()
+            }
+        };
+
+        let mut buf = vec![0u8; MAX_MESSAGE_LENGTH];
+        let len = self.state.write_message(&payload[..], &mut buf)?;
+        buf.truncate(len);
+        self.messages += 1;
+        Ok(buf)
+    }
+
+    /// Read the next handshake message, checking that the peer's identity is the expected one
+    pub fn read_message(&mut self, message: &[u8], expected: &NetworkedPublicKey) -> Result<(), NoiseHandshakeError> {
+        let mut buf = vec![0u8; MAX_MESSAGE_LENGTH];
+        let len = self.state.read_message(message, &mut buf)?;
+        self.messages += 1;
+        if len == 0 {
+            return Ok(());
+        }
+
+        let payload: IdentityPayload = bincode::deserialize(&buf[..len]).map_err(|_| NoiseHandshakeError::InvalidPayload)?;
+        let remote_static = self.state.get_remote_static().ok_or(NoiseHandshakeError::InvalidPayload)?;
+        if &payload.public_key != expected || !expected.verify(&[SIGNATURE_CONTEXT, remote_static].concat(), &payload.signature) {
+            return Err(NoiseHandshakeError::InvalidSignature);
+        }
+        self.remote_verified = true;
+        Ok(())
+    }
+
+    /// Turn the finished handshake into transport keys.
+    ///
+    /// Noise's transport mode isn't used, since the keys are needed by `SymmetricEncryption`, which also authenticates
+    /// the packet headers, seals packets and rekeys. Taking the raw split is safe, because the handshake state is
+    /// thrown away with it, so Noise never encrypts anything with these keys itself. Each direction has its own key,
+    /// which `SymmetricEncryption` uses with its own nonces, from a random prefix and a counter which is never reset.
+    /// Rekeys derive the next keys from a new X25519 exchange, so they never reuse these keys either.
+    pub fn into_symmetric_encryption(mut self) -> Result<SymmetricEncryption, NoiseHandshakeError> {
+        if !self.state.is_handshake_finished() || !self.remote_verified {
+            return Err(NoiseHandshakeError::Unfinished);
+        }
+        let (initiator_key, responder_key) = self.state.dangerously_get_raw_split();
+        let (initiator_key, responder_key) = (Zeroizing::new(initiator_key), Zeroizing::new(responder_key));
+        match self.state.is_initiator() {
+            true => Ok(SymmetricEncryption::new_from_split(&initiator_key[..], &responder_key[..])),
+            false => Ok(SymmetricEncryption::new_from_split(&responder_key[..], &initiator_key[..])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the handshake to its end, returning the initiator and the responder
+    fn handshake(initiator_identity: &AsymmetricEncryption, responder_identity: &AsymmetricEncryption) -> (NoiseHandshake, NoiseHandshake) {
+        let (initiator_key, responder_key) = (initiator_identity.get_public_key(), responder_identity.get_public_key());
+        let mut initiator = NoiseHandshake::new_initiator().unwrap();
+        let mut responder = NoiseHandshake::new_responder().unwrap();
+
+        let message = initiator.write_message(initiator_identity).unwrap();
+        responder.read_message(&message, &initiator_key).unwrap();
+        let message = responder.write_message(responder_identity).unwrap();
+        initiator.read_message(&message, &responder_key).unwrap();
+        let message = initiator.write_message(initiator_identity).unwrap();
+        responder.read_message(&message, &initiator_key).unwrap();
+        (initiator, responder)
+    }
+
+    #[test]
+    fn both_sides_end_up_with_matching_keys() {
+        let (initiator_identity, responder_identity) = (AsymmetricEncryption::new(), AsymmetricEncryption::new());
+        let (initiator, responder) = handshake(&initiator_identity, &responder_identity);
+        assert!(initiator.is_finished() && responder.is_finished());
+
+        let mut initiator = initiator.into_symmetric_encryption().unwrap();
+        let mut responder = responder.into_symmetric_encryption().unwrap();
+        let encrypted = initiator.encrypt(b"from the initiator");
+        assert_eq!(responder.decrypt(&encrypted).unwrap(), b"from the initiator");
+        let encrypted = responder.encrypt(b"from the responder");
+        assert_eq!(initiator.decrypt(&encrypted).unwrap(), b"from the responder");
+    }
+
+    #[test]
+    fn each_direction_has_its_own_key() {
+        let (initiator_identity, responder_identity) = (AsymmetricEncryption::new(), AsymmetricEncryption::new());
+        let (initiator, _) = handshake(&initiator_identity, &responder_identity);
+        let initiator = initiator.into_symmetric_encryption().unwrap();
+        assert_ne!(*initiator.send_key, *initiator.recv_key);
+    }
+
+    #[test]
+    fn rejects_a_tampered_message() {
+        let (initiator_identity, responder_identity) = (AsymmetricEncryption::new(), AsymmetricEncryption::new());
+        let mut initiator = NoiseHandshake::new_initiator().unwrap();
+        let mut responder = NoiseHandshake::new_responder().unwrap();
+        let message = initiator.write_message(&initiator_identity).unwrap();
+        responder.read_message(&message, &initiator_identity.get_public_key()).unwrap();
+
+        let mut message = responder.write_message(&responder_identity).unwrap();
+        let last = message.len() - 1;
+        message[last] ^= 1;
+        assert!(matches!(initiator.read_message(&message, &responder_identity.get_public_key()), Err(NoiseHandshakeError::Noise(_))));
+    }
+
+    #[test]
+    fn rejects_an_unexpected_identity() {
+        let (initiator_identity, responder_identity) = (AsymmetricEncryption::new(), AsymmetricEncryption::new());
+        let mut initiator = NoiseHandshake::new_initiator().unwrap();
+        let mut responder = NoiseHandshake::new_responder().unwrap();
+        let message = initiator.write_message(&initiator_identity).unwrap();
+        responder.read_message(&message, &initiator_identity.get_public_key()).unwrap();
+
+        let message = responder.write_message(&responder_identity).unwrap();
+        let result = initiator.read_message(&message, &initiator_identity.get_public_key());
+        assert!(matches!(result, Err(NoiseHandshakeError::InvalidSignature)));
+    }
+
+    #[test]
+    fn refuses_an_unfinished_handshake() {
+        let identity = AsymmetricEncryption::new();
+        let mut initiator = NoiseHandshake::new_initiator().unwrap();
+        initiator.write_message(&identity).unwrap();
+        assert!(matches!(initiator.into_symmetric_encryption(), Err(NoiseHandshakeError::Unfinished)));
+    }
+}
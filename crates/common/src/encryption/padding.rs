@@ -0,0 +1,56 @@
+use std::convert::TryInto;
+
+/// Padded messages are a multiple of this size. It's large enough to fit a voice packet,
+/// a chat message or a file chunk, so they can't be told apart by their size.
+/// A sealed packet of a single bucket, with its header and encryption, still fits into the minimum MTU of IPv6.
+pub const PADDING_BUCKET_SIZE: usize = 1176;
+/// Size of the length which precedes the padded data
+const LENGTH_SIZE: usize = 4;
+
+/// Pad the data to the next bucket, so its length doesn't reveal what it contains
+pub fn pad(data: &[u8]) -> Vec<u8> {
+    let length = LENGTH_SIZE + data.len();
+    let buckets = length.div_ceil(PADDING_BUCKET_SIZE);
+    let mut padded = Vec::with_capacity(buckets * PADDING_BUCKET_SIZE);
+    padded.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    padded.extend_from_slice(data);
+    padded.resize(buckets * PADDING_BUCKET_SIZE, 0);
+    padded
+}
+
+/// Remove the padding added by `pad`, returning `None` if the length is invalid
+pub fn unpad(padded: &[u8]) -> Option<&[u8]> {
+    let length = u32::from_le_bytes(padded.get(..LENGTH_SIZE)?.try_into().unwrap()) as usize;
+    padded.get(LENGTH_SIZE..LENGTH_SIZE.checked_add(length)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_to_whole_buckets() {
+        assert_eq!(pad(&[]).len(), PADDING_BUCKET_SIZE);
+        assert_eq!(pad(&[1; PADDING_BUCKET_SIZE - LENGTH_SIZE]).len(), PADDING_BUCKET_SIZE);
+        assert_eq!(pad(&[1; PADDING_BUCKET_SIZE - LENGTH_SIZE + 1]).len(), 2 * PADDING_BUCKET_SIZE);
+    }
+
+    #[test]
+    fn unpads_what_was_padded() {
+        for length in [0, 1, PADDING_BUCKET_SIZE - LENGTH_SIZE, PADDING_BUCKET_SIZE, 3 * PADDING_BUCKET_SIZE].iter() {
+            let data: Vec<u8> = (0..*length).map(|i| i as u8).collect();
+            assert_eq!(unpad(&pad(&data)), Some(&data[..]));
+        }
+    }
+
+    #[test]
+    fn rejects_an_invalid_length() {
+        assert_eq!(unpad(&[]), None);
+        assert_eq!(unpad(&[1, 0, 0]), None);
+        let mut padded = pad(b"hello");
+        padded[..LENGTH_SIZE].copy_from_slice(&(PADDING_BUCKET_SIZE as u32).to_le_bytes());
+        assert_eq!(unpad(&padded), None);
+        padded[..LENGTH_SIZE].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(unpad(&padded), None);
+    }
+}
@@ -0,0 +1,155 @@
+/// Amount of counters kept track of behind the highest received one
+pub const REPLAY_WINDOW_SIZE: u64 = 1024;
+const WORDS: usize = (REPLAY_WINDOW_SIZE / 64) as usize;
+
+/// Sliding window used to reject counters that have already been seen.
+///
+/// Counters that are older than the window are always rejected, since it's
+/// impossible to tell whether they have been received before.
+pub struct ReplayWindow {
+    /// Highest counter accepted so far
+    highest: Option<u64>,
+    /// Bit `i` is set if `highest - i` has been received
+    bitmap: [u64; WORDS],
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow {
+            highest: None,
+            bitmap: [0; WORDS],
+        }
+    }
+
+    /// Check whether the counter would be accepted, without marking it as received.
+    pub fn check(&self, counter: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) => {
+                let offset = highest - counter;
+                offset < REPLAY_WINDOW_SIZE && !self.get_bit(offset)
+            }
+        }
+    }
+
+    /// Mark the counter as received. Returns false if it has already been seen
+    /// or is too old to tell.
+    pub fn update(&mut self, counter: u64) -> bool {
+        if !self.check(counter) {
+            return false;
+        }
+        match self.highest {
+            Some(highest) if counter <= highest => {
+                self.set_bit(highest - counter);
+            }
+            Some(highest) => {
+                self.shift(counter - highest);
+                self.highest = Some(counter);
+                self.set_bit(0);
+            }
+            None => {
+                self.highest = Some(counter);
+                self.set_bit(0);
+            }
+        }
+        true
+    }
+
+    fn get_bit(&self, offset: u64) -> bool {
+        self.bitmap[(offset / 64) as usize] & (1 << (offset % 64)) != 0
+    }
+
+    fn set_bit(&mut self, offset: u64) {
+        self.bitmap[(offset / 64) as usize] |= 1 << (offset % 64);
+    }
+
+    /// Move every bit `by` positions towards the old end of the window
+    fn shift(&mut self, by: u64) {
+        if by >= REPLAY_WINDOW_SIZE {
+            self.bitmap = [0; WORDS];
+            return;
+        }
+        let words = (by / 64) as usize;
+        let bits = by % 64;
+        for i in (0..WORDS).rev() {
+            let mut word = 0;
+            if i >= words {
+                word = self.bitmap[i - words] << bits;
+                if bits != 0 && i > words {
+                    word |= self.bitmap[i - words - 1] >> (64 - bits);
+                }
+            }
+            self.bitmap[i] = word;
+        }
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        ReplayWindow::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut window = ReplayWindow::new();
+        assert!(window.update(5));
+        assert!(!window.check(5));
+        assert!(!window.update(5));
+        // Older counters are still accepted once
+        assert!(window.update(3));
+        assert!(!window.update(3));
+        assert!(window.update(4));
+    }
+
+    #[test]
+    fn rejects_counters_behind_the_window() {
+        let mut window = ReplayWindow::new();
+        assert!(window.update(REPLAY_WINDOW_SIZE + 10));
+        assert!(window.check(11));
+        assert!(!window.check(10));
+        assert!(!window.update(0));
+    }
+
+    #[test]
+    fn keeps_received_counters_across_words() {
+        let mut window = ReplayWindow::new();
+        for counter in [0, 1, 63, 64, 100] {
+            assert!(window.update(counter));
+        }
+        // Shifting by an amount which isn't a multiple of 64 moves bits across words
+        assert!(window.update(170));
+        for counter in [0, 1, 63, 64, 100, 170] {
+            assert!(!window.check(counter));
+        }
+        for counter in [2, 62, 65, 99, 169] {
+            assert!(window.check(counter));
+        }
+    }
+
+    #[test]
+    fn forgets_everything_after_a_large_jump() {
+        let mut window = ReplayWindow::new();
+        assert!(window.update(7));
+        assert!(window.update(7 + REPLAY_WINDOW_SIZE));
+        // The old counter has fallen out of the window, the new ones are clean
+        assert!(!window.check(7));
+        assert!(window.check(8));
+        assert!(window.check(6 + REPLAY_WINDOW_SIZE));
+    }
+
+    #[test]
+    fn handles_the_largest_counters() {
+        let mut window = ReplayWindow::new();
+        assert!(window.update(u64::MAX - 1));
+        assert!(window.update(u64::MAX));
+        assert!(!window.update(u64::MAX));
+        assert!(!window.update(u64::MAX - 1));
+        assert!(window.update(u64::MAX - 2));
+    }
+}
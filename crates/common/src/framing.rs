@@ -0,0 +1,188 @@
+//! Framing of the TCP messages sent between the clients and the rendezvous server, in both directions.
+//!
+//! Every frame is `[length][payload]`, where the length is a little endian u64 and the payload is
+//! `[message type][bincode body]`. Once the keys have been exchanged, the payload is encrypted with the
+//! session key and the length is authenticated as associated data. Before that, only the key exchange
+//! messages are sent unencrypted.
+
+use std::io::{self, Read, Write};
+
+use serde::Serialize;
+
+use crate::{encryption::{NONCE_LENGTH, SymmetricEncryption, TAG_LENGTH}, message_type::MsgType, protocol::{self, ProtocolError}};
+
+/// Size of the length which precedes every frame
+pub const LENGTH_SIZE: usize = 8;
+/// Most bytes read from the socket at once
+const READ_SIZE: usize = 64 * 1024;
+
+/// Serialize the message into a frame, encrypting it if a session key is given
+pub fn encode_frame<T: ?Sized>(t: MsgType, msg: &T, key: Option<&mut SymmetricEncryption>) -> Vec<u8> where T: Serialize {
+    let t: u8 = num::ToPrimitive::to_u8(&t).unwrap();
+    let payload = [&[t], &bincode::serialize(msg).unwrap()[..]].concat();
+
+    match key {
+        Some(key) => {
+            let length = ((payload.len() + NONCE_LENGTH + TAG_LENGTH) as u64).to_le_bytes();
+            let encrypted = key.encrypt_with_aad(&payload[..], &length);
+            [&length[..], &encrypted[..]].concat()
+        }
+        None => {
+            let length = (payload.len() as u64).to_le_bytes();
+            [&length[..], &payload[..]].concat()
+        }
+    }
+}
+
+/// Serialize the message and write it to the socket as a single frame
+pub fn write_frame<W: Write, T: ?Sized>(sock: &mut W, t: MsgType, msg: &T, key: Option<&mut SymmetricEncryption>) -> io::Result<()> where T: Serialize {
+    sock.write_all(&encode_frame(t, msg, key)[..])
+}
+
+/// Collects the bytes arriving on a non-blocking socket, until whole frames have arrived.
+/// A frame can arrive in any number of reads, so nothing waits for the rest of it.
+pub struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader {
+            buf: Vec::new(),
+        }
+    }
+
+    /// Read once from the socket, failing with `UnexpectedEof` once the other side has closed the connection
+    pub fn read_from<R: Read>(&mut self, sock: &mut R) -> io::Result<()> {
+        let mut chunk = [0u8; READ_SIZE];
+        match sock.read(&mut chunk)? {
+            0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The connection has been closed")),
+            read => {
+                self.buf.extend_from_slice(&chunk[..read]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Take the next frame which has fully arrived, including its length.
+    /// Frames longer than `protocol::MAX_MESSAGE_SIZE` are rejected as soon as their length arrives, so the stream can't be used afterwards.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ProtocolError> {
+        if self.buf.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+        let mut length = [0u8; LENGTH_SIZE];
+        length.copy_from_slice(&self.buf[..LENGTH_SIZE]);
+        let end = LENGTH_SIZE + protocol::frame_length(length)?;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+        let rest = self.buf.split_off(end);
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+/// Get the payload of a frame taken from a `FrameReader`, decrypting it if a session key is given
+pub fn open_frame(frame: &[u8], key: Option<&mut SymmetricEncryption>) -> Result<Vec<u8>, ProtocolError> {
+    if frame.len() < LENGTH_SIZE {
+        return Err(ProtocolError::Truncated);
+    }
+    let (length, payload) = frame.split_at(LENGTH_SIZE);
+    let mut length_bytes = [0u8; LENGTH_SIZE];
+    length_bytes.copy_from_slice(length);
+    if protocol::frame_length(length_bytes)? != payload.len() {
+        return Err(ProtocolError::Truncated);
+    }
+
+    match key {
+        Some(key) => Ok(key.decrypt_with_aad(payload, length)?),
+        None => Ok(payload.to_vec())
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        FrameReader::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::MAX_MESSAGE_SIZE;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"01234567890123456789012345678901";
+
+    fn payload(t: MsgType, msg: &str) -> Vec<u8> {
+        [&[num::ToPrimitive::to_u8(&t).unwrap()], &bincode::serialize(msg).unwrap()[..]].concat()
+    }
+
+    /// Feed the bytes to the reader a few at a time, like a socket would, collecting the frames
+    fn read_frames(bytes: &[u8], step: usize) -> Result<Vec<Vec<u8>>, ProtocolError> {
+        let mut reader = FrameReader::new();
+        let mut frames = vec![];
+        for mut chunk in bytes.chunks(step) {
+            reader.read_from(&mut chunk)?;
+            while let Some(frame) = reader.next_frame()? {
+                frames.push(frame);
+            }
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = [
+            encode_frame(MsgType::KeyExchange, "first", None),
+            encode_frame(MsgType::Announce, "second", None)
+        ].concat();
+        let frames = read_frames(&bytes, 3).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(open_frame(&frames[0], None).unwrap(), payload(MsgType::KeyExchange, "first"));
+        assert_eq!(open_frame(&frames[1], None).unwrap(), payload(MsgType::Announce, "second"));
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let mut sender = SymmetricEncryption::new_from_secret(SECRET);
+        let mut receiver = SymmetricEncryption::new_from_secret(SECRET);
+        let bytes = encode_frame(MsgType::Announce, "secret", Some(&mut sender));
+        let frames = read_frames(&bytes, 5).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(open_frame(&frames[0], Some(&mut receiver)).unwrap(), payload(MsgType::Announce, "secret"));
+    }
+
+    #[test]
+    fn rejects_a_tampered_length() {
+        let mut sender = SymmetricEncryption::new_from_secret(SECRET);
+        let mut receiver = SymmetricEncryption::new_from_secret(SECRET);
+        let mut frame = encode_frame(MsgType::Announce, "secret", Some(&mut sender));
+        // Claim that the frame is one byte longer, and add that byte
+        let mut length = [0u8; LENGTH_SIZE];
+        length.copy_from_slice(&frame[..LENGTH_SIZE]);
+        frame[..LENGTH_SIZE].copy_from_slice(&(u64::from_le_bytes(length) + 1).to_le_bytes());
+        frame.push(0);
+        assert!(matches!(open_frame(&frame, Some(&mut receiver)), Err(ProtocolError::Decryption(_))));
+    }
+
+    #[test]
+    fn rejects_a_length_which_doesnt_match() {
+        let mut frame = encode_frame(MsgType::Announce, "message", None);
+        frame.push(0);
+        assert!(matches!(open_frame(&frame, None), Err(ProtocolError::Truncated)));
+        assert!(matches!(open_frame(&frame[..LENGTH_SIZE - 1], None), Err(ProtocolError::Truncated)));
+    }
+
+    #[test]
+    fn rejects_a_huge_length_before_the_payload_arrives() {
+        let length = (MAX_MESSAGE_SIZE + 1).to_le_bytes();
+        assert!(matches!(read_frames(&length, LENGTH_SIZE), Err(ProtocolError::TooLarge)));
+    }
+
+    #[test]
+    fn reports_a_closed_connection() {
+        let mut reader = FrameReader::new();
+        let error = reader.read_from(&mut &[][..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{env, fmt, fs::{self, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}};
+
+use directories::ProjectDirs;
+use rsa::pkcs8;
+use zeroize::Zeroizing;
+
+use crate::encryption::AsymmetricEncryption;
+
+/// Name of the identity key file inside the data directory
+pub const IDENTITY_FILE: &str = "identity.pem";
+/// Name of the rendezvous server's identity key file inside the data directory
+pub const SERVER_IDENTITY_FILE: &str = "server_identity.pem";
+/// Environment variable which overrides the data directory
+const DATA_DIR_VAR: &str = "DATA_DIR";
+/// Environment variable containing the passphrase the identity key is encrypted with
+const PASSPHRASE_VAR: &str = "IDENTITY_PASSPHRASE";
+
+pub enum Error {
+    IOError(io::Error),
+    KeyError(pkcs8::Error),
+    /// The home directory of the user couldn't be found
+    NoDataDirectory,
+    /// Tried generating an identity, but one already exists
+    AlreadyExists(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IOError(e) => fmt::Display::fmt(e, f),
+            Error::KeyError(e) => write!(f, "Failed to read or write the key (wrong passphrase?): {}", e),
+            Error::NoDataDirectory => f.write_str("Couldn't find a directory to store the identity in."),
+            Error::AlreadyExists(path) => write!(f, "An identity already exists at: {}", path.display()),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IOError(e)
+    }
+}
+
+impl From<pkcs8::Error> for Error {
+    fn from(e: pkcs8::Error) -> Self {
+        Error::KeyError(e)
+    }
+}
+
+/// The per-user directory where p2pthing stores its data
+pub fn data_dir() -> Result<PathBuf, Error> {
+    match env::vars().find(|(k, _)| k == DATA_DIR_VAR) {
+        Some((_, v)) => Ok(PathBuf::from(v)),
+        None => ProjectDirs::from("", "", "p2pthing")
+            .map(|dirs| dirs.data_dir().to_path_buf())
+            .ok_or(Error::NoDataDirectory)
+    }
+}
+
+pub fn identity_path() -> Result<PathBuf, Error> {
+    Ok(data_dir()?.join(IDENTITY_FILE))
+}
+
+fn passphrase() -> Option<Zeroizing<String>> {
+    env::vars().find(|(k, _)| k == PASSPHRASE_VAR).map(|(_, v)| Zeroizing::new(v))
+}
+
+/// Load the identity key from a PEM file, decrypting it with the passphrase if one is set
+pub fn load_identity(path: &Path) -> Result<AsymmetricEncryption, Error> {
+    let pem = Zeroizing::new(fs::read_to_string(path)?);
+    let passphrase = passphrase();
+    Ok(AsymmetricEncryption::from_pem(&pem, passphrase.as_ref().map(|p| p.as_str()))?)
+}
+
+/// Save the identity key to a PEM file, encrypting it with the passphrase if one is set
+pub fn save_identity(identity: &AsymmetricEncryption, path: &Path) -> Result<(), Error> {
+    let passphrase = passphrase();
+    let pem = identity.to_pem(passphrase.as_ref().map(|p| p.as_str()))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Only the owner should be able to read the private key
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(pem.as_bytes())?;
+    Ok(())
+}
+
+/// Generate a new identity, refusing to overwrite an existing one
+pub fn generate_identity() -> Result<AsymmetricEncryption, Error> {
+    let path = identity_path()?;
+    if path.exists() {
+        return Err(Error::AlreadyExists(path));
+    }
+    let identity = AsymmetricEncryption::new();
+    save_identity(&identity, &path)?;
+    Ok(identity)
+}
+
+/// Load the identity from the data directory, generating one on the first launch
+pub fn load_or_generate() -> Result<AsymmetricEncryption, Error> {
+    load_or_generate_at(&identity_path()?)
+}
+
+/// Load the rendezvous server's identity from the data directory, generating one on the first launch.
+/// It has to stay the same between restarts, so clients can pin its fingerprint.
+pub fn load_or_generate_server() -> Result<AsymmetricEncryption, Error> {
+    load_or_generate_at(&data_dir()?.join(SERVER_IDENTITY_FILE))
+}
+
+fn load_or_generate_at(path: &Path) -> Result<AsymmetricEncryption, Error> {
+    match path.exists() {
+        true => load_identity(path),
+        false => {
+            let identity = AsymmetricEncryption::new();
+            save_identity(&identity, path)?;
+            Ok(identity)
+        }
+    }
+}
+
+/// Replace the stored identity with the one in the given PEM file
+pub fn import_identity(file: &Path) -> Result<AsymmetricEncryption, Error> {
+    let identity = load_identity(file)?;
+    save_identity(&identity, &identity_path()?)?;
+    Ok(identity)
+}
+
+/// Write the stored identity into the given PEM file
+pub fn export_identity(file: &Path) -> Result<(), Error> {
+    let identity = load_identity(&identity_path()?)?;
+    save_identity(&identity, file)
+}
@@ -3,16 +3,22 @@ pub mod encryption;
 pub mod debug_message;
 pub mod ui;
 pub mod statistics;
+pub mod identity;
+pub mod framing;
+pub mod protocol;
 
-use std::io::Read;
+use std::io::{self, Read};
 
-// TODO: Error handling
-pub fn read_exact<T>(sock: &mut T, buf: &mut [u8]) where T: Read {
+/// Fill the buffer from a blocking reader, failing with `UnexpectedEof` if the other side closes the connection first
+pub fn read_exact<T>(sock: &mut T, buf: &mut [u8]) -> io::Result<()> where T: Read {
     let mut read = 0;
     while read < buf.len() {
         match sock.read(&mut buf[read..]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The connection has been closed")),
             Ok(c) => read += c,
-            Err(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e)
         }
     }
+    Ok(())
 }
\ No newline at end of file
@@ -2,29 +2,45 @@ use std::{collections::HashMap, net::SocketAddr, fmt::Display};
 use num_derive::{FromPrimitive, ToPrimitive};
 use serde::{Serialize, Deserialize};
 
-use crate::statistics::Statistics;
+use crate::{protocol::{self, ProtocolError}, statistics::Statistics, ui::CallPolicy};
 
 use self::msg_types::{FileChunks, RequestFileChunks};
 
-use super::{debug_message::DebugMessageType, encryption::{NetworkedPublicKey, SymmetricEncryption}};
+use super::{debug_message::DebugMessageType, encryption::{NetworkedPublicKey, SymmetricEncryption, pad, unpad}};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum InterthreadMessage {
     SendChatMessage(NetworkedPublicKey, String, u32),
-    OnChatMessage(Peer, String),
+    /// The bool is whether the author's signature is valid
+    OnChatMessage(Peer, String, bool),
     OnChatMessageReceived(u32), //u32 is the custom_id
+    /// The chat message with the custom_id couldn't be delivered, the peer didn't acknowledge it
+    OnChatMessageFailed(u32),
     AnnounceResponse(Vec<Peer>),
     CallAccepted(NetworkedPublicKey),
     CallDenied(NetworkedPublicKey),
     PunchThroughSuccessfull(NetworkedPublicKey),
     Quit(),
     PeerDisconnected(NetworkedPublicKey),
+    /// - **From CM to client:** Nothing has been received from the peer for a while, the punch through is being done again
+    ConnectionLost(NetworkedPublicKey),
+    /// - **From CM to client:** The peer couldn't be reached again, so it has to be called again.
+    ///   The peer is still announced, and a `PunchThroughSuccessfull` follows if it comes back on its own.
+    ConnectionClosed(NetworkedPublicKey),
     Call(NetworkedPublicKey),
     OpusPacketReady(Vec<u8>),
     AudioDataReadyToBeProcessed(Vec<f32>),
     DebugMessage(String, DebugMessageType),
     ConnectToServer(),
     ConnectionStatistics(Vec<(NetworkedPublicKey, Statistics)>),
+    // TRUST
+    /// - **From CM to client:** The peer announced a different key than the one first seen with its name
+    PeerKeyChanged(Peer),
+    /// - **From client to CM:** The user verified the peer's new key, so replace the stored one
+    TrustPeerKey(Peer),
+    /// - **From CM to client:** The stored call policy of a peer, sent when it's announced and after it's changed
+    /// - **From client to CM:** Change and store the call policy of a peer
+    CallPolicy(NetworkedPublicKey, CallPolicy),
     // AUDIO
     AudioChangeInputDevice(String),
     AudioChangeOutputDevice(String),
@@ -55,7 +71,7 @@ pub enum InterthreadMessage {
 #[derive(ToPrimitive, FromPrimitive)]
 pub enum MsgType {
     Announce=0,
-    AnnounceSecret=8,
+    KeyExchange=8,
     Call=1,
     CallResponse=2,
     Disconnect=3,
@@ -67,7 +83,18 @@ pub enum MsgType {
     OpusPacket=10,
     SendFilesRequest=11,
     RequestFileChunks=12,
-    FileChunks=13
+    FileChunks=13,
+    NoiseHandshake=14,
+    AnnounceChallenge=15,
+    AnnounceProof=16,
+    RatchetInit=17,
+    Rekey=18,
+    RekeyAck=19,
+    MtuProbe=20,
+    MtuProbeAck=21,
+    PathChallenge=22,
+    PathResponse=23,
+    Skipped=24
 }
 
 #[derive(Serialize, Deserialize)]
@@ -75,6 +102,8 @@ pub struct Peer {
     pub addr: Option<SocketAddr>,
     pub udp_addr: Option<SocketAddr>,
     pub public_key: NetworkedPublicKey,
+    /// The name the peer announced itself with
+    pub name: String,
     #[serde(skip)]
     pub sym_key: Option<SymmetricEncryption>
 }
@@ -91,6 +120,7 @@ impl Peer {
     fn full_clone(&self) -> Self {
         Self {
             public_key: self.public_key.clone(),
+            name: self.name.clone(),
             addr: self.addr.clone(),
             udp_addr: self.udp_addr.clone(),
             sym_key: None,
@@ -101,6 +131,7 @@ impl Peer {
     pub fn safe_clone(&self) -> Self{
         Self {
             public_key: self.public_key.clone(),
+            name: self.name.clone(),
             addr: None,
             udp_addr: None,
             sym_key: None,
@@ -117,7 +148,48 @@ impl PartialEq for Peer {
 pub enum MsgEncryption {
     Unencrypted,
     PublicKey,
-    SymmetricKey
+    SymmetricKey,
+    /// The whole packet, including the header fields, is padded and encrypted with the symmetric key
+    Sealed
+}
+
+/// Reliable messages are delivered in order within their stream, but the streams don't wait for each other
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Stream {
+    /// Handshakes, key changes and everything else
+    Control=0,
+    Chat=1,
+    /// File requests, the file chunks themselves are unreliable
+    FileMetadata=2
+}
+
+impl Stream {
+    pub const COUNT: usize = 3;
+
+    /// The stream which carries the reliable messages of the type
+    pub fn of(msg_type: &MsgType) -> Stream {
+        match msg_type {
+            MsgType::ChatMessage => Stream::Chat,
+            MsgType::SendFilesRequest | MsgType::RequestFileChunks => Stream::FileMetadata,
+            _ => Stream::Control
+        }
+    }
+}
+
+/// The position of a reliable message in its stream
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct StreamHeader {
+    pub stream: Stream,
+    pub seq: u32
+}
+
+/// Identifies a part of a packet which has been split, because it wouldn't fit into a single datagram
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct FragmentHeader {
+    /// Every fragment of the packet has the same id
+    pub id: u32,
+    pub index: u16,
+    pub count: u16
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -125,18 +197,96 @@ pub struct UdpPacket {
     pub data: Vec<u8>,
     pub reliable: bool,
     pub msg_id: u32,
+    /// Only reliable messages are part of a stream
+    pub stream: Option<StreamHeader>,
+    /// Set if the data is only a part of the packet's data
+    pub fragment: Option<FragmentHeader>,
+    /// Identifies the sender's connection, so it's still found after the sender's address changes.
+    /// Only packets sent to peers have one.
+    pub connection_id: Option<u64>,
     pub upgraded: MsgEncryption
 }
 
+impl UdpPacket {
+    /// The header fields which are authenticated along with the encrypted data, so they can't be changed on the way.
+    /// The fragment header isn't part of it, since the data is encrypted before it's split.
+    fn associated_data(&self) -> Vec<u8> {
+        bincode::serialize(&(self.reliable, self.msg_id, self.stream, self.connection_id)).unwrap()
+    }
+
+    /// Every header field of a fragment, since it's authenticated before the packet is put back together
+    fn fragment_associated_data(&self) -> Vec<u8> {
+        bincode::serialize(&(self.reliable, self.msg_id, self.stream, self.fragment, self.connection_id, &self.upgraded)).unwrap()
+    }
+
+    /// Encrypt the data, once the header fields are final
+    pub fn encrypt(&self, key: &mut SymmetricEncryption) -> UdpPacket {
+        UdpPacket {
+            data: key.encrypt_with_aad(&self.data[..], &self.associated_data()),
+            reliable: self.reliable,
+            msg_id: self.msg_id,
+            stream: self.stream,
+            fragment: self.fragment,
+            connection_id: self.connection_id,
+            upgraded: MsgEncryption::SymmetricKey
+        }
+    }
+
+    /// Recover the data hidden by `encrypt`, failing if either the data or the header fields have been changed
+    pub fn decrypt(&self, key: &mut SymmetricEncryption) -> Result<Vec<u8>, ProtocolError> {
+        Ok(key.decrypt_with_aad(&self.data[..], &self.associated_data())?)
+    }
+
+    /// Encrypt a fragment of an encrypted packet again, so forged fragments can't poison the packet they claim to be a part of
+    pub fn encrypt_fragment(&self, key: &mut SymmetricEncryption) -> UdpPacket {
+        UdpPacket {
+            data: key.encrypt_with_aad(&self.data[..], &self.fragment_associated_data()),
+            ..self.clone()
+        }
+    }
+
+    /// Recover the fragment hidden by `encrypt_fragment`, failing if either the data or the header fields have been changed
+    pub fn decrypt_fragment(&self, key: &mut SymmetricEncryption) -> Result<UdpPacket, ProtocolError> {
+        Ok(UdpPacket {
+            data: key.decrypt_with_aad(&self.data[..], &self.fragment_associated_data())?,
+            ..self.clone()
+        })
+    }
+
+    /// Hide the header fields and the length of the data, by padding and encrypting the whole packet.
+    /// The outer packet's header fields are always the same, except for the connection id, which is needed to find the key.
+    pub fn seal(&self, key: &mut SymmetricEncryption) -> UdpPacket {
+        let padded = pad(&bincode::serialize(self).unwrap()[..]);
+        UdpPacket {
+            data: key.encrypt(&padded[..]),
+            reliable: false,
+            msg_id: 0,
+            stream: None,
+            fragment: None,
+            connection_id: self.connection_id,
+            upgraded: MsgEncryption::Sealed
+        }
+    }
+
+    /// Recover the packet hidden by `seal`
+    pub fn unseal(&self, key: &mut SymmetricEncryption) -> Result<UdpPacket, ProtocolError> {
+        let padded = key.decrypt(&self.data[..])?;
+        let inner = unpad(&padded[..]).ok_or(ProtocolError::InvalidPadding)?;
+        protocol::decode_udp_packet(inner)
+    }
+}
+
 pub type FileId = String;
 
 /// A file which has been split into transmittable chunks
 #[derive(Clone, Serialize, Deserialize)]
 pub struct SplitFile {
-    /// This is a base64 value that is obtained by hashing the filename and file size
+    /// This is a base64 value that is obtained by hashing the filename, file size and chunk size
     pub file_id: FileId,
     pub file_name: String,
     pub total_length: u64,
+    /// The size of every chunk but the last one, chosen so a chunk fills a datagram on the path to the receiver
+    pub chunk_size: usize,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -147,7 +297,7 @@ pub struct FileChunk {
 
 impl Display for FileChunk {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}[{}]", &self.file_id[0..10], self.index)
+        write!(f, "{}[{}]", self.file_id.get(0..10).unwrap_or(&self.file_id), self.index)
     }
 }
 
@@ -163,26 +313,68 @@ pub struct FileDataChunk {
 pub mod msg_types {
     use std::net::SocketAddr;
 
+    use chrono::Utc;
     use serde::{Serialize, Deserialize};
-    use crate::encryption::NetworkedPublicKey;
+    use crate::{encryption::{AsymmetricEncryption, NetworkedPublicKey, RatchetHeader}, protocol::Capabilities};
 
     use super::{FileChunk, FileDataChunk, SplitFile};
+
+    /// Prepended to the signed chat message fields, so the signature can't be reused elsewhere
+    const CHAT_MESSAGE_CONTEXT: &[u8] = b"p2pthing chat message";
     
     /// The server announced itself to the client, requesting an announcement.
+    /// Also starts the key exchange with the server's signed ephemeral key.
+    /// The client signs this ephemeral key in its response, proving that it owns its identity key.
     #[derive(Serialize, Deserialize)]
     pub struct AnnounceRequest {
-        pub public_key: NetworkedPublicKey
+        pub public_key: NetworkedPublicKey,
+        pub ephemeral_key: Vec<u8>,
+        pub signature: Vec<u8>,
+        /// The server's protocol version and capabilities
+        pub version: u16,
+        pub capabilities: Capabilities
     }
 
     
     #[derive(Serialize, Deserialize)]
     pub struct AnnouncePublic {
         pub public_key: NetworkedPublicKey,
+        pub name: String,
+        /// The client's protocol version and capabilities
+        pub version: u16,
+        pub capabilities: Capabilities
+    }
+    /// Client sends its signed ephemeral key to either the server, or another peer
+    #[derive(Serialize, Deserialize)]
+    pub struct KeyExchange {
+        pub public_key: NetworkedPublicKey,
+        pub ephemeral_key: Vec<u8>,
+        pub signature: Vec<u8>,
+        /// The sender's protocol version and capabilities
+        pub version: u16,
+        pub capabilities: Capabilities
+    }
+
+    /// The server challenges the client to prove that it owns the public key it announced over UDP
+    #[derive(Serialize, Deserialize)]
+    pub struct AnnounceChallenge {
+        pub challenge: Vec<u8>
+    }
+
+    /// The client's signature of the challenge, sent from the address which is being announced
+    #[derive(Serialize, Deserialize)]
+    pub struct AnnounceProof {
+        pub public_key: NetworkedPublicKey,
+        pub signature: Vec<u8>
     }
-    /// Client announces its secret to either the server, or another peer
+
+    /// A single message of a Noise handshake between two peers
     #[derive(Serialize, Deserialize)]
-    pub struct AnnounceSecret {
-        pub secret: Vec<u8>
+    pub struct NoiseHandshake {
+        pub message: Vec<u8>,
+        /// The sender's protocol version and capabilities
+        pub version: u16,
+        pub capabilities: Capabilities
     }
 
     
@@ -200,9 +392,66 @@ pub mod msg_types {
         pub response: bool
     }
 
+    /// A chat message signed by its author, so it can be verified no matter who delivered it
     #[derive(Serialize, Deserialize)]
     pub struct ChatMessage {
         pub msg: String,
+        /// Random identifier chosen by the author
+        pub id: u64,
+        /// Milliseconds since the unix epoch, when the message was written
+        pub timestamp: i64,
+        pub author: NetworkedPublicKey,
+        /// The author's signature over the fields above
+        pub signature: Vec<u8>
+    }
+
+    impl ChatMessage {
+        pub fn new(msg: String, identity: &AsymmetricEncryption) -> ChatMessage {
+            let mut chat_message = ChatMessage {
+                msg,
+                id: rand::random(),
+                timestamp: Utc::now().timestamp_millis(),
+                author: identity.get_public_key(),
+                signature: vec![]
+            };
+            chat_message.signature = identity.sign(&chat_message.signed_data());
+            chat_message
+        }
+
+        /// Check whether the message was signed by its author
+        pub fn verify(&self) -> bool {
+            self.author.verify(&self.signed_data(), &self.signature)
+        }
+
+        fn signed_data(&self) -> Vec<u8> {
+            let fields = bincode::serialize(&(&self.author, self.id, self.timestamp, &self.msg)).unwrap();
+            [CHAT_MESSAGE_CONTEXT, &fields[..]].concat()
+        }
+    }
+
+    /// Starts replacing the session key, with a new ephemeral key of the side which started it
+    #[derive(Serialize, Deserialize)]
+    pub struct Rekey {
+        pub ephemeral_key: Vec<u8>
+    }
+
+    /// Answers a rekey with our ephemeral key. We switch to the new key once the other side uses it.
+    #[derive(Serialize, Deserialize)]
+    pub struct RekeyAck {
+        pub ephemeral_key: Vec<u8>
+    }
+
+    /// Starts a double ratchet session for the chat messages between two identities
+    #[derive(Serialize, Deserialize)]
+    pub struct RatchetInit {
+        pub ratchet_key: Vec<u8>
+    }
+
+    /// A signed `ChatMessage`, encrypted with the double ratchet shared by the two identities
+    #[derive(Serialize, Deserialize)]
+    pub struct EncryptedChatMessage {
+        pub header: RatchetHeader,
+        pub ciphertext: Vec<u8>
     }
 
     #[derive(Serialize, Deserialize)]
@@ -215,11 +464,47 @@ pub mod msg_types {
         pub public_key: NetworkedPublicKey
     }
 
+    /// Acknowledges the received reliable messages, both cumulatively and selectively
+    #[derive(Serialize, Deserialize)]
+    pub struct Acknowledgement {
+        /// Every reliable message before this id has been received
+        pub next_id: u32,
+        /// Bit `i` is set if the message `next_id + 1 + i` has been received
+        pub received: u64
+    }
+
+    /// Takes the place of a reliable message which the sender has given up on, so the receiver can move past it
+    #[derive(Serialize, Deserialize)]
+    pub struct Skipped {
+        /// The type of the message which has been given up on
+        pub msg_type: u8
+    }
+
+    /// Padded to a size, to check whether the path to the peer can carry datagrams that large
+    #[derive(Serialize, Deserialize)]
+    pub struct MtuProbe {
+        pub id: u32,
+        pub padding: Vec<u8>
+    }
+
+    /// The probe has arrived
     #[derive(Serialize, Deserialize)]
-    pub struct ReliableMessageReceived {
+    pub struct MtuProbeAck {
         pub id: u32
     }
 
+    /// Sent to the new address of a peer, which has to answer it from there, before the connection is moved
+    #[derive(Serialize, Deserialize)]
+    pub struct PathChallenge {
+        pub token: u64
+    }
+
+    /// The answer to a challenge, with its token
+    #[derive(Serialize, Deserialize)]
+    pub struct PathResponse {
+        pub token: u64
+    }
+
     #[derive(Serialize, Deserialize)]
     pub struct SendFilesRequest {
         pub files: Vec<SplitFile>,
@@ -235,4 +520,67 @@ pub mod msg_types {
         pub chunks: Vec<FileDataChunk>
     }
     
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> UdpPacket {
+        UdpPacket {
+            data: b"hello".to_vec(),
+            reliable: true,
+            msg_id: 7,
+            stream: Some(StreamHeader {stream: Stream::Chat, seq: 3}),
+            fragment: None,
+            connection_id: Some(5),
+            upgraded: MsgEncryption::Sealed
+        }
+    }
+
+    /// Both sides of a connection, which share a key
+    fn keys() -> (SymmetricEncryption, SymmetricEncryption) {
+        let secret = [7u8; 32];
+        (SymmetricEncryption::new_from_secret(&secret), SymmetricEncryption::new_from_secret(&secret))
+    }
+
+    #[test]
+    fn seals_and_unseals() {
+        let (mut ours, mut theirs) = keys();
+        let sealed = packet().seal(&mut ours);
+        assert!(!sealed.reliable && sealed.msg_id == 0 && sealed.stream.is_none());
+        assert_eq!(sealed.connection_id, Some(5));
+
+        let unsealed = sealed.unseal(&mut theirs).unwrap();
+        assert_eq!(unsealed.data, b"hello");
+        assert!(unsealed.reliable && unsealed.msg_id == 7);
+        assert_eq!(unsealed.stream.unwrap().seq, 3);
+    }
+
+    #[test]
+    fn sealed_packets_have_the_same_size() {
+        let (mut ours, _) = keys();
+        let mut large = packet();
+        large.data = vec![1; 500];
+        assert_eq!(packet().seal(&mut ours).data.len(), large.seal(&mut ours).data.len());
+    }
+
+    #[test]
+    fn rejects_a_malformed_length_prefix() {
+        let (mut ours, mut theirs) = keys();
+        let mut padded = pad(&bincode::serialize(&packet()).unwrap());
+        padded[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut sealed = packet().seal(&mut ours);
+        sealed.data = ours.encrypt(&padded);
+        assert!(matches!(sealed.unseal(&mut theirs), Err(ProtocolError::InvalidPadding)));
+    }
+
+    #[test]
+    fn rejects_a_truncated_packet_inside_the_padding() {
+        let (mut ours, mut theirs) = keys();
+        let serialized = bincode::serialize(&packet()).unwrap();
+        let mut sealed = packet().seal(&mut ours);
+        sealed.data = ours.encrypt(&pad(&serialized[..serialized.len() - 1]));
+        assert!(sealed.unseal(&mut theirs).is_err());
+    }
+}
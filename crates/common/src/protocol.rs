@@ -0,0 +1,170 @@
+use std::{fmt::Display, io, ops::{BitAnd, BitOr}};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{encryption::DecryptionError, framing::LENGTH_SIZE, message_type::{MsgType, UdpPacket}};
+
+/// Version of the protocol spoken by this build.
+/// It's raised whenever the messages change in a way which the builds before can't read.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The oldest version this build can still talk to. Version 1 didn't authenticate the headers of the UDP packets.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+/// The largest message which is decoded. Larger ones are rejected before anything is allocated for them,
+/// so a length field can't make us allocate more than this.
+pub const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Optional features which the other side understands. A feature is only used, if both sides have it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Packets with encrypted headers, which are sent in privacy mode
+    pub const SEALED: Capabilities = Capabilities(1 << 0);
+    /// Answers path MTU probes
+    pub const MTU_PROBES: Capabilities = Capabilities(1 << 1);
+    /// Answers path challenges, so the connection can follow it to a new address
+    pub const MIGRATION: Capabilities = Capabilities(1 << 2);
+    /// Plays the voice packets
+    pub const AUDIO: Capabilities = Capabilities(1 << 3);
+    /// Sends and receives files
+    pub const FILES: Capabilities = Capabilities(1 << 4);
+
+    pub const fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 & rhs.0)
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// What two sides speak with each other: the older of their versions, and the features both of them have
+#[derive(Clone, Copy)]
+pub struct Negotiated {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Negotiated {
+    /// Agree on the version and the features, failing if the other side's version is too old.
+    /// A newer version is fine, the newer side is the one which has to stay compatible.
+    pub fn new(ours: Capabilities, version: u16, capabilities: Capabilities) -> Result<Negotiated, String> {
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(format!("Protocol version {} is too old, at least version {} is needed", version, MIN_PROTOCOL_VERSION));
+        }
+        Ok(Negotiated {
+            version: version.min(PROTOCOL_VERSION),
+            capabilities: ours & capabilities,
+        })
+    }
+}
+
+/// Why bytes received from the network were rejected
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The message doesn't even have a type
+    Empty,
+    /// The frame is shorter than its length says
+    Truncated,
+    /// The message is larger than `MAX_MESSAGE_SIZE`
+    TooLarge,
+    /// The bytes aren't a valid encoding of the expected message
+    Malformed(bincode::Error),
+    /// The message couldn't be decrypted
+    Decryption(DecryptionError),
+    /// The padding of a sealed packet is invalid
+    InvalidPadding,
+    /// The connection failed while the message was read
+    Io(io::Error),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Empty => f.write_str("Message is empty."),
+            ProtocolError::Truncated => f.write_str("Frame doesn't match its length."),
+            ProtocolError::TooLarge => write!(f, "Message is larger than the {} bytes allowed.", MAX_MESSAGE_SIZE),
+            ProtocolError::Malformed(e) => write!(f, "Message is malformed: {}", e),
+            ProtocolError::Decryption(e) => e.fmt(f),
+            ProtocolError::InvalidPadding => f.write_str("Message has an invalid padding."),
+            ProtocolError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<bincode::Error> for ProtocolError {
+    fn from(e: bincode::Error) -> ProtocolError {
+        match *e {
+            bincode::ErrorKind::SizeLimit => ProtocolError::TooLarge,
+            _ => ProtocolError::Malformed(e)
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> ProtocolError {
+        ProtocolError::Io(e)
+    }
+}
+
+impl From<DecryptionError> for ProtocolError {
+    fn from(e: DecryptionError) -> ProtocolError {
+        ProtocolError::Decryption(e)
+    }
+}
+
+// The functions below are the only ones which parse bytes received from the network.
+// They never panic, whatever the bytes are, so they are also the entry points for fuzzing.
+
+/// Decode a message body, the same way `bincode::serialize` encodes it, but never reading more than `MAX_MESSAGE_SIZE`
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, ProtocolError> {
+    let options = bincode::DefaultOptions::new()
+    .with_fixint_encoding()
+    .allow_trailing_bytes()
+    .with_limit(MAX_MESSAGE_SIZE);
+    Ok(options.deserialize(data)?)
+}
+
+/// Decode a datagram, as it was received from the socket
+pub fn decode_udp_packet(data: &[u8]) -> Result<UdpPacket, ProtocolError> {
+    decode(data)
+}
+
+/// Split a decrypted message into its type and its body. The type is `None` if it's unknown to us.
+pub fn split_message(data: &[u8]) -> Result<(Option<MsgType>, &[u8]), ProtocolError> {
+    match data.split_first() {
+        Some((msg_type, body)) => Ok((num::FromPrimitive::from_u8(*msg_type), body)),
+        None => Err(ProtocolError::Empty)
+    }
+}
+
+/// Size of the frame which follows the length read from the TCP stream, see `framing`
+pub fn frame_length(length: [u8; LENGTH_SIZE]) -> Result<usize, ProtocolError> {
+    match u64::from_le_bytes(length) {
+        length if length > MAX_MESSAGE_SIZE => Err(ProtocolError::TooLarge),
+        length => Ok(length as usize)
+    }
+}
@@ -1,4 +1,7 @@
+use std::fmt::Display;
+
 use mio_misc::channel::Sender;
+use serde::{Serialize, Deserialize};
 
 use crate::{debug_message::DebugMessageType, encryption::NetworkedPublicKey, message_type::InterthreadMessage};
 
@@ -21,7 +24,9 @@ pub enum CallStatus {
     PunchThroughSuccessfull,
     PunchThroughInProgress,
     SentRequest,
-    RequestFailed
+    RequestFailed,
+    /// The peer stopped answering, and the punch through is being done again
+    Reconnecting
 }
 
 pub struct CallStatusHolder {
@@ -29,6 +34,44 @@ pub struct CallStatusHolder {
     pub public_key: NetworkedPublicKey
 }
 
+/// What to do with the incoming calls of a peer
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum CallPolicy {
+    /// Ask the user whether to accept the call
+    Ask,
+    /// Deny the call without notifying the user
+    Block,
+    /// Accept the call without asking the user
+    AutoAccept
+}
+
+impl CallPolicy {
+    /// The policy after this one, used for cycling through them
+    pub fn next(&self) -> CallPolicy {
+        match self {
+            CallPolicy::Ask => CallPolicy::AutoAccept,
+            CallPolicy::AutoAccept => CallPolicy::Block,
+            CallPolicy::Block => CallPolicy::Ask,
+        }
+    }
+}
+
+impl Default for CallPolicy {
+    fn default() -> Self {
+        CallPolicy::Ask
+    }
+}
+
+impl Display for CallPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallPolicy::Ask => f.write_str("Always ask"),
+            CallPolicy::Block => f.write_str("Blocked"),
+            CallPolicy::AutoAccept => f.write_str("Auto-accept"),
+        }
+    }
+}
+
 /// Helper trait for logging messages
 pub trait UIConn {
     fn log_message(&self, msg: &str, msg_type: DebugMessageType);
@@ -1,4 +1,4 @@
-import { CallStatus, ChatMessage, GuiData, UIPeer } from "./interfaces";
+import { CallPolicy, CallStatus, ChatMessage, GuiData, NetworkedPublicKey, UIPeer } from "./interfaces";
 
 type Handler = (data: GuiData, event_data: any) => GuiData | void;
 export class EventHandler {
@@ -28,13 +28,18 @@ export function build_event_handler(): EventHandler {
 		.add_handler("PeerDisconnected", on_peer_disconnected)
 		.add_handler("CallDenied", on_call_denied)
 		.add_handler("PunchThroughSuccessfull", on_punchthrough_successfull)
+		.add_handler("ConnectionLost", on_connection_lost)
+		.add_handler("ConnectionClosed", on_connection_closed)
 		.add_handler("Call", on_call)
 		.add_handler("CallAccepted", on_call_accepted)
 		.add_handler("OnChatMessage", on_chat_message)
 		.add_handler("OnChatMessageReceived", on_chat_message_received)
+		.add_handler("OnChatMessageFailed", on_chat_message_failed)
 		.add_handler("AudioNewInputDevices", on_audio_new_input_devices)
 		.add_handler("AudioNewOutputDevices", on_audio_new_output_devices)
-		.add_handler("ConnectionStatistics", on_connection_statistics);
+		.add_handler("ConnectionStatistics", on_connection_statistics)
+		.add_handler("PeerKeyChanged", on_peer_key_changed)
+		.add_handler("CallPolicy", on_call_policy);
 	return event_handler;
 }
 
@@ -62,6 +67,17 @@ function on_punchthrough_successfull(data: GuiData, public_key: any) {
 	return data;
 }
 
+function on_connection_lost(data: GuiData, public_key: any) {
+	data.p(public_key).call_status = CallStatus.Reconnecting;
+	return data;
+}
+
+/** The peer can be called again */
+function on_connection_closed(data: GuiData, public_key: any) {
+	data.p(public_key).call_status = CallStatus.None;
+	return data;
+}
+
 function on_call_denied(data: GuiData, public_key: any) {
 	data.p(public_key).call_status = CallStatus.RequestFailed;
 	return data;
@@ -80,9 +96,10 @@ function on_call_accepted(data: GuiData, public_key: any) {
 function on_chat_message(data: GuiData, ev: any[]) {
 	let msg_peer: UIPeer = ev[0];
 	let msg: string = ev[1];
+	let verified: boolean = ev[2];
 
 	let p = data.peers.find((p) => p.public_key.equals(new UIPeer(msg_peer).public_key));
-	p.messages.push(new ChatMessage(p.public_key, msg));
+	p.messages.push(new ChatMessage(p.public_key, msg, undefined, undefined, verified));
 	return data;
 }
 
@@ -96,8 +113,31 @@ function on_chat_message_received(data: GuiData, custom_id: number) {
 	return data;
 }
 
+function on_chat_message_failed(data: GuiData, custom_id: number) {
+	for (const peer of data.peers) {
+		for (const msg of peer.messages) {
+			if (msg.custom_id === custom_id) msg.failed = true;
+		}
+	}
+
+	return data;
+}
+
 function on_audio_new_input_devices(data: GuiData, debug_data: any) {}
 
 function on_audio_new_output_devices(data: GuiData, debug_data: any) {}
 
 function on_connection_statistics(data: GuiData, debug_data: any) {}
+
+function on_peer_key_changed(data: GuiData, peer: any) {
+	let p = data.p(new UIPeer(peer).public_key);
+	if (p) p.key_changed = true;
+	console.error(`The key of peer ${peer.name} has changed since it was first seen!`);
+	return data;
+}
+
+function on_call_policy(data: GuiData, policy_data: any) {
+	let p = data.p(new NetworkedPublicKey(policy_data[0]));
+	if (p) p.call_policy = policy_data[1];
+	return data;
+}
@@ -14,15 +14,22 @@ export class GuiData {
 
 export interface IPeer {
 	public_key: NetworkedPublicKey;
+	name: string;
 }
 
 export class UIPeer implements IPeer {
 	public_key: NetworkedPublicKey;
+	name: string;
 	call_status: CallStatus = CallStatus.None;
 	messages: ChatMessage[] = [];
+	/** The peer's key is different from the one first seen with its name */
+	key_changed: boolean = false;
+	/** How the peer's incoming calls are handled, only editable from the TUI for now */
+	call_policy: CallPolicy = CallPolicy.Ask;
 
 	constructor(p: IPeer) {
 		this.public_key = new NetworkedPublicKey(p.public_key);
+		this.name = p.name;
 	}
 
 	equals(other: UIPeer): boolean {
@@ -56,6 +63,14 @@ export enum CallStatus {
 	RequestFailed,
 	PunchthroughSuccessfull,
 	WaitingForAnswer,
+	Reconnecting,
+}
+
+/** Serialized as the name of the variant */
+export enum CallPolicy {
+	Ask = "Ask",
+	Block = "Block",
+	AutoAccept = "AutoAccept",
 }
 
 export class ChatMessage {
@@ -63,16 +78,22 @@ export class ChatMessage {
 	contents: string;
 	custom_id?: number;
 	received?: boolean;
+	/** The peer never acknowledged our message */
+	failed: boolean = false;
+	/** Whether the author's signature is valid, own messages are always verified */
+	verified: boolean;
 
 	constructor(
 		author: NetworkedPublicKey,
 		contents: string,
 		custom_id?: number,
-		received?: boolean
+		received?: boolean,
+		verified: boolean = true
 	) {
 		this.author = author;
 		this.contents = contents;
 		this.custom_id = custom_id;
 		this.received = received;
+		this.verified = verified;
 	}
 }
@@ -0,0 +1,53 @@
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+use p2pthing_common::{encryption::NetworkedPublicKey, identity, ui::CallPolicy};
+
+/// Name of the call policy file inside the data directory
+const CALL_POLICY_FILE: &str = "call_policies";
+
+/// Remembers how the incoming calls of every peer should be handled
+pub struct CallPolicyStore {
+    path: Option<PathBuf>,
+    policies: HashMap<NetworkedPublicKey, CallPolicy>,
+}
+
+impl CallPolicyStore {
+    /// Load the store from the data directory. If it can't be read, start with an empty one.
+    pub fn load() -> CallPolicyStore {
+        let path = identity::data_dir().ok().map(|dir| dir.join(CALL_POLICY_FILE));
+        let policies = path.as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| bincode::deserialize(&data[..]).ok())
+            .unwrap_or_default();
+
+        CallPolicyStore {
+            path,
+            policies,
+        }
+    }
+
+    /// The policy of the peer, peers without a stored policy are always asked about
+    pub fn get(&self, public_key: &NetworkedPublicKey) -> CallPolicy {
+        self.policies.get(public_key).copied().unwrap_or_default()
+    }
+
+    /// Store the policy of the peer, replacing the previous one
+    pub fn set(&mut self, public_key: &NetworkedPublicKey, policy: CallPolicy) -> io::Result<()> {
+        match policy {
+            CallPolicy::Ask => self.policies.remove(public_key),
+            _ => self.policies.insert(public_key.clone(), policy),
+        };
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "Couldn't find a directory to store the call policies in"))
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, bincode::serialize(&self.policies).unwrap())
+    }
+}
@@ -1,16 +1,24 @@
 use super::connection_manager::ConnectionManager;
-use p2pthing_common::ui::{UI, UIType};
+use p2pthing_common::{identity, ui::{UI, UIType}};
 use p2pthing_gui::gui::Gui;
 use p2pthing_tui::tui::Tui;
 
 
 pub fn start_client(ip: String, ui_type: UIType) {
+    let encryption = match identity::load_or_generate() {
+        Ok(encryption) => encryption,
+        Err(e) => {
+            println!("Failed to load the identity key: {}", e);
+            return;
+        }
+    };
+
     let mut ui = match ui_type {
         UIType::TUI => Box::new(Tui::new()) as Box<dyn UI>,
         UIType::GUI => Box::new(Gui::new()) as Box<dyn UI>,
     };
 
-    let (cm_s, cm_thr, own_public_key) = ConnectionManager::start(ip, ui.get_notifier());
+    let (cm_s, cm_thr, own_public_key) = ConnectionManager::start(ip, ui.get_notifier(), encryption);
     
     ui.main_loop(cm_s.clone(), own_public_key);
     
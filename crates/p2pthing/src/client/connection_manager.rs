@@ -1,12 +1,12 @@
 use mio_misc::{NotificationId, channel::channel, queue::NotificationQueue};
 use mio::{Interest, Poll, Waker, net::{TcpStream, UdpSocket}};
-use p2pthing_common::{encryption::{AsymmetricEncryption, NetworkedPublicKey, SymmetricEncryption}, message_type::{InterthreadMessage, MsgType, Peer, UdpPacket, msg_types::Call}};
-use std::{net::SocketAddr, rc::Rc, str::FromStr, sync::{Arc}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use p2pthing_common::{encryption::{AsymmetricEncryption, DoubleRatchet, NetworkedPublicKey, RatchetHandshake}, framing::FrameReader, message_type::{InterthreadMessage, MsgType, Peer, UdpPacket, msg_types::Call}, protocol::Capabilities};
+use std::{collections::HashMap, env, net::SocketAddr, rc::Rc, str::FromStr, sync::{Arc}, thread::{self, JoinHandle}, time::{Duration, Instant}};
 use mio_misc::channel::Sender;
 
 use mio::Token;
 
-use super::{audio::Audio, file_manager::FileManager, udp_connection::{UdpConnection, UdpConnectionState}};
+use super::{audio::Audio, call_policy_store::CallPolicyStore, file_manager::FileManager, trust_store::TrustStore, udp_connection::{DEFAULT_SEND_RATE, HandshakeMode, UdpConnection, UdpConnectionState}};
 
 mod event_loop;
 mod tcp_messages;
@@ -25,17 +25,23 @@ pub const KEEP_ALIVE_DELAY: Duration = Duration::from_secs(10);
 pub const KEEP_ALIVE_DELAY_MIDCALL: Duration = Duration::from_secs(1); 
 /// Message sending interval when announcing
 pub const ANNOUNCE_DELAY: Duration = Duration::from_secs(1); 
+/// A peer is considered lost, if nothing has been received from it for this long
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// The punch through is given up on after this long, both when calling and when reconnecting
+pub const PUNCH_THROUGH_TIMEOUT: Duration = Duration::from_secs(30);
 /// Delay between rendezvous server reconnect tries
 const RECONNECT_DELAY: Duration = Duration::from_secs(5);
-/// Delay between retrying to send a reliable message
-pub const RELIABLE_MESSAGE_DELAY: Duration = Duration::from_secs(2);
 /// Delay between updating the UI about connection statistics
 pub const STATS_UPDATE_DELAY: Duration = Duration::from_secs(3);
 
 pub struct ConnectionManager {
     rendezvous_socket: TcpStream,
+    /// The frame which has only partly arrived from the rendezvous server
+    rendezvous_reader: FrameReader,
     rendezvous_ip: SocketAddr,
     rendezvous_public_key: Option<NetworkedPublicKey>,
+    /// The fingerprint the rendezvous server's key has to match, if it's pinned
+    rendezvous_fingerprint: Option<String>,
     udp_socket: Rc<UdpSocket>,
     peers: Vec<Peer>,
     udp_connections: Vec<UdpConnection>,
@@ -44,42 +50,62 @@ pub struct ConnectionManager {
     ui_s: Sender<InterthreadMessage>,
     file_manager: FileManager,
     encryption: Rc<AsymmetricEncryption>,
+    /// The name we announce ourselves with
+    name: String,
+    trust_store: TrustStore,
+    /// How the incoming calls of each peer are handled
+    call_policies: CallPolicyStore,
+    /// Handshake used when calling a peer
+    handshake_mode: HandshakeMode,
+    /// Hide the packet headers and pad the packets sent to peers
+    privacy_mode: bool,
+    /// Bytes per second sent to a peer, at most
+    send_rate: usize,
+    /// The optional features we have, sent in the announce and the handshakes
+    capabilities: Capabilities,
+    /// Double ratchet sessions used for chat messages, by identity. They outlive the UDP connections.
+    ratchets: HashMap<NetworkedPublicKey, DoubleRatchet>,
+    /// Ratchet handshakes which haven't been answered yet
+    ratchet_handshakes: HashMap<NetworkedPublicKey, RatchetHandshake>,
+    /// Chat messages waiting for the ratchet session to be established, with their custom id
+    pending_chat_messages: Vec<(NetworkedPublicKey, String, u32)>,
     /// Instant is when the call was sent
     calls_in_progress: Vec<(Call, Instant)>,
+    /// Incoming calls which haven't been answered yet, with the caller's UDP address
+    incoming_calls: HashMap<NetworkedPublicKey, SocketAddr>,
     audio: Audio,
     // The last instant when the connection statistics were sent to the UI
     last_stats_update: Instant
 }
 
+/// A reliable message which hasn't been acknowledged yet
 pub struct UdpHolder {
+    /// The packet before sealing, so every retransmission is sealed with a new nonce
     pub packet: UdpPacket,
     pub last_send: Instant,
     /// The instant when the message was first sent
     pub sent: Instant,
-    pub sock: Rc<UdpSocket>,
-    pub address: SocketAddr,
+    /// Amount of times the message has been retransmitted
+    pub retransmissions: u32,
     pub msg_type: MsgType,
     /// Custom identifier used when trying to identify a confirmed message
     pub custom_id: Option<u32>
 }
 
-impl UdpHolder{
-    pub fn resend(&mut self) {
-        let packet_data = &bincode::serialize(&self.packet).unwrap()[..];
-        self.sock.send_to(packet_data, self.address).unwrap();
-        self.last_send = Instant::now();
-    }
-}
-
 impl ConnectionManager {
     pub fn new(encryption:AsymmetricEncryption, rend_ip: String, poll: Poll, ui_s: Sender<InterthreadMessage>, cm_s: Sender<InterthreadMessage>) -> ConnectionManager {
-        let rend_ip = SocketAddr::from_str(&rend_ip).unwrap();
+        // The server's fingerprint can be pinned with the host:port#fingerprint syntax, or with an environment variable
+        let (rend_ip, rendezvous_fingerprint) = match rend_ip.split_once('#') {
+            Some((ip, fingerprint)) => (ip, Some(fingerprint.to_string())),
+            None => (&rend_ip[..], env::var("SERVER_FINGERPRINT").ok())
+        };
+        let rend_ip = SocketAddr::from_str(rend_ip).unwrap();
 
         let mut rendezvous_socket = TcpStream::connect(rend_ip).unwrap();
         poll.registry().register(&mut rendezvous_socket, RENDEZVOUS, Interest::READABLE).unwrap();
 
         let mut udp_socket = UdpSocket::bind(SocketAddr::from_str("0.0.0.0:0").unwrap()).unwrap();
-        poll.registry().register(&mut udp_socket, UDP_SOCKET, Interest::READABLE).unwrap();
+        poll.registry().register(&mut udp_socket, UDP_SOCKET, Interest::READABLE | Interest::WRITABLE).unwrap();
         let mut udp_connections = Vec::new();
 
         let audio = Audio::new(ui_s.clone(), cm_s.clone());
@@ -87,18 +113,40 @@ impl ConnectionManager {
 
         let udp_socket = Rc::new(udp_socket);
         let encryption = Rc::new(encryption);
+        let handshake_mode = match env::vars().find(|(k, _)| k == "HANDSHAKE") {
+            Some((_, v)) if v.eq_ignore_ascii_case("noise") => HandshakeMode::Noise,
+            _ => HandshakeMode::KeyExchange
+        };
+        let privacy_mode = match env::vars().find(|(k, _)| k == "PRIVACY_MODE") {
+            Some((_, v)) => v == "1" || v.eq_ignore_ascii_case("true"),
+            None => false
+        };
+        let mut capabilities = Capabilities::SEALED | Capabilities::MTU_PROBES | Capabilities::MIGRATION | Capabilities::FILES;
+        if cfg!(feature = "audio") {
+            capabilities = capabilities | Capabilities::AUDIO;
+        }
+        // Given in kbit/s
+        let send_rate = env::var("SEND_RATE").ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|rate| *rate > 0)
+        .map_or(DEFAULT_SEND_RATE, |rate| rate * 1000 / 8);
+        let name = ["NAME", "USER", "USERNAME"].iter()
+        .find_map(|var| env::var(var).ok())
+        .unwrap_or(String::from("anonymous"));
         udp_connections.push(UdpConnection::new(
             UdpConnectionState::Unannounced, 
             rend_ip, 
             udp_socket.clone(), 
-            Some(SymmetricEncryption::new()),
+            None, // The key is exchanged once the server requests the announcement
             encryption.clone()
         ));
 
         ConnectionManager {
             rendezvous_socket,
+            rendezvous_reader: FrameReader::new(),
             rendezvous_ip: rend_ip,
             rendezvous_public_key: None,
+            rendezvous_fingerprint,
             udp_socket: udp_socket.clone(),
             peers: Vec::new(),
             udp_connections,
@@ -107,19 +155,29 @@ impl ConnectionManager {
             ui_s,
             file_manager,
             encryption,
+            name,
+            trust_store: TrustStore::load(),
+            call_policies: CallPolicyStore::load(),
+            handshake_mode,
+            privacy_mode,
+            send_rate,
+            capabilities,
+            ratchets: HashMap::new(),
+            ratchet_handshakes: HashMap::new(),
+            pending_chat_messages: Vec::new(),
             calls_in_progress: Vec::new(),
+            incoming_calls: HashMap::new(),
             audio,
             last_stats_update: Instant::now()
         }
     }
 
-    pub fn start(rend_ip: String, ui_s: Sender<InterthreadMessage>) -> (mio_misc::channel::Sender<InterthreadMessage>, JoinHandle<()>, NetworkedPublicKey) {
+    pub fn start(rend_ip: String, ui_s: Sender<InterthreadMessage>, encryption: AsymmetricEncryption) -> (mio_misc::channel::Sender<InterthreadMessage>, JoinHandle<()>, NetworkedPublicKey) {
         let poll = Poll::new().unwrap();
         let waker = Arc::new(Waker::new(poll.registry(), WAKER).unwrap());
         let queue = Arc::new(NotificationQueue::new(waker.clone()));
         let (cm_s, mut cm_r) = channel(queue, NotificationId::gen_next());
 
-        let encryption = AsymmetricEncryption::new();
         let key = encryption.get_public_key();
 
         let cm_s1 = cm_s.clone();
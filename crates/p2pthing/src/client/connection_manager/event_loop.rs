@@ -1,8 +1,8 @@
-use std::{io::{self, Read}, net::Shutdown, sync::mpsc::{self, Receiver}, thread, time::{Duration, Instant}};
+use std::{io, net::Shutdown, sync::mpsc::{self, Receiver}, thread, time::{Duration, Instant}};
 
 use io::ErrorKind;
 use mio::{Events, Interest, net::TcpStream};
-use p2pthing_common::{message_type::{InterthreadMessage, MsgType, msg_types}, ui::UIConn};
+use p2pthing_common::{encryption::EphemeralKeyExchange, framing::FrameReader, message_type::{InterthreadMessage, MsgType, msg_types}, protocol::{Capabilities, PROTOCOL_VERSION}, ui::UIConn};
 use p2pthing_tui::tui::Tui;
 
 use crate::client::{file_manager::FileManager, udp_connection::UdpConnectionState};
@@ -30,12 +30,27 @@ impl ConnectionManager {
                 return time.elapsed() < CALL_DECAY;
             });
 
+            // Notice the peers which have stopped answering
+            self.check_dead_peers();
+
             // Send keep alive messages
             self.send_keep_alive_messages();
 
-            // Send reliable messages
+            // Retransmit and acknowledge reliable messages
             self.send_reliable_messages();
 
+            // Send the datagrams which have been held back by the pacing
+            self.flush_send_queues();
+
+            // Replace old session keys
+            self.check_rekeys();
+
+            // Discover how large datagrams the paths to the peers can carry
+            self.probe_mtus();
+
+            // Check the new addresses of the peers which have moved
+            self.validate_paths();
+
             // Handle interthread messages 
             self.handle_interthread_messages(r, &mut running);
 
@@ -50,12 +65,103 @@ impl ConnectionManager {
         }
     }
 
+    /// Start a rekey on every connection whose key has been used for too long
+    fn check_rekeys(&mut self) {
+        let rendezvous_ip = self.rendezvous_ip;
+        let mut rendezvous_rekey = None;
+        for conn in &mut self.udp_connections {
+            let needs_rekey = match &conn.symmetric_key {
+                Some(key) => key.needs_rekey() && !key.is_rekeying() && conn.rekey.is_none(),
+                None => false
+            };
+            if !needs_rekey {
+                continue;
+            }
+            let rekey = EphemeralKeyExchange::new();
+            let msg = msg_types::Rekey {
+                ephemeral_key: rekey.public_key()
+            };
+            conn.rekey = Some(rekey);
+            match conn.associated_peer.clone() {
+                Some(public_key) if conn.upgraded => {
+                    match conn.send_udp_message(MsgType::Rekey, &msg, true, None) {
+                        Ok(()) => self.ui_s.log_info(&format!("Started rekeying the connection with peer: ({})", public_key)),
+                        Err(e) => {
+                            conn.rekey = None;
+                            self.ui_s.log_error(&format!("Couldn't start rekeying the connection with peer ({}): {}", public_key, e));
+                        }
+                    }
+                }
+                // The rendezvous server's key is only used for the TCP connection
+                None if conn.address == rendezvous_ip => rendezvous_rekey = Some(msg),
+                _ => conn.rekey = None
+            }
+        }
+        if let Some(msg) = rendezvous_rekey {
+            match self.send_tcp_message(MsgType::Rekey, &msg) {
+                Ok(()) => self.ui_s.log_info("Started rekeying the connection with the rendezvous server"),
+                Err(e) => {
+                    if let Some(conn) = self.udp_connections.iter_mut().find(|x| x.address == rendezvous_ip) {
+                        conn.rekey = None;
+                    }
+                    self.ui_s.log_error(&format!("Couldn't start rekeying the connection with the rendezvous server: {}", e));
+                }
+            }
+        }
+    }
+
+    /// Punch through again to the peers which have stopped answering, and give up on the ones which don't come back
+    fn check_dead_peers(&mut self) {
+        for conn in &mut self.udp_connections {
+            let p = match &conn.associated_peer {
+                Some(p) if conn.next_peer_timeout() == Some(Duration::from_secs(0)) => p.clone(),
+                _ => continue
+            };
+            // The peer could have vanished without the rendezvous server noticing it, so it's only looked for while it's announced
+            let announced = self.peers.iter().any(|peer| peer.public_key == p);
+            match conn.state {
+                UdpConnectionState::Connected if announced => {
+                    conn.state = UdpConnectionState::Reconnecting;
+                    self.ui_s.log_warning(&format!("Lost the connection to peer ({}), trying to reconnect", p));
+                    self.ui_s.send(InterthreadMessage::ConnectionLost(p)).unwrap();
+                }
+                _ => {
+                    conn.state = UdpConnectionState::Disconnected;
+                    if let Some(peer) = self.peers.iter_mut().find(|peer| peer.public_key == p) {
+                        peer.udp_addr = None;
+                    }
+                    self.ui_s.log_warning(&format!("Couldn't reach peer ({}), disconnected", p));
+                    self.ui_s.send(InterthreadMessage::ConnectionClosed(p)).unwrap();
+                }
+            }
+        }
+    }
+
+    fn validate_paths(&mut self) {
+        for conn in &mut self.udp_connections {
+            if let Err(e) = conn.poll_path_validation() {
+                self.ui_s.log_warning(&format!("Couldn't move the connection with ({}): {}", conn.address, e));
+            }
+        }
+    }
+
+    fn probe_mtus(&mut self) {
+        for conn in &mut self.udp_connections {
+            if !conn.probes_mtu() {
+                continue;
+            }
+            if let Err(e) = conn.probe_mtu() {
+                self.ui_s.log_info(&format!("Couldn't send a path MTU probe to ({}): {}", conn.address, e));
+            }
+        }
+    }
+
     fn send_keep_alive_messages(&mut self) {
         for conn in &mut self.udp_connections {
             match conn.state {
-                UdpConnectionState::MidCall | UdpConnectionState::Connected => {
+                UdpConnectionState::MidCall | UdpConnectionState::Connected | UdpConnectionState::Reconnecting => {
                     let delay = match conn.state { 
-                        UdpConnectionState::MidCall => KEEP_ALIVE_DELAY_MIDCALL,
+                        UdpConnectionState::MidCall | UdpConnectionState::Reconnecting => KEEP_ALIVE_DELAY_MIDCALL,
                         UdpConnectionState::Connected => KEEP_ALIVE_DELAY,
                         _ => unreachable!()
                     };
@@ -63,13 +169,24 @@ impl ConnectionManager {
                         Some(time) if time.elapsed() < delay => {}
                         None | _ => {
                             match conn.associated_peer.clone() {
+                                // In privacy mode, keep alive messages have to look like every other packet
+                                Some(public_key) if conn.sealed && conn.upgraded => {
+                                    match conn.send_udp_message(MsgType::KeepAlive, &(), false, None) {
+                                        Ok(()) => self.ui_s.log_info(&format!("Sent keep alive message to ({})", public_key)),
+                                        Err(e) => self.ui_s.log_error(&format!("Couldn't send a keep alive message to ({}): {}", public_key, e))
+                                    }
+                                }
                                 Some(public_key) => {
-                                    conn.send_raw_message(MsgType::KeepAlive, &(), false, None); //TODO: Error handling
-                                    self.ui_s.log_info(&format!("Sent keep alive message to ({})", public_key));
+                                    match conn.send_raw_message(MsgType::KeepAlive, &(), false, None) {
+                                        Ok(()) => self.ui_s.log_info(&format!("Sent keep alive message to ({})", public_key)),
+                                        Err(e) => self.ui_s.log_error(&format!("Couldn't send a keep alive message to ({}): {}", public_key, e))
+                                    }
                                 }
                                 None => {
-                                    conn.send_raw_message(MsgType::KeepAlive, &(), false, None); //TODO: Error handling
-                                    self.ui_s.log_info("Sent keep alive message to the rendezvous server");
+                                    match conn.send_raw_message(MsgType::KeepAlive, &(), false, None) {
+                                        Ok(()) => self.ui_s.log_info("Sent keep alive message to the rendezvous server"),
+                                        Err(e) => self.ui_s.log_error(&format!("Couldn't send a keep alive message to the rendezvous server: {}", e))
+                                    }
                                 }
                             }
                             
@@ -81,26 +198,51 @@ impl ConnectionManager {
                         Some(time) if time.elapsed() < ANNOUNCE_DELAY => {}
                         None | _ => {
                             let announce = msg_types::AnnouncePublic {
-                                public_key: self.encryption.get_public_key()
+                                public_key: self.encryption.get_public_key(),
+                                name: self.name.clone(),
+                                version: PROTOCOL_VERSION,
+                                capabilities: self.capabilities
                             };
-                            conn.send_raw_message(MsgType::Announce, &announce, false, None);
+                            if let Err(e) = conn.send_raw_message(MsgType::Announce, &announce, false, None) {
+                                self.ui_s.log_error(&format!("Failed to announce ourselves to the rendezvous server: {}", e));
+                            }
                             conn.last_announce = Some(Instant::now());
                         }
                     }
                 }
-                UdpConnectionState::Pending => {}
+                UdpConnectionState::Disconnected => {}
             };
         }
     }
 
+    fn flush_send_queues(&mut self) {
+        for conn in &mut self.udp_connections {
+            if let Err(e) = conn.flush() {
+                self.ui_s.log_error(&format!("Couldn't send a datagram to ({}): {}", conn.address, e));
+            }
+        }
+    }
+
     fn send_reliable_messages(&mut self) {
         for conn in &mut self.udp_connections {
-            match conn.state {
-                UdpConnectionState::Connected => {
-                    conn.resend_reliable_messages();
-                },
-                _ => {}
+            if let Err(e) = conn.send_acknowledgement() {
+                self.ui_s.log_error(&format!("Couldn't acknowledge the messages from ({}): {}", conn.address, e));
+            }
+            let given_up = match conn.state {
+                UdpConnectionState::Connected => conn.resend_reliable_messages(),
+                _ => continue
             };
+            let peer = conn.associated_peer.clone();
+            for msg in given_up {
+                match (msg.msg_type, &peer) {
+                    (MsgType::ChatMessage, Some(p)) => {
+                        self.ui_s.log_error(&format!("Failed to deliver a chat message to peer ({})", p));
+                        self.ui_s.send(InterthreadMessage::OnChatMessageFailed(msg.custom_id.unwrap())).unwrap();
+                    }
+                    (msg_type, Some(p)) => self.ui_s.log_error(&format!("Peer ({}) didn't acknowledge a message of type {}, giving up", p, num::ToPrimitive::to_u8(&msg_type).unwrap())),
+                    (msg_type, None) => self.ui_s.log_error(&format!("The rendezvous server didn't acknowledge a message of type {}, giving up", num::ToPrimitive::to_u8(&msg_type).unwrap()))
+                }
+            }
         }
     }
 
@@ -109,59 +251,36 @@ impl ConnectionManager {
             match r.try_recv() {
                 Ok(msg) => {
                     match msg {
-                        InterthreadMessage::SendChatMessage(p, msg, custom_id) => 
-                            match self.send_udp_message(Some(p), MsgType::ChatMessage, &msg_types::ChatMessage {msg,}, true, Some(custom_id)) {
-                                Ok(_) => {}
-                                Err(e) => self.ui_s.log_error(&format!("Error while trying to send a chat message: {}", e.to_string()))
-                        },
+                        InterthreadMessage::SendChatMessage(p, msg, custom_id) => self.send_chat_message(p, msg, custom_id),
                         InterthreadMessage::OpusPacketReady(data) => {
                             for conn in &mut self.udp_connections {
-                                if conn.upgraded && conn.associated_peer.is_some() {
-                                    conn.send_udp_message(MsgType::OpusPacket, &data, false, None) // TODO: Indexing packets
+                                if conn.upgraded && conn.associated_peer.is_some() && conn.capabilities.contains(Capabilities::AUDIO) {
+                                    // TODO: Indexing packets
+                                    if let Err(e) = conn.send_udp_message(MsgType::OpusPacket, &data, false, None) {
+                                        self.ui_s.log_error(&format!("Couldn't send a voice packet to ({}): {}", conn.address, e));
+                                    }
                                 }
                             }
                         }
                         InterthreadMessage::AudioDataReadyToBeProcessed(data) => self.audio.process_and_send_packet(data),
-                        InterthreadMessage::OnChatMessage(p, msg) => Tui::on_chat_message(&self.ui_s, p, msg),
+                        InterthreadMessage::OnChatMessage(p, msg, verified) => Tui::on_chat_message(&self.ui_s, p, msg, verified),
                         InterthreadMessage::ConnectToServer() => {
                             self.rendezvous_socket = TcpStream::connect(self.rendezvous_ip).unwrap();
+                            self.rendezvous_reader = FrameReader::new();
+                            // The new connection starts with a new key exchange
+                            let rendezvous_ip = self.rendezvous_ip;
+                            self.udp_connections.iter_mut()
+                            .find(|x| x.address == rendezvous_ip).unwrap()
+                            .symmetric_key = None;
+                            self.rendezvous_public_key = None;
                             self.poll.registry().register(&mut self.rendezvous_socket, RENDEZVOUS, Interest::READABLE).unwrap();
                             self.ui_s.log_info("Trying to connect to server");
                         }
-                        InterthreadMessage::CallAccepted(p) => {
-                            let msg = msg_types::CallResponse {
-                                call: msg_types::Call {
-                                    callee: self.encryption.get_public_key().clone(),
-                                    caller: Some(p.clone()),
-                                    udp_address: None
-                                },
-                                response: true
-                            };
-
-                            self.send_tcp_message(MsgType::CallResponse, &msg).unwrap();
-
-                            let conn = self.udp_connections.iter_mut().find(|c| c.associated_peer.is_some() && c.associated_peer.clone().unwrap() == p).unwrap();
-                            conn.state = UdpConnectionState::MidCall;
-                            let peer = self.peers.iter_mut().find(|peer| peer.public_key == p).unwrap();
-                            peer.udp_addr = Some(conn.address);
-                            
-                            self.ui_s.log_info(&format!("Accepted call from peer ({};{}), starting the punch through protocol", p, conn.address));
-                        }
+                        InterthreadMessage::CallAccepted(p) => self.accept_call(p),
                         InterthreadMessage::CallDenied(p) => {
-                            let msg = msg_types::CallResponse {
-                                call: msg_types::Call {
-                                    callee: self.encryption.get_public_key().clone(),
-                                    caller: Some(p.clone()),
-                                    udp_address: None
-                                },
-                                response: false
-                            };
-
-                            self.send_tcp_message(MsgType::CallResponse, &msg).unwrap();
-
-                            let i = self.udp_connections.iter().position(|c| c.associated_peer.is_some() && c.associated_peer.clone().unwrap() == p).unwrap();
-                            let conn = self.udp_connections.remove(i);
-                            self.ui_s.log_info(&format!("Denied call from peer ({};{})", p, conn.address));
+                            if let Some(address) = self.deny_call(p.clone()) {
+                                self.ui_s.log_info(&format!("Denied call from peer ({};{})", p, address));
+                            }
                         }
                         InterthreadMessage::Call(p) => {
                             let peer = self.peers.iter().find(|peer| peer.public_key == p).unwrap();
@@ -179,8 +298,10 @@ impl ConnectionManager {
                                 None => {
                                     self.ui_s.log_info(&format!("Calling peer: {}", p));
                             
-                                    self.calls_in_progress.push((call.clone(), Instant::now()));
-                                    self.send_tcp_message(MsgType::Call, &call).unwrap(); //TODO: Error handling
+                                    match self.send_tcp_message(MsgType::Call, &call) {
+                                        Ok(()) => self.calls_in_progress.push((call, Instant::now())),
+                                        Err(e) => self.ui_s.log_error(&format!("Failed to call peer ({}): {}", p, e))
+                                    }
                                 }
                             }
                         }
@@ -197,8 +318,33 @@ impl ConnectionManager {
                             *running = false;
                             return;
                         },
+                        InterthreadMessage::TrustPeerKey(p) => {
+                            match self.trust_store.trust(&p.name, &p.public_key) {
+                                Ok(_) => self.ui_s.log_info(&format!("Trusted the new key of peer {} ({})", p.name, p.public_key.fingerprint())),
+                                Err(e) => self.ui_s.log_error(&format!("Failed to store the key of peer {}: {}", p.name, e))
+                            }
+                        }
+                        InterthreadMessage::CallPolicy(p, policy) => {
+                            match self.call_policies.set(&p, policy) {
+                                Ok(_) => self.ui_s.log_info(&format!("Changed the call policy of peer ({}) to: {}", p, policy)),
+                                Err(e) => self.ui_s.log_error(&format!("Failed to store the call policy of peer ({}): {}", p, e))
+                            }
+                            self.ui_s.send(InterthreadMessage::CallPolicy(p, policy)).unwrap();
+                        }
                         InterthreadMessage::SendFiles(peer, files) => {
-                            match self.file_manager.send_files(files) {
+                            // The chunks are sized to fill the datagrams on the path to the peer
+                            let max_message_size = match self.udp_connections.iter().find(|c| c.associated_peer.as_ref() == Some(&peer)) {
+                                Some(conn) if !conn.capabilities.contains(Capabilities::FILES) => {
+                                    self.ui_s.log_error(&format!("Peer ({}) can't receive files", peer));
+                                    continue;
+                                }
+                                Some(conn) => conn.max_message_size(),
+                                None => {
+                                    self.ui_s.log_error(&format!("Cannot find udp connection with public key: ({})", peer));
+                                    continue;
+                                }
+                            };
+                            match self.file_manager.send_files(files, max_message_size) {
                                 Ok(files) => {
                                     if let Err(e) = self.send_udp_message(Some(peer), MsgType::SendFilesRequest, &msg_types::SendFilesRequest {files,}, true, None) {
                                         self.ui_s.log_error(&format!("Error while trying to send a file send request: {}", e.to_string()))
@@ -218,20 +364,29 @@ impl ConnectionManager {
 
     fn handle_io_events(&mut self, events: &Events) {
         for event in events.iter() {
+            // The socket has room for the datagrams which are waiting again
+            if event.token() == UDP_SOCKET && event.is_writable() {
+                self.flush_send_queues();
+            }
             match event.token() {
                 token => {
                     loop {
                         match token {
                             WAKER => break,
                             RENDEZVOUS => {
-                                let mut msg_type = [0;1];
-                                match self.rendezvous_socket.read(&mut msg_type) {
-                                    Ok(0) => {
+                                match self.rendezvous_reader.read_from(&mut self.rendezvous_socket) {
+                                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
                                         self.ui_s.log_warning("Disconnected from rendezvous server");
                                         break;
                                     }
-                                    Ok(_) => {
-                                        self.read_tcp_message(msg_type[0], token);
+                                    Ok(()) => {
+                                        // The stream can't be followed after a frame which is too large, so start over
+                                        if let Err(e) = self.read_tcp_frames() {
+                                            self.ui_s.log_warning(&format!("Received a malformed frame from the rendezvous server, reconnecting in {}: {}", RECONNECT_DELAY.as_secs(), e));
+                                            self.poll.registry().deregister(&mut self.rendezvous_socket).unwrap();
+                                            self.try_server_reconnect();
+                                            break;
+                                        }
                                     }
                                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                                         // Socket is not ready anymore, stop reading
@@ -280,6 +435,7 @@ impl ConnectionManager {
     }
 
     fn check_new_chunks(&mut self) {
+        self.file_manager.check_lost_chunks();
         if let Some(chunks) = self.file_manager.get_requested_chunks() {
             for (peer, chunks) in chunks {
                 if let Err(e) = self.send_udp_message(Some(peer), MsgType::RequestFileChunks, &msg_types::RequestFileChunks {chunks,}, true, None) {
@@ -304,12 +460,11 @@ impl ConnectionManager {
 
     /// Lists the next timeouts, and also sorts the list, so the first one is always the smallest
     fn get_next_timeouts(&mut self, durations: &mut Vec<Duration>) {
-        for conn in &mut self.udp_connections {
-            match conn.next_resendable() {
-                Some(d) => durations.push(d),
-                None => {}
-            }
-            durations.push(conn.next_keep_alive());
+        for conn in &self.udp_connections {
+            durations.extend(conn.next_timeouts());
+        }
+        if let Some(d) = self.file_manager.next_timeout() {
+            durations.push(d);
         }
         let next_stats_update = (self.last_stats_update + STATS_UPDATE_DELAY).checked_duration_since(self.last_stats_update).unwrap_or(Duration::from_secs(0));
         durations.push(next_stats_update);
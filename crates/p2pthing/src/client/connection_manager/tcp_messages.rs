@@ -1,115 +1,265 @@
 use std::net::SocketAddr;
 
-use mio::Token;
-use p2pthing_common::{encryption::SymmetricEncryption, message_type::{InterthreadMessage, MsgType, Peer, msg_types::{self, AnnounceRequest, AnnounceSecret, Call, CallResponse, Disconnect}}, read_exact, ui::UIConn};
+use p2pthing_common::{encryption::{EphemeralKeyExchange, NoiseHandshake}, message_type::{InterthreadMessage, MsgType, Peer, msg_types::{self, AnnounceRequest, Call, CallResponse, Disconnect}}, framing, protocol::{self, Negotiated, PROTOCOL_VERSION, ProtocolError}, ui::{CallPolicy, UIConn}};
+
+use crate::client::{trust_store::TrustStatus, udp_connection::HandshakeMode};
 
 use super::{ConnectionManager, UdpConnection, UdpConnectionState};
 
 impl ConnectionManager {
-    pub fn read_tcp_message(&mut self, msg_type: u8, _: Token) {
-        let sock = &mut self.rendezvous_socket;
-        let addr = sock.peer_addr().unwrap();
+    /// Handle every frame which has fully arrived from the rendezvous server
+    pub fn read_tcp_frames(&mut self) -> Result<(), ProtocolError> {
+        while let Some(frame) = self.rendezvous_reader.next_frame()? {
+            self.read_tcp_message(&frame);
+        }
+        Ok(())
+    }
 
-        let msg_type = num::FromPrimitive::from_u8(msg_type);
+    fn read_tcp_message(&mut self, frame: &[u8]) {
+        let addr = self.rendezvous_ip;
+
+        // Until the keys are exchanged, the server's messages are unencrypted
+        let rendezvous_ip = self.rendezvous_ip;
+        let key = self.udp_connections.iter_mut()
+        .find(|x| x.address == rendezvous_ip).unwrap()
+        .symmetric_key.as_mut();
+        let msg = match framing::open_frame(frame, key) {
+            Ok(msg) => msg,
+            Err(e) => {
+                self.ui_s.log_warning(&format!("Dropped message from the rendezvous server: {}", e));
+                return;
+            }
+        };
+        if let Err(e) = self.handle_tcp_message(addr, &msg) {
+            self.ui_s.log_warning(&format!("Dropped a malformed message from the rendezvous server: {}", e));
+        }
+    }
 
-        let mut msg_size = [0u8; 8];
-        read_exact(sock, &mut msg_size);
-        let msg_size: u64 = bincode::deserialize(&msg_size).unwrap();
-        let mut msg = vec![0;msg_size as usize];
-        read_exact(sock, &mut msg[..]);
+    fn handle_tcp_message(&mut self, addr: SocketAddr, msg: &[u8]) -> Result<(), ProtocolError> {
+        let (msg_type, body) = protocol::split_message(msg)?;
 
         match msg_type {
             Some(MsgType::AnnounceRequest) => {
-                let announcement: AnnounceRequest = bincode::deserialize(&msg).unwrap();
-                self.on_announce_request(addr, announcement);
+                self.on_announce_request(addr, protocol::decode(body)?);
             }
             Some(MsgType::Announce) => {
-                let peers: Vec<Peer> = bincode::deserialize(&msg).unwrap();
-                self.on_tcp_announce(addr, peers);
+                self.on_tcp_announce(addr, protocol::decode(body)?);
             }
             Some(MsgType::Call) => {
-                let call: msg_types::Call = bincode::deserialize(&mut msg[..]).unwrap();
-                self.on_call(addr, call);
+                self.on_call(addr, protocol::decode(body)?);
             }
             Some(MsgType::CallResponse) => {
-                let call_response: msg_types::CallResponse = bincode::deserialize(&mut msg[..]).unwrap();
-                self.on_call_response(addr, call_response);
+                self.on_call_response(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::RekeyAck) => {
+                self.on_rekey_ack(addr, protocol::decode(body)?);
             }
             Some(MsgType::Disconnect) => {
-                let disconnect_peer: msg_types::Disconnect = bincode::deserialize(&mut msg[..]).unwrap();
-                self.on_disconnect(addr, disconnect_peer);
+                self.on_disconnect(addr, protocol::decode(body)?);
             }
-            _ => unreachable!()
+            // A newer server can send messages which we don't know about yet
+            _ => self.ui_s.log_warning(&format!("Ignored an unexpected message of type {} from the rendezvous server", msg[0]))
         }
-        
+        Ok(())
     }
 
     fn on_announce_request(&mut self, addr: SocketAddr, announcement: AnnounceRequest) {
-        let conn = self.udp_connections.iter()
-        .find(|x| x.address == addr).unwrap();
+        if let Err(e) = EphemeralKeyExchange::verify(&announcement.public_key, &announcement.ephemeral_key, None, &announcement.signature) {
+            self.ui_s.log_error(&format!("Failed to verify the rendezvous server's ephemeral key, closing the connection: {}", e));
+            self.close_rendezvous_connection();
+            return;
+        }
+        match &self.rendezvous_fingerprint {
+            Some(fingerprint) if !announcement.public_key.matches_fingerprint(fingerprint) => {
+                self.ui_s.log_error(&format!("The rendezvous server's key ({}) doesn't match the pinned fingerprint, closing the connection", announcement.public_key.fingerprint()));
+                self.close_rendezvous_connection();
+                return;
+            }
+            Some(_) => {}
+            None => self.ui_s.log_warning(&format!("The rendezvous server's fingerprint isn't pinned, its fingerprint is: {}", announcement.public_key.fingerprint()))
+        }
+        if let Err(e) = Negotiated::new(self.capabilities, announcement.version, announcement.capabilities) {
+            self.ui_s.log_error(&format!("Can't connect to the rendezvous server: {}", e));
+            return;
+        }
 
-        self.rendezvous_public_key = Some(announcement.public_key);
-        let announce_secret = msg_types::AnnounceSecret {
-            secret: conn.symmetric_key.as_ref().unwrap().secret.clone()
+        let key_exchange = EphemeralKeyExchange::new();
+        let response = msg_types::KeyExchange {
+            public_key: self.encryption.get_public_key(),
+            ephemeral_key: key_exchange.public_key(),
+            signature: key_exchange.sign(&self.encryption, Some(&announcement.ephemeral_key)),
+            version: PROTOCOL_VERSION,
+            capabilities: self.capabilities
         };
-        self.send_tcp_message_public_key(MsgType::AnnounceSecret, &announce_secret).unwrap();
+        let sym_key = match key_exchange.derive(&announcement.ephemeral_key) {
+            Ok(sym_key) => sym_key,
+            Err(e) => {
+                self.ui_s.log_error(&format!("Failed to exchange keys with the rendezvous server: {}", e));
+                return;
+            }
+        };
+
+        match self.udp_connections.iter_mut().find(|x| x.address == addr) {
+            Some(conn) => conn.symmetric_key = Some(sym_key),
+            None => {
+                self.ui_s.log_error("Cannot find the rendezvous server's udp connection, closing the connection");
+                self.close_rendezvous_connection();
+                return;
+            }
+        }
+
+        self.rendezvous_public_key = Some(announcement.public_key);
+        if let Err(e) = self.send_raw_tcp_message(MsgType::KeyExchange, &response) {
+            self.ui_s.log_error(&format!("Failed to exchange keys with the rendezvous server: {}", e));
+            return;
+        }
         
         let announce_public = msg_types::AnnouncePublic {
-            public_key: self.encryption.get_public_key().clone()
+            public_key: self.encryption.get_public_key().clone(),
+            name: self.name.clone(),
+            version: PROTOCOL_VERSION,
+            capabilities: self.capabilities
         };
-        self.send_tcp_message(MsgType::Announce, &announce_public).unwrap();
+        if let Err(e) = self.send_tcp_message(MsgType::Announce, &announce_public) {
+            self.ui_s.log_error(&format!("Failed to announce ourselves to the rendezvous server: {}", e));
+        }
     }
 
     fn on_tcp_announce(&mut self, _: SocketAddr, peers: Vec<Peer>) {
+        let mut changed_keys = vec![];
+        let mut policies = vec![];
         for new_p in peers {
             if !self.peers.iter().any(|p| p.public_key == new_p.public_key) {
+                if !self.check_peer_trust(&new_p) {
+                    changed_keys.push(new_p.safe_clone());
+                }
+                match self.call_policies.get(&new_p.public_key) {
+                    CallPolicy::Ask => {}
+                    policy => policies.push((new_p.public_key.clone(), policy))
+                }
                 self.peers.push(new_p);
             }
         }
         self.ui_s.send(InterthreadMessage::AnnounceResponse(self.peers.clone())).unwrap();
+        for p in changed_keys {
+            self.ui_s.send(InterthreadMessage::PeerKeyChanged(p)).unwrap();
+        }
+        for (p, policy) in policies {
+            self.ui_s.send(InterthreadMessage::CallPolicy(p, policy)).unwrap();
+        }
     }
 
-    /// Handle incoming call
-    fn on_call(&mut self, _: SocketAddr, call: Call) {
-        let caller = call.caller.unwrap();
-        let udp_address = call.udp_address.unwrap();
+    /// Compare the peer's key with the one first seen with its name, returns false if it has changed
+    fn check_peer_trust(&mut self, p: &Peer) -> bool {
+        match self.trust_store.check(&p.name, &p.public_key) {
+            Ok(TrustStatus::New) => self.ui_s.log_info(&format!("Stored the key of new peer {} ({})", p.name, p.public_key.fingerprint())),
+            Ok(TrustStatus::Trusted) => {}
+            Ok(TrustStatus::Changed(known)) => {
+                self.ui_s.log_error(&format!(
+                    "WARNING: The key of peer {} has changed! Known fingerprint: ({}), new fingerprint: ({}). Verify the safety number before trusting it.",
+                    p.name, known.fingerprint(), p.public_key.fingerprint()));
+                return false;
+            }
+            Err(e) => self.ui_s.log_error(&format!("Failed to store the key of peer {}: {}", p.name, e))
+        }
+        true
+    }
 
-        let mut conn = UdpConnection::new(UdpConnectionState::Pending, udp_address, self.udp_socket.clone(), None, self.encryption.clone());
-        conn.associated_peer = Some(caller.clone());
-        self.udp_connections.push(conn);
+    /// Handle incoming call, depending on the caller's call policy
+    fn on_call(&mut self, _: SocketAddr, call: Call) {
+        let (caller, udp_address) = match (call.caller, call.udp_address) {
+            (Some(caller), Some(udp_address)) => (caller, udp_address),
+            _ => {
+                self.ui_s.log_warning("Dropped a call from the rendezvous server without the caller's address");
+                return;
+            }
+        };
 
-        // Notify the UI of the incoming call
-        self.ui_s.send(InterthreadMessage::Call(caller)).unwrap();
+        // The existing connections with the caller are only replaced once the call has been accepted
+        match self.call_policies.get(&caller) {
+            // The UI isn't notified, so blocked peers can't bother the user
+            CallPolicy::Block => self.send_call_response(caller, false),
+            CallPolicy::AutoAccept => {
+                self.incoming_calls.insert(caller.clone(), udp_address);
+                self.accept_call(caller.clone());
+                self.ui_s.send(InterthreadMessage::CallAccepted(caller)).unwrap();
+            }
+            // Notify the UI of the incoming call, unless it's already being shown
+            CallPolicy::Ask => {
+                if self.incoming_calls.insert(caller.clone(), udp_address).is_none() {
+                    self.ui_s.send(InterthreadMessage::Call(caller)).unwrap();
+                }
+            }
+        }
     }
 
     /// Handle the response to a sent call
     fn on_call_response(&mut self, _: SocketAddr, call_response: CallResponse) {
         let call = call_response.call;
         if !call_response.response {
-            let i = self.calls_in_progress.iter()
-            .position(|(c, _)| c.callee == call.callee)
-            .unwrap();
-            self.calls_in_progress.remove(i);
-            self.ui_s.send(InterthreadMessage::CallDenied(call.callee)).unwrap();
+            match self.calls_in_progress.iter().position(|(c, _)| c.callee == call.callee) {
+                Some(i) => {
+                    self.calls_in_progress.remove(i);
+                    self.ui_s.send(InterthreadMessage::CallDenied(call.callee)).unwrap();
+                }
+                None => self.ui_s.log_warning(&format!("A call which hasn't been sent has been denied by peer ({})", call.callee))
+            }
         }
         else {
-            let udp_address = call.udp_address.unwrap();
+            let udp_address = match call.udp_address {
+                Some(udp_address) => udp_address,
+                None => {
+                    self.ui_s.log_warning(&format!("Dropped a call response from peer ({}) without its address", call.callee));
+                    return;
+                }
+            };
         
-            let p = self.peers.iter_mut().find(|p| p.public_key == call.callee).unwrap();
-            p.udp_addr = Some(udp_address);
+            match self.peers.iter_mut().find(|p| p.public_key == call.callee) {
+                Some(p) => p.udp_addr = Some(udp_address),
+                None => {
+                    self.ui_s.log_warning(&format!("A call has been accepted by an unknown peer ({})", call.callee));
+                    return;
+                }
+            }
             
             if let Some(i) = self.calls_in_progress.iter().position(|(c, _)| c.callee == call.callee) {
                 self.calls_in_progress.remove(i);
+                self.remove_peer_connections(&call.callee);
     
-                let sym_key = SymmetricEncryption::new();
-                let mut conn = UdpConnection::new(UdpConnectionState::MidCall, udp_address, self.udp_socket.clone(), Some(sym_key), self.encryption.clone());
+                let mut conn = UdpConnection::new(UdpConnectionState::MidCall, udp_address, self.udp_socket.clone(), None, self.encryption.clone());
                 conn.associated_peer = Some(call.callee.clone());
+                conn.sealed = self.privacy_mode;
+                conn.send_queue.rate = self.send_rate;
                 self.ui_s.log_info(
                 &format!("A sent call has been accepted by peer ({};{}), starting the punch through protocol", call.callee, conn.address));
     
-                conn.send_udp_message_with_public_key(MsgType::AnnounceSecret, &AnnounceSecret{secret: conn.symmetric_key.as_ref().unwrap().secret.clone()}, true, None).unwrap();
+                match self.handshake_mode {
+                    HandshakeMode::KeyExchange => {
+                        let key_exchange = EphemeralKeyExchange::new();
+                        let msg = msg_types::KeyExchange {
+                            public_key: self.encryption.get_public_key(),
+                            ephemeral_key: key_exchange.public_key(),
+                            signature: key_exchange.sign(&self.encryption, None),
+                            version: PROTOCOL_VERSION,
+                            capabilities: self.capabilities
+                        };
+                        conn.key_exchange = Some(key_exchange);
+                        conn.send_raw_message(MsgType::KeyExchange, &msg, true, None).unwrap();
+                    }
+                    HandshakeMode::Noise => {
+                        let mut handshake = NoiseHandshake::new_initiator().unwrap();
+                        let message = handshake.write_message(&self.encryption).unwrap();
+                        conn.noise_handshake = Some(handshake);
+                        let msg = msg_types::NoiseHandshake {
+                            message,
+                            version: PROTOCOL_VERSION,
+                            capabilities: self.capabilities
+                        };
+                        conn.send_raw_message(MsgType::NoiseHandshake, &msg, true, None).unwrap();
+                    }
+                }
     
-                self.ui_s.send(InterthreadMessage::CallAccepted(p.public_key.clone())).unwrap();
+                self.ui_s.send(InterthreadMessage::CallAccepted(call.callee.clone())).unwrap();
                 self.udp_connections.push(conn);
             }
             else {
@@ -120,16 +270,9 @@ impl ConnectionManager {
     }
 
     fn on_disconnect(&mut self, _: SocketAddr, disconnect_peer: Disconnect) {
-        let p = self.peers.iter_mut().find(|p| p.public_key == disconnect_peer.public_key).unwrap();
-        self.ui_s.log_info(&format!("Peer ({}) disconnected", p.public_key));
-        match p.udp_addr {
-            Some(addr) => {
-                self.udp_connections.iter_mut()
-                .position(|conn| conn.address == addr)
-                .map(|i| self.udp_connections.remove(i)).unwrap();
-            }
-            None => {}
-        }
+        self.ui_s.log_info(&format!("Peer ({}) disconnected", disconnect_peer.public_key));
+        self.remove_peer_connections(&disconnect_peer.public_key);
+        self.incoming_calls.remove(&disconnect_peer.public_key);
         self.peers.iter_mut()
         .position(|p| p.public_key == disconnect_peer.public_key)
         .map(|i| self.peers.remove(i));
@@ -1,16 +1,26 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Instant};
 
-use p2pthing_common::{encryption::SymmetricEncryption, message_type::{InterthreadMessage, MsgType, UdpPacket, msg_types::{self, AnnounceSecret, ChatMessage}}, ui::UIConn};
+use p2pthing_common::{encryption::{EphemeralKeyExchange, NoiseHandshake}, message_type::{InterthreadMessage, MsgEncryption, MsgType, Peer, msg_types::{self, Acknowledgement}}, protocol::{self, Capabilities, PROTOCOL_VERSION, ProtocolError}, ui::UIConn};
 use p2pthing_tui::tui::Tui;
 
-use crate::client::udp_connection::UdpConnectionState;
+use crate::client::udp_connection::{ReceiveStatus, ReceivedMessage, UdpConnectionState};
 
 use super::ConnectionManager;
 
 impl ConnectionManager {
-    pub fn read_udp_message(&mut self, _: usize, addr: SocketAddr, buf: &[u8]) {
-        let conn = match self.udp_connections.iter_mut().find(|x| x.address == addr) {
-            Some(c) => c,
+    pub fn read_udp_message(&mut self, size: usize, addr: SocketAddr, buf: &[u8]) {
+        let udp_packet = match protocol::decode_udp_packet(&buf[..size]) {
+            Ok(udp_packet) => udp_packet,
+            Err(e) => {
+                self.ui_s.log_warning(&format!("Dropped a malformed packet from ({}): {}", addr, e));
+                return;
+            }
+        };
+        // A peer whose address has changed is found by its connection id
+        let i = self.udp_connections.iter().position(|x| x.address == addr)
+        .or_else(|| self.udp_connections.iter().position(|x| x.peer_connection_id.is_some() && x.peer_connection_id == udp_packet.connection_id));
+        let conn = match i {
+            Some(i) => &mut self.udp_connections[i],
             None => {
                 self.ui_s.log_warning(&format!("Tried reading from ({}), but couldn't find the associated connection", addr));
                 return;
@@ -19,55 +29,199 @@ impl ConnectionManager {
 
         //TODO: Move all this logic to udp_connection.rs
 
-        let udp_packet: UdpPacket = bincode::deserialize(&buf).unwrap();
         conn.statistics.received_bytes(bincode::serialized_size(&udp_packet).unwrap());
-        if conn.received_messages.contains(&udp_packet.msg_id) { // If already received this message
-            return;
-        }
-        conn.received_messages.push(udp_packet.msg_id);
-
-        if udp_packet.reliable {
-            conn.send_confirmation(udp_packet.msg_id);
+        // Large packets are only handled once all of their fragments have arrived
+        let udp_packet = match udp_packet.fragment {
+            Some(header) => match conn.decrypt_fragment(udp_packet).and_then(|fragment| conn.reassembler.add(header, fragment)) {
+                Ok(Some(udp_packet)) => udp_packet,
+                Ok(None) => return,
+                Err(e) => {
+                    self.ui_s.log_warning(&format!("Dropped fragment from ({}): {}", addr, e));
+                    return;
+                }
+            },
+            None => udp_packet
+        };
+        let udp_packet = match conn.unseal(udp_packet) {
+            Ok(udp_packet) => udp_packet,
+            Err(e) => {
+                self.ui_s.log_warning(&format!("Dropped sealed message from ({}): {}", addr, e));
+                return;
+            }
+        };
+        let (msg_id, reliable, stream, connection_id) = (udp_packet.msg_id, udp_packet.reliable, udp_packet.stream, udp_packet.connection_id);
+        // Messages which change the session keys are only accepted through the encrypted tunnel
+        let encrypted = matches!(udp_packet.upgraded, MsgEncryption::SymmetricKey | MsgEncryption::Sealed);
+        match reliable {
+            true => match conn.reliable_receiver.check(msg_id) {
+                // Only the handshake is sent unencrypted, once it's done anyone could have sent the message
+                ReceiveStatus::New if !encrypted && conn.upgraded => {
+                    self.ui_s.log_warning(&format!("Dropped an unencrypted reliable message from ({}), the keys have already been exchanged", addr));
+                    return;
+                }
+                ReceiveStatus::New => {}
+                ReceiveStatus::Duplicate => {
+                    conn.reliable_receiver.request_ack();
+                    return;
+                }
+                ReceiveStatus::OutOfWindow => return
+            },
+            // If already received this message, or it's too old to tell.
+            // Unencrypted ones are keep-alives and announcements, which don't mind being handled twice.
+            false if encrypted && !conn.received_messages.check(msg_id) => return,
+            false => {}
         }
         
         let buf = match conn.decrypt(udp_packet) {
             Ok(buf) => buf,
-            Err(_) => return
+            Err(e) => {
+                self.ui_s.log_warning(&format!("Dropped message from ({}): {}", addr, e));
+                return;
+            }
+        };
+        // Packets from a new address are only handled once the peer has proven that it can be reached there
+        if conn.address != addr {
+            let response = match (encrypted, protocol::split_message(&buf)) {
+                (true, Ok((Some(MsgType::PathResponse), body))) => protocol::decode::<msg_types::PathResponse>(body).ok(),
+                _ => None
+            };
+            match response {
+                Some(response) if conn.migrate(addr, &response) => {
+                    let p = conn.associated_peer.clone().unwrap();
+                    if let Some(peer) = self.peers.iter_mut().find(|peer| peer.public_key == p) {
+                        peer.udp_addr = Some(addr);
+                    }
+                    self.ui_s.log_info(&format!("Peer ({}) has moved to ({})", p, addr));
+                }
+                // Authenticated packets can be sent again from anywhere, so they only start the validation
+                _ if encrypted && conn.capabilities.contains(Capabilities::MIGRATION) => {
+                    if let Err(e) = conn.validate_path(addr) {
+                        self.ui_s.log_warning(&format!("Couldn't validate the new address of ({}): {}", conn.address, e));
+                    }
+                    return;
+                }
+                _ if encrypted => {
+                    self.ui_s.log_warning(&format!("Dropped a message from ({}), the peer can't move to a new address", addr));
+                    return;
+                }
+                _ => {
+                    self.ui_s.log_warning(&format!("Dropped an unencrypted message from ({}), which isn't the address of its connection", addr));
+                    return;
+                }
+            }
+        }
+        if encrypted {
+            conn.peer_connection_id = connection_id;
+        }
+
+        // Only remember the message once it's decrypted, so it's acknowledged and retransmissions are ignored.
+        // Unencrypted messages could be spoofed, so they can't move the windows, except for the handshake which comes before the keys.
+        match reliable {
+            true => conn.reliable_receiver.update(msg_id),
+            false if encrypted => conn.received_messages.update(msg_id),
+            false => {}
+        }
+        conn.last_message_received = Instant::now();
+        let reconnected = matches!(conn.state, UdpConnectionState::Reconnecting | UdpConnectionState::Disconnected);
+
+        // Reliable messages might have to wait for the earlier messages of their stream
+        let msg = ReceivedMessage {
+            data: buf,
+            encrypted
         };
+        let messages = match stream {
+            Some(header) if reliable => conn.streams.receive(header, msg),
+            _ => vec![msg]
+        };
+        if reconnected {
+            self.check_punchthrough(addr);
+        }
+        for msg in messages {
+            self.on_udp_message(addr, msg);
+        }
+    }
+
+    fn on_udp_message(&mut self, addr: SocketAddr, msg: ReceivedMessage) {
+        if let Err(e) = self.handle_udp_message(addr, msg) {
+            self.ui_s.log_warning(&format!("Dropped a malformed message from ({}): {}", addr, e));
+        }
+    }
 
-        let msg_type = buf[0];
-        let msg_type = num::FromPrimitive::from_u8(msg_type);
+    fn handle_udp_message(&mut self, addr: SocketAddr, msg: ReceivedMessage) -> Result<(), ProtocolError> {
+        let (buf, encrypted) = (msg.data, msg.encrypted);
+        let (msg_type, body) = protocol::split_message(&buf)?;
+        let from_peer = self.udp_connections.iter().any(|c| c.address == addr && c.associated_peer.is_some());
 
         match msg_type {
+            Some(MsgType::Announce) | Some(MsgType::AnnounceChallenge) if from_peer => {
+                self.ui_s.log_warning(&format!("Dropped a message from ({}), which only the rendezvous server sends", addr));
+            }
+            Some(MsgType::ChatMessage) | Some(MsgType::OpusPacket) | Some(MsgType::SendFilesRequest) | Some(MsgType::RequestFileChunks) | Some(MsgType::FileChunks) if !from_peer => {
+                self.ui_s.log_warning(&format!("Dropped a message from ({}), which only peers send", addr));
+            }
             Some(MsgType::Announce) => {
                 self.on_udp_announce(addr);
             }
+            Some(MsgType::AnnounceChallenge) => {
+                self.on_announce_challenge(addr, protocol::decode(body)?);
+            }
             Some(MsgType::KeepAlive) => {
                 self.on_keep_alive(addr);
             }
             Some(MsgType::ChatMessage) => {
-                self.on_chat_message(addr, &buf[1..]);
+                self.on_chat_message(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::KeyExchange) => {
+                self.on_key_exchange(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::NoiseHandshake) => {
+                self.on_noise_handshake(addr, protocol::decode(body)?);
             }
-            Some(MsgType::AnnounceSecret) => {
-                self.on_secret_announce(addr, &buf[1..]);
+            Some(MsgType::RatchetInit) | Some(MsgType::Rekey) | Some(MsgType::RekeyAck) if !encrypted => {
+                self.ui_s.log_warning(&format!("Dropped an unencrypted key change from ({})", addr));
+            }
+            Some(MsgType::RatchetInit) => {
+                self.on_ratchet_init(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::Rekey) => {
+                self.on_rekey(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::RekeyAck) => {
+                self.on_rekey_ack(addr, protocol::decode(body)?);
             }
             Some(MsgType::MessageConfirmation) => {
-                self.on_confirmation_message(addr, &buf[1..]);
+                self.on_acknowledgement(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::Skipped) => {
+                self.on_skipped(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::MtuProbe) => {
+                self.on_mtu_probe(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::MtuProbeAck) => {
+                self.on_mtu_probe_ack(addr, protocol::decode(body)?);
             }
+            Some(MsgType::PathChallenge) => {
+                self.on_path_challenge(addr, protocol::decode(body)?);
+            }
+            // Answers the challenge of a path which has already been validated, see `read_udp_message`
+            Some(MsgType::PathResponse) => {}
             Some(MsgType::OpusPacket) => {
-                self.on_opus_packet(addr, &buf[1..]);
+                self.on_opus_packet(addr, protocol::decode(body)?);
             }
             Some(MsgType::SendFilesRequest) => {
-                self.on_send_file_request(addr, &buf[1..]);
+                self.on_send_file_request(addr, protocol::decode(body)?);
             }
             Some(MsgType::RequestFileChunks) => {
-                self.on_request_file_chunks(addr, &buf[1..]);
+                self.on_request_file_chunks(addr, protocol::decode(body)?);
             }
             Some(MsgType::FileChunks) => {
-                self.on_file_chunks(addr, &buf[1..]);
+                self.on_file_chunks(addr, protocol::decode(body)?);
             }
-            _ => unreachable!()
+            // A newer peer can send messages which we don't know about yet
+            _ => self.ui_s.log_warning(&format!("Ignored an unexpected message of type {} from ({})", buf[0], addr))
         }
+        Ok(())
     }
 
     fn check_punchthrough(&mut self, addr: SocketAddr) {
@@ -80,49 +234,255 @@ impl ConnectionManager {
                 self.ui_s.log_info(&format!("Punch through successfull. Connected to peer: ({})", p));
                 self.ui_s.send(InterthreadMessage::PunchThroughSuccessfull(p)).unwrap();
             }
+            // The peer is answering again
+            UdpConnectionState::Reconnecting | UdpConnectionState::Disconnected => {
+                let p = conn.associated_peer.clone().unwrap();
+                conn.state = UdpConnectionState::Connected;
+                if let Some(peer) = self.peers.iter_mut().find(|peer| peer.public_key == p) {
+                    peer.udp_addr = Some(addr);
+                }
+                self.ui_s.log_info(&format!("Reconnected to peer: ({})", p));
+                self.ui_s.send(InterthreadMessage::PunchThroughSuccessfull(p)).unwrap();
+            }
             _ => {}
         }
     }
 
-    fn on_secret_announce(&mut self, addr: SocketAddr, data: &[u8]) {
-        let secret: AnnounceSecret = bincode::deserialize(data).unwrap();
-        let secret = &secret.secret[..];
+    fn on_key_exchange(&mut self, addr: SocketAddr, msg: msg_types::KeyExchange) {
+        if !self.negotiate(addr, msg.version, msg.capabilities) {
+            return;
+        }
 
         let conn = self.udp_connections.iter_mut()
         .find(|x| x.address == addr).unwrap();
-        
-        conn.symmetric_key = Some(SymmetricEncryption::new_from_secret(secret));
-        conn.upgraded = true;
+        if conn.associated_peer.as_ref() != Some(&msg.public_key) {
+            self.ui_s.log_warning(&format!("Received a key exchange from ({}) with an unexpected public key", addr));
+            return;
+        }
 
-        self.ui_s.log_info(&format!("Received secret for peer: ({})", conn.associated_peer.as_ref().unwrap()));
-        self.check_punchthrough(addr);
+        let sym_key = match conn.key_exchange.take() {
+            // We started the key exchange, so this is the response to it
+            Some(key_exchange) => {
+                EphemeralKeyExchange::verify(&msg.public_key, &msg.ephemeral_key, Some(&key_exchange.public_key()), &msg.signature)
+                .and_then(|_| key_exchange.derive(&msg.ephemeral_key))
+            }
+            None => {
+                let key_exchange = EphemeralKeyExchange::new();
+                let response = msg_types::KeyExchange {
+                    public_key: self.encryption.get_public_key(),
+                    ephemeral_key: key_exchange.public_key(),
+                    signature: key_exchange.sign(&self.encryption, Some(&msg.ephemeral_key)),
+                    version: PROTOCOL_VERSION,
+                    capabilities: self.capabilities
+                };
+                let sym_key = EphemeralKeyExchange::verify(&msg.public_key, &msg.ephemeral_key, None, &msg.signature)
+                .and_then(|_| key_exchange.derive(&msg.ephemeral_key));
+                if sym_key.is_ok() {
+                    conn.send_raw_message(MsgType::KeyExchange, &response, true, None).unwrap();
+                }
+                sym_key
+            }
+        };
+
+        match sym_key {
+            Ok(sym_key) => {
+                conn.symmetric_key = Some(sym_key);
+                conn.upgraded = true;
+                self.ui_s.log_info(&format!("Exchanged keys with peer: ({})", msg.public_key));
+                self.check_punchthrough(addr);
+                self.start_ratchet(msg.public_key);
+            }
+            Err(e) => self.ui_s.log_error(&format!("Key exchange with peer ({}) failed: {}", msg.public_key, e))
+        }
     }
 
-    fn on_confirmation_message(&mut self, addr: SocketAddr, data: &[u8]) {
-        let id: u32 = bincode::deserialize(data).unwrap();
+    fn on_noise_handshake(&mut self, addr: SocketAddr, msg: msg_types::NoiseHandshake) {
+        if !self.negotiate(addr, msg.version, msg.capabilities) {
+            return;
+        }
+
         let conn = self.udp_connections.iter_mut()
         .find(|x| x.address == addr).unwrap();
+        let peer = match conn.associated_peer.clone() {
+            Some(peer) => peer,
+            None => {
+                self.ui_s.log_warning(&format!("Received a noise handshake from ({}), which isn't a peer", addr));
+                return;
+            }
+        };
 
-        let removed = conn.sent_messages.iter_mut().position(|msg| msg.packet.msg_id == id).map(|i| conn.sent_messages.remove(i));
-        match removed {
-            Some(msg) => {
-                conn.statistics.new_ping(msg.sent.elapsed());
-                match msg.msg_type {
-                    MsgType::AnnounceSecret => {
+        // The first message starts a new handshake, where we are the responder
+        let handshake = match conn.noise_handshake.take() {
+            Some(handshake) => Ok(handshake),
+            None => NoiseHandshake::new_responder()
+        };
+        let encryption = self.encryption.clone();
+        let capabilities = self.capabilities;
+        let result = handshake.and_then(|mut handshake| {
+            handshake.read_message(&msg.message, &peer)?;
+            if handshake.is_my_turn() {
+                let message = handshake.write_message(&encryption)?;
+                let msg = msg_types::NoiseHandshake {
+                    message,
+                    version: PROTOCOL_VERSION,
+                    capabilities
+                };
+                conn.send_raw_message(MsgType::NoiseHandshake, &msg, true, None).unwrap();
+            }
+            Ok(handshake)
+        });
+
+        match result {
+            Ok(handshake) if handshake.is_finished() => {
+                match handshake.into_symmetric_encryption() {
+                    Ok(sym_key) => {
+                        conn.symmetric_key = Some(sym_key);
                         conn.upgraded = true;
-                        self.ui_s.log_info(&format!("Peer received secret: ({})", conn.associated_peer.as_ref().unwrap()));
+                        self.ui_s.log_info(&format!("Finished noise handshake with peer: ({})", peer));
                         self.check_punchthrough(addr);
+                        self.start_ratchet(peer);
                     }
-                    MsgType::ChatMessage => {
-                        self.ui_s.log_info(&format!("Chat message confirmed by: ({})", conn.associated_peer.as_ref().unwrap()));
-                        self.ui_s.send(InterthreadMessage::OnChatMessageReceived(msg.custom_id.unwrap())).unwrap();
-                    }
-                    MsgType::SendFilesRequest => {}
-                    MsgType::RequestFileChunks => {}
-                    _ => unreachable!()
+                    Err(e) => self.ui_s.log_error(&format!("Noise handshake with peer ({}) failed: {}", peer, e))
                 }
             }
-            None => self.ui_s.log_warning(&format!("Couldn't find message with confirmation id: ({})", id))
+            Ok(handshake) => conn.noise_handshake = Some(handshake),
+            Err(e) => self.ui_s.log_error(&format!("Noise handshake with peer ({}) failed: {}", peer, e))
+        }
+    }
+
+    /// Finish the ratchet handshake, answering it first if the peer started it.
+    /// An existing session is replaced, since the peer must have lost it.
+    fn on_ratchet_init(&mut self, addr: SocketAddr, msg: msg_types::RatchetInit) {
+        let peer = match self.udp_connections.iter().find(|x| x.address == addr).and_then(|c| c.associated_peer.clone()) {
+            Some(peer) => peer,
+            None => {
+                self.ui_s.log_warning(&format!("Received a ratchet handshake from ({}), which isn't a peer", addr));
+                return;
+            }
+        };
+
+        if !self.ratchet_handshakes.contains_key(&peer) {
+            self.ratchets.remove(&peer);
+            self.start_ratchet(peer.clone());
+        }
+        let handshake = match self.ratchet_handshakes.remove(&peer) {
+            Some(handshake) => handshake,
+            None => return
+        };
+        // Both sides have to agree on the roles, so the one with the lower fingerprint initiates
+        let initiator = self.encryption.get_public_key().fingerprint() < peer.fingerprint();
+        match handshake.complete(&msg.ratchet_key, initiator) {
+            Ok(ratchet) => {
+                self.ratchets.insert(peer.clone(), ratchet);
+                self.ui_s.log_info(&format!("Started a ratchet session with peer: ({})", peer));
+
+                let (pending, rest): (Vec<_>, Vec<_>) = self.pending_chat_messages.drain(..).partition(|(p, _, _)| p == &peer);
+                self.pending_chat_messages = rest;
+                for (p, msg, custom_id) in pending {
+                    self.send_chat_message(p, msg, custom_id);
+                }
+            }
+            Err(e) => self.ui_s.log_error(&format!("Ratchet handshake with peer ({}) failed: {}", peer, e))
+        }
+    }
+
+    /// Answer the peer's rekey, switching to the new key once the peer starts using it
+    fn on_rekey(&mut self, addr: SocketAddr, msg: msg_types::Rekey) {
+        let conn = self.udp_connections.iter_mut()
+        .find(|x| x.address == addr).unwrap();
+        if conn.symmetric_key.is_none() {
+            self.ui_s.log_warning(&format!("Received a rekey from ({}) before the keys were exchanged", addr));
+            return;
+        }
+
+        if let Some(rekey) = &conn.rekey {
+            // Both sides started a rekey at the same time, the one with the lower key answers
+            if rekey.public_key() > msg.ephemeral_key {
+                return;
+            }
+            conn.rekey = None;
+        }
+
+        let rekey = EphemeralKeyExchange::new();
+        let ack = msg_types::RekeyAck {
+            ephemeral_key: rekey.public_key()
+        };
+        match rekey.derive_rekey(&msg.ephemeral_key, false) {
+            Ok((send_secret, recv_secret)) => {
+                if let Err(e) = conn.send_udp_message(MsgType::RekeyAck, &ack, true, None) {
+                    self.ui_s.log_error(&format!("Couldn't answer the rekey from ({}): {}", addr, e));
+                    return;
+                }
+                if let Some(key) = conn.symmetric_key.as_mut() {
+                    key.stage_rekey(send_secret, recv_secret);
+                }
+                self.ui_s.log_info(&format!("Answered a rekey from ({})", addr));
+            }
+            Err(e) => self.ui_s.log_error(&format!("Rekey with ({}) failed: {}", addr, e))
+        }
+    }
+
+    /// Our rekey has been answered, so switch to the new key
+    pub fn on_rekey_ack(&mut self, addr: SocketAddr, ack: msg_types::RekeyAck) {
+        let conn = self.udp_connections.iter_mut()
+        .find(|x| x.address == addr).unwrap();
+        match conn.finish_rekey(&ack) {
+            Ok(true) => self.ui_s.log_info(&format!("Rekeyed the connection with ({})", addr)),
+            Ok(false) => self.ui_s.log_warning(&format!("Received a rekey answer from ({}), but no rekey was started", addr)),
+            Err(e) => self.ui_s.log_error(&format!("Rekey with ({}) failed: {}", addr, e))
+        }
+    }
+
+    fn on_acknowledgement(&mut self, addr: SocketAddr, ack: Acknowledgement) {
+        let conn = self.udp_connections.iter_mut()
+        .find(|x| x.address == addr).unwrap();
+
+        for msg in conn.on_acknowledgement(&ack) {
+            // Nothing is waiting for the other messages to be acknowledged
+            if let (MsgType::ChatMessage, Some(p), Some(custom_id)) = (msg.msg_type, &conn.associated_peer, msg.custom_id) {
+                self.ui_s.log_info(&format!("Chat message confirmed by: ({})", p));
+                self.ui_s.send(InterthreadMessage::OnChatMessageReceived(custom_id)).unwrap();
+            }
+        }
+    }
+
+    fn on_skipped(&mut self, addr: SocketAddr, skipped: msg_types::Skipped) {
+        let peer = match self.udp_connections.iter().find(|c| c.address == addr).and_then(|c| c.associated_peer.as_ref()) {
+            Some(p) => p.clone(),
+            None => return
+        };
+        match num::FromPrimitive::from_u8(skipped.msg_type) {
+            Some(MsgType::ChatMessage) => self.ui_s.log_error(&format!("A chat message from peer ({}) has been lost", peer)),
+            _ => self.ui_s.log_warning(&format!("Peer ({}) gave up on sending a message of type {}", peer, skipped.msg_type))
+        }
+    }
+
+    /// The probe got through, so answer it
+    fn on_mtu_probe(&mut self, addr: SocketAddr, probe: msg_types::MtuProbe) {
+        let conn = self.udp_connections.iter_mut()
+        .find(|x| x.address == addr).unwrap();
+        if let Err(e) = conn.send_udp_message(MsgType::MtuProbeAck, &msg_types::MtuProbeAck {id: probe.id}, false, None) {
+            self.ui_s.log_warning(&format!("Couldn't answer the path MTU probe from ({}): {}", addr, e));
+        }
+    }
+
+    fn on_mtu_probe_ack(&mut self, addr: SocketAddr, ack: msg_types::MtuProbeAck) {
+        let conn = self.udp_connections.iter_mut()
+        .find(|x| x.address == addr).unwrap();
+        if conn.pmtu.on_ack(ack.id) {
+            self.ui_s.log_info(&format!("The path MTU to ({}) is at least {} bytes", addr, conn.pmtu.mtu()));
+        }
+    }
+
+    /// Prove that we can be reached at our current address, by sending back the token
+    fn on_path_challenge(&mut self, addr: SocketAddr, challenge: msg_types::PathChallenge) {
+        let conn = self.udp_connections.iter_mut()
+        .find(|x| x.address == addr).unwrap();
+        let response = msg_types::PathResponse {
+            token: challenge.token
+        };
+        if let Err(e) = conn.send_udp_message(MsgType::PathResponse, &response, false, None) {
+            self.ui_s.log_warning(&format!("Couldn't answer the path challenge from ({}): {}", addr, e));
         }
     }
 
@@ -133,27 +493,88 @@ impl ConnectionManager {
         self.ui_s.log_info("UDP Announcement has been accepted");
     }
 
+    /// Prove to the rendezvous server that the UDP announcement came from us
+    fn on_announce_challenge(&mut self, addr: SocketAddr, challenge: msg_types::AnnounceChallenge) {
+        let proof = msg_types::AnnounceProof {
+            public_key: self.encryption.get_public_key(),
+            signature: self.encryption.sign_challenge(&challenge.challenge)
+        };
+        let conn = match self.udp_connections.iter_mut().find(|x| x.address == addr && x.associated_peer.is_none() && x.state == UdpConnectionState::Unannounced) {
+            Some(conn) => conn,
+            None => {
+                self.ui_s.log_warning(&format!("Dropped an announce challenge from ({}), which we haven't announced ourselves to", addr));
+                return;
+            }
+        };
+        // The announcement is sent again until it's accepted, which brings a new challenge
+        if let Err(e) = conn.send_raw_message(MsgType::AnnounceProof, &proof, false, None) {
+            conn.last_announce = None;
+            self.ui_s.log_error(&format!("Failed to answer the announce challenge, announcing again: {}", e));
+        }
+    }
+
     fn on_keep_alive(&mut self, addr: SocketAddr) {
         self.ui_s.log_info(&format!("Keep alive message received from {}", addr));
         self.check_punchthrough(addr);
     }
 
-    fn on_chat_message(&mut self, addr: SocketAddr, data: &[u8]) {
-        let chat_message: msg_types::ChatMessage = bincode::deserialize(data).unwrap();
-        let p = self.peers.iter().find(|p| p.udp_addr.unwrap() == addr).unwrap();
-        Tui::on_chat_message(&self.ui_s, p.clone(), chat_message.msg);
+    /// The announced peer at the address. Peer messages from anywhere else are dropped.
+    fn find_peer(&self, addr: SocketAddr) -> Option<Peer> {
+        let peer = self.peers.iter().find(|p| p.udp_addr == Some(addr)).cloned();
+        if peer.is_none() {
+            self.ui_s.log_warning(&format!("Dropped a message from ({}), which isn't an announced peer", addr));
+        }
+        peer
+    }
+
+    fn on_chat_message(&mut self, addr: SocketAddr, encrypted: msg_types::EncryptedChatMessage) {
+        let p = match self.find_peer(addr) {
+            Some(p) => p,
+            None => return
+        };
+        let decrypted = match self.ratchets.get_mut(&p.public_key) {
+            Some(ratchet) => ratchet.decrypt(&encrypted.header, &encrypted.ciphertext),
+            None => {
+                self.ui_s.log_warning(&format!("Received a chat message from ({}), but there is no ratchet session with them", p.public_key));
+                return;
+            }
+        };
+        let chat_message = match decrypted {
+            Ok(decrypted) => protocol::decode::<msg_types::ChatMessage>(&decrypted),
+            Err(e) => {
+                self.ui_s.log_warning(&format!("Failed to decrypt a chat message from ({}): {}", p.public_key, e));
+                return;
+            }
+        };
+        let chat_message = match chat_message {
+            Ok(chat_message) => chat_message,
+            Err(e) => {
+                self.ui_s.log_warning(&format!("Received a malformed chat message from ({}): {}", p.public_key, e));
+                return;
+            }
+        };
+        // Messages aren't relayed yet, so the author has to be the peer who sent it
+        let verified = chat_message.author == p.public_key && chat_message.verify();
+        if !verified {
+            self.ui_s.log_warning(&format!("Received a chat message with an invalid signature from ({})", p.public_key));
+        }
+        Tui::on_chat_message(&self.ui_s, p, chat_message.msg, verified);
     }
 
-    fn on_opus_packet(&mut self, addr: SocketAddr, data: &[u8]) {
-        let data: Vec<u8> = bincode::deserialize(data).unwrap();
-        let p = self.peers.iter().find(|p| p.udp_addr.unwrap() == addr).unwrap();
+    fn on_opus_packet(&mut self, addr: SocketAddr, data: Vec<u8>) {
+        let p = match self.find_peer(addr) {
+            Some(p) => p,
+            None => return
+        };
 
-        self.audio.decode_and_queue_packet(&data[..], p.public_key.clone());
+        self.audio.decode_and_queue_packet(&data[..], p.public_key);
     }
 
-    fn on_send_file_request(&mut self, addr: SocketAddr, data: &[u8]) {
-        let data: msg_types::SendFilesRequest = bincode::deserialize(data).unwrap();
-        let p = self.peers.iter().find(|p| p.udp_addr.unwrap() == addr).unwrap();
+    fn on_send_file_request(&mut self, addr: SocketAddr, data: msg_types::SendFilesRequest) {
+        let p = match self.find_peer(addr) {
+            Some(p) => p,
+            None => return
+        };
 
         //TODO: Ability to accept or deny file download
         for file in data.files {
@@ -163,24 +584,29 @@ impl ConnectionManager {
         }
     }
 
-    fn on_request_file_chunks(&mut self, addr: SocketAddr, data: &[u8]) {
-        let data: msg_types::RequestFileChunks = bincode::deserialize(data).unwrap();
-        let p = self.peers.iter().find(|p| p.udp_addr.unwrap() == addr).unwrap();
-        let public_key = p.public_key.clone();
+    fn on_request_file_chunks(&mut self, addr: SocketAddr, data: msg_types::RequestFileChunks) {
+        let p = match self.find_peer(addr) {
+            Some(p) => p,
+            None => return
+        };
+        let public_key = p.public_key;
 
         //TODO: Ability to accept or deny file download
         match self.file_manager.get_file_chunks(data) {
             Ok(chunks) => {
-                if let Err(e) = self.send_udp_message(Some(public_key), MsgType::FileChunks, &msg_types::FileChunks {chunks,}, false, None) {
-                    self.ui_s.log_error(&format!("Error while trying to send a file chunk request: {}", e.to_string()));
+                // Every chunk fills a datagram, so they are sent one by one
+                for chunk in chunks {
+                    if let Err(e) = self.send_udp_message(Some(public_key.clone()), MsgType::FileChunks, &msg_types::FileChunks {chunks: vec![chunk]}, false, None) {
+                        self.ui_s.log_error(&format!("Error while trying to send a file chunk request: {}", e.to_string()));
+                        break;
+                    }
                 }
             },
             Err(e) => self.ui_s.log_error(&format!("Failed reading file chunks: {}", &e)),
         }
     }
 
-    fn on_file_chunks(&mut self, addr: SocketAddr, data: &[u8]) {
-        let data: msg_types::FileChunks = bincode::deserialize(data).unwrap();
+    fn on_file_chunks(&mut self, _: SocketAddr, data: msg_types::FileChunks) {
 
         //TODO: Ability to accept or deny file download
         if let Err(e) = self.file_manager.store_file_chunks(data) {
@@ -1,43 +1,41 @@
-use std::io::{self, Write};
+use std::{io, net::{Shutdown, SocketAddr}};
 
-use p2pthing_common::{encryption::NetworkedPublicKey, message_type::MsgType, ui::UIConn};
+use p2pthing_common::{encryption::{NetworkedPublicKey, RatchetHandshake}, framing::{self, FrameReader}, message_type::{InterthreadMessage, MsgType, msg_types}, protocol::{Capabilities, Negotiated}, ui::UIConn};
 use serde::Serialize;
 
-use super::ConnectionManager;
+use super::{ConnectionManager, UdpConnection, UdpConnectionState};
 
 impl ConnectionManager {
     pub fn send_tcp_message<T: ?Sized>(&mut self, t: MsgType, msg: &T) -> io::Result<()> where T: Serialize {
-        let t: u8 = num::ToPrimitive::to_u8(&t).unwrap();
-        let msg = &bincode::serialize(msg).unwrap()[..];
-
-        let conn = self.udp_connections.iter()
-        .find(|x| x.address == self.rendezvous_ip).unwrap();
-
-        let encrypted = &conn.symmetric_key.as_ref().unwrap().encrypt(&[&[t], msg].concat()[..])[..];
+        let rendezvous_ip = self.rendezvous_ip;
+        let conn = match self.udp_connections.iter_mut().find(|x| x.address == rendezvous_ip) {
+            Some(conn) => conn,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to the rendezvous server"))
+        };
 
-        let msg_size = bincode::serialize(&encrypted.len()).unwrap();
-        let chained: &[u8] = &[&msg_size[..], encrypted].concat()[..];
+        let key = match conn.symmetric_key.as_mut() {
+            Some(key) => key,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "The keys haven't been exchanged with the rendezvous server yet"))
+        };
 
-        self.rendezvous_socket.write_all(&chained[..])?;
-        Ok(())
+        framing::write_frame(&mut self.rendezvous_socket, t, msg, Some(key))
     }
 
-    pub fn send_tcp_message_public_key<T: ?Sized>(&mut self, t:MsgType, msg: &T) -> io::Result<()> where T: Serialize {
-        let t: u8 = num::ToPrimitive::to_u8(&t).unwrap();
-        let msg = &bincode::serialize(msg).unwrap()[..];
-
-        let key = self.rendezvous_public_key.as_ref().unwrap();
-        let encrypted = &key.encrypt(&[&[t], msg].concat()[..]);
-
-        let msg_size = bincode::serialize(&encrypted.len()).unwrap();
-        let chained: &[u8] = &[&msg_size[..], encrypted].concat()[..];
+    /// Close the connection to the rendezvous server for good, it isn't reconnected to.
+    /// The frames which have already arrived are thrown away.
+    pub fn close_rendezvous_connection(&mut self) {
+        self.poll.registry().deregister(&mut self.rendezvous_socket).ok();
+        self.rendezvous_socket.shutdown(Shutdown::Both).ok();
+        self.rendezvous_reader = FrameReader::new();
+    }
 
-        self.rendezvous_socket.write_all(&chained[..])?;
-        Ok(())
+    /// Send a message unencrypted, this is only used before the key exchange is finished
+    pub fn send_raw_tcp_message<T: ?Sized>(&mut self, t:MsgType, msg: &T) -> io::Result<()> where T: Serialize {
+        framing::write_frame(&mut self.rendezvous_socket, t, msg, None)
     }
 
     /// Send a UDP packet which optionally can be reliable
-    pub fn send_udp_message<T: ?Sized>(&mut self, public_key: Option<NetworkedPublicKey>, t: MsgType, msg: &T, reliable: bool, custom_id: Option<u32>) -> Result<(), &'static str> where T: Serialize  {
+    pub fn send_udp_message<T: ?Sized>(&mut self, public_key: Option<NetworkedPublicKey>, t: MsgType, msg: &T, reliable: bool, custom_id: Option<u32>) -> Result<(), String> where T: Serialize  {
         let rendezvous_ip = self.rendezvous_ip.clone();
         let conn = match public_key {
             Some(public_key) => {
@@ -48,13 +46,135 @@ impl ConnectionManager {
                     Some(conn) => conn,
                     None => {
                         self.ui_s.log_error(&format!("Cannot find udp connection with public key: ({})", public_key));
-                        return Err("Cannot find udp connection");
+                        return Err("Cannot find udp connection".into());
                     }
                 }
             }
-            None => self.udp_connections.iter_mut().find(|c| c.address == rendezvous_ip).unwrap()
+            None => match self.udp_connections.iter_mut().find(|c| c.address == rendezvous_ip) {
+                Some(conn) => conn,
+                None => return Err("Cannot find the rendezvous server's udp connection".into())
+            }
+        };
+        conn.send_udp_message(t, msg, reliable, custom_id)
+    }
+
+    /// Sign the chat message and encrypt it with the peer's ratchet session.
+    /// If there is no session yet, the message is sent once it's established.
+    pub fn send_chat_message(&mut self, public_key: NetworkedPublicKey, msg: String, custom_id: u32) {
+        let ratchet = match self.ratchets.get_mut(&public_key) {
+            Some(ratchet) => ratchet,
+            None => {
+                self.pending_chat_messages.push((public_key.clone(), msg, custom_id));
+                self.start_ratchet(public_key);
+                return;
+            }
+        };
+        let chat_message = msg_types::ChatMessage::new(msg, &self.encryption);
+        let (header, ciphertext) = ratchet.encrypt(&bincode::serialize(&chat_message).unwrap()[..]);
+        let encrypted = msg_types::EncryptedChatMessage {
+            header,
+            ciphertext
+        };
+        if let Err(e) = self.send_udp_message(Some(public_key), MsgType::ChatMessage, &encrypted, true, Some(custom_id)) {
+            self.ui_s.log_error(&format!("Error while trying to send a chat message: {}", e));
+        }
+    }
+
+    /// Start a ratchet session with the peer, unless there already is one or it's being established
+    pub fn start_ratchet(&mut self, public_key: NetworkedPublicKey) {
+        if self.ratchets.contains_key(&public_key) || self.ratchet_handshakes.contains_key(&public_key) {
+            return;
+        }
+        // The keys are sent over the encrypted connection, so wait until it's upgraded
+        if !self.udp_connections.iter().any(|c| c.upgraded && c.associated_peer.as_ref() == Some(&public_key)) {
+            return;
+        }
+        let handshake = RatchetHandshake::new();
+        let init = msg_types::RatchetInit {
+            ratchet_key: handshake.public_key()
+        };
+        self.ratchet_handshakes.insert(public_key.clone(), handshake);
+        if let Err(e) = self.send_udp_message(Some(public_key), MsgType::RatchetInit, &init, true, None) {
+            self.ui_s.log_error(&format!("Error while trying to start a ratchet session: {}", e));
+        }
+    }
+
+    /// Agree with the peer on the optional features used on the connection, from the version and capabilities in its handshake.
+    /// Returns false if the peer's version is too old, so the handshake has to be stopped.
+    pub fn negotiate(&mut self, addr: SocketAddr, version: u16, capabilities: Capabilities) -> bool {
+        let conn = self.udp_connections.iter_mut()
+        .find(|x| x.address == addr).unwrap();
+        let negotiated = match Negotiated::new(self.capabilities, version, capabilities) {
+            Ok(negotiated) => negotiated,
+            Err(e) => {
+                self.ui_s.log_error(&format!("Can't connect to ({}): {}", addr, e));
+                return false;
+            }
+        };
+        conn.capabilities = negotiated.capabilities;
+        if conn.sealed && !negotiated.capabilities.contains(Capabilities::SEALED) {
+            conn.sealed = false;
+            self.ui_s.log_warning(&format!("The peer at ({}) doesn't support privacy mode, its packets won't be sealed", addr));
+        }
+        true
+    }
+
+    /// Throw away the connections with the peer, including the ones which have been lost.
+    /// The chat messages which haven't been acknowledged are reported as failed.
+    pub fn remove_peer_connections(&mut self, p: &NetworkedPublicKey) {
+        for conn in self.udp_connections.iter_mut().filter(|c| c.associated_peer.as_ref() == Some(p)) {
+            for msg in conn.sent_messages.drain(..).chain(conn.queued_messages.drain(..)) {
+                if let (MsgType::ChatMessage, Some(custom_id)) = (msg.msg_type, msg.custom_id) {
+                    self.ui_s.send(InterthreadMessage::OnChatMessageFailed(custom_id)).unwrap();
+                }
+            }
+        }
+        self.udp_connections.retain(|c| c.associated_peer.as_ref() != Some(p));
+    }
+
+    /// Accept an incoming call, and start the punch through protocol with the caller
+    pub fn accept_call(&mut self, p: NetworkedPublicKey) {
+        let address = match self.incoming_calls.remove(&p) {
+            Some(address) => address,
+            None => {
+                self.ui_s.log_warning(&format!("Tried accepting a call from peer ({}), but it isn't calling", p));
+                return;
+            }
+        };
+        self.send_call_response(p.clone(), true);
+
+        // A lost connection is replaced by the new one
+        self.remove_peer_connections(&p);
+        let mut conn = UdpConnection::new(UdpConnectionState::MidCall, address, self.udp_socket.clone(), None, self.encryption.clone());
+        conn.associated_peer = Some(p.clone());
+        conn.sealed = self.privacy_mode;
+        conn.send_queue.rate = self.send_rate;
+        self.udp_connections.push(conn);
+        if let Some(peer) = self.peers.iter_mut().find(|peer| peer.public_key == p) {
+            peer.udp_addr = Some(address);
+        }
+
+        self.ui_s.log_info(&format!("Accepted call from peer ({};{}), starting the punch through protocol", p, address));
+    }
+
+    /// Deny an incoming call, returning the address the caller would have been called on
+    pub fn deny_call(&mut self, p: NetworkedPublicKey) -> Option<SocketAddr> {
+        let address = self.incoming_calls.remove(&p)?;
+        self.send_call_response(p, false);
+        Some(address)
+    }
+
+    pub(super) fn send_call_response(&mut self, p: NetworkedPublicKey, response: bool) {
+        let msg = msg_types::CallResponse {
+            call: msg_types::Call {
+                callee: self.encryption.get_public_key().clone(),
+                caller: Some(p),
+                udp_address: None
+            },
+            response
         };
-        conn.send_udp_message(t, msg, reliable, custom_id);
-        Ok(())
+        if let Err(e) = self.send_tcp_message(MsgType::CallResponse, &msg) {
+            self.ui_s.log_error(&format!("Failed to answer the call: {}", e));
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, convert::TryInto, env, fs::{self, File, Metadata}, io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write}, ops::{Deref, DerefMut}, path::{Path, PathBuf}, sync::{Arc, mpsc::{self, Receiver}}, thread, time::Instant};
+use std::{collections::HashMap, convert::TryInto, env, fs::{self, File, Metadata}, io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write}, ops::{Deref, DerefMut}, path::{Path, PathBuf}, sync::{Arc, mpsc::{self, Receiver}}, thread, time::{Duration, Instant}};
 use mio::{Events, Poll, Token, Waker};
 use mio_misc::{NotificationId, channel::{Sender, channel}, queue::NotificationQueue};
 use sha2::{Digest, Sha256};
@@ -7,7 +7,9 @@ use base64::{encode_config};
 use p2pthing_common::{encryption::NetworkedPublicKey, message_type::{FileChunk, FileDataChunk, FileId, InterthreadMessage, SplitFile, msg_types::{FileChunks, RequestFileChunks}}, ui::UIConn};
 
 mod chunk_writer;
+mod congestion;
 use chunk_writer::ChunkWriter;
+use congestion::{CongestionController, MAX_WINDOW};
 
 pub struct FileManager {
     open_files: HashMap<FileId, OpenFile>,
@@ -18,7 +20,9 @@ pub struct FileManager {
     read_buffer: Vec<u8>,
     ui_s: Sender<InterthreadMessage>,
     /// Requests that haven't been sent to their respective peers
-    new_requests: HashMap<NetworkedPublicKey, Vec<FileChunk>>
+    new_requests: HashMap<NetworkedPublicKey, Vec<FileChunk>>,
+    /// Limits the chunks requested from every peer, so file transfers don't congest the link
+    congestion: HashMap<NetworkedPublicKey, CongestionController>
 }
 
 #[derive(Clone)]
@@ -39,7 +43,8 @@ impl ReceivableChunk {
 /// This struct holds an open file. It can either be a Reader or a Writer, but never both.
 struct OpenFile {
     file: FileType,
-    metadata: Metadata
+    metadata: Metadata,
+    chunk_size: usize
 }
 
 enum FileType {
@@ -65,12 +70,10 @@ impl TransferStatistics {
 }
 
 
-/// Max size of a single packet in bytes
-const CHUNK_SIZE: usize = 1 * 1000;
+/// Chunks can't be larger than this, so a peer can't make us allocate huge buffers
+const MAX_CHUNK_SIZE: usize = 64 * 1000;
 /// This is where the file downloads will be placed
 const DOWNLOADS_FOLDER: &str = "downloads";
-/// Request this many chunks at the same time
-const REQUESTED_CHUNK_COUNT: usize = 50;
 
 impl FileManager {
     pub fn new(ui_s: Sender<InterthreadMessage>) -> FileManager {
@@ -80,54 +83,81 @@ impl FileManager {
             file_senders: HashMap::new(),
             transfer_statistics: HashMap::new(),
             ui_s,
-            read_buffer: vec![0u8; CHUNK_SIZE],
+            read_buffer: Vec::new(),
             new_requests: HashMap::new(),
+            congestion: HashMap::new(),
         }
     }
 
-    /// Split files, prepare for uploading
-    pub fn send_files(&mut self, filenames: Vec<String>) -> io::Result<Vec<SplitFile>> {
+    /// Split files, prepare for uploading. The chunks are sized to fit into messages of the given size.
+    pub fn send_files(&mut self, filenames: Vec<String>, max_message_size: usize) -> io::Result<Vec<SplitFile>> {
+        let chunk_size = FileManager::chunk_size(max_message_size);
         let mut split_files = Vec::new();
         for filename in filenames {
-            split_files.push(self.start_sending_file(filename)?);
+            split_files.push(self.start_sending_file(filename, chunk_size)?);
         }
         return Ok(split_files);
     }
 
-    pub fn start_sending_file(&mut self, filename: String) -> io::Result<SplitFile> {
+    /// The largest chunk which still fits into a message of the given size, together with its header
+    fn chunk_size(max_message_size: usize) -> usize {
+        let empty_chunk = FileChunks {
+            chunks: vec![FileDataChunk {
+                file_id: encode_config(Sha256::digest(&[]), base64::URL_SAFE),
+                index: 0,
+                data: vec![]
+            }]
+        };
+        max_message_size - bincode::serialized_size(&empty_chunk).unwrap() as usize
+    }
+
+    pub fn start_sending_file(&mut self, filename: String, chunk_size: usize) -> io::Result<SplitFile> {
         let path = Path::new(&filename);
         let filename = path.file_name().unwrap().to_str().unwrap();
 
         let metadata = fs::metadata(path.clone())?;
         let total_length = metadata.len();
 
-        let file_id = [&filename.as_bytes(), &total_length.to_be_bytes()[..]].concat();
+        // The same file can be sent in different chunk sizes to different peers
+        let file_id = [&filename.as_bytes(), &total_length.to_be_bytes()[..], &(chunk_size as u64).to_be_bytes()[..]].concat();
         let file_id = Sha256::digest(&file_id);
         let file_id = encode_config(file_id, base64::URL_SAFE);
         
         // If the file is not already opened, then open it
         // TODO: What if the size of the file changes (someone else writes to it). Because then the hash would change.
         if !self.open_files.contains_key(&file_id) {
-            self.open_file(&file_id, path, false, None)?;
+            self.open_file(&file_id, path, false, None, chunk_size)?;
         }
 
         return Ok(SplitFile {
             file_id,
             file_name: filename.to_string(),
             total_length,
+            chunk_size,
         })
     }
 
     pub fn start_receiving_file(&mut self, file: SplitFile, sender: NetworkedPublicKey) -> io::Result<()> {
-        let chunk_count: usize = file.total_length as usize / CHUNK_SIZE + 1;
+        if file.chunk_size == 0 || file.chunk_size > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid chunk size: {}", file.chunk_size)));
+        }
+        // The file id is used as the file name, so it can't be allowed to point outside of the downloads folder
+        if file.file_id.is_empty() || !file.file_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '=') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid file id: {}", file.file_id)));
+        }
+        let chunk_count: usize = file.total_length as usize / file.chunk_size + 1;
         let original_name = PathBuf::from(file.file_name.clone());
-        let download_path = env::current_dir().unwrap().join(PathBuf::from(DOWNLOADS_FOLDER)).join(file.file_id.clone()).with_extension(original_name.extension().unwrap());
-        self.open_file(&file.file_id, &download_path, true, Some(file.total_length))?;
+        let mut download_path = env::current_dir().unwrap().join(PathBuf::from(DOWNLOADS_FOLDER)).join(file.file_id.clone());
+        if let Some(extension) = original_name.extension() {
+            download_path.set_extension(extension);
+        }
+        self.open_file(&file.file_id, &download_path, true, Some(file.total_length), file.chunk_size)?;
 
         // TODO: Enable receiving same file from multiple senders
         if !self.receiving_chunks.contains_key(&file.file_id) {
             let x = self.receiving_chunks.insert(file.file_id.clone(), vec![ReceivableChunk::new(); chunk_count.try_into().unwrap()]);
             assert!(x.is_none());
+            self.congestion.entry(sender.clone()).or_insert_with(CongestionController::new);
             let x = self.file_senders.insert(file.file_id.clone(), sender);
             assert!(x.is_none());
         }
@@ -135,15 +165,31 @@ impl FileManager {
         Ok(())
     }
 
-    /// Generate the file chunks that need to be requested
+    /// Request the chunks which haven't arrived in time again
+    pub fn check_lost_chunks(&mut self) {
+        let mut lost_any = false;
+        for controller in self.congestion.values_mut() {
+            for (file_id, index) in controller.take_lost() {
+                if let Some(chunks) = self.receiving_chunks.get_mut(&file_id) {
+                    chunks[index].requested = false;
+                    lost_any = true;
+                }
+            }
+        }
+        if lost_any {
+            self.update_requested_chunks();
+        }
+    }
+
+    /// Generate the file chunks that need to be requested, as many as the congestion windows allow
     fn update_requested_chunks(&mut self) {
-        let mut total_requested = 0;
         for (file_id, chunks) in self.receiving_chunks.iter_mut() {
+            let sender = self.file_senders.get(file_id).unwrap();
+            let controller = self.congestion.get_mut(sender).unwrap();
             // Skip chunks that are already downloaded
-            for (index, chunk) in chunks.iter_mut().enumerate().skip_while(|(_, x)| x.requested && x.received) {
-                if total_requested >= REQUESTED_CHUNK_COUNT { break; }
-                if !chunk.requested {
-                    let sender = self.file_senders.get(file_id).unwrap();
+            for (index, chunk) in chunks.iter_mut().enumerate().skip_while(|(_, x)| x.received) {
+                if !controller.can_request() { break; }
+                if !chunk.requested && !chunk.received {
                     let peer_vec = match self.new_requests.get_mut(&sender) {
                         Some(v) => v,
                         None =>  {
@@ -153,8 +199,8 @@ impl FileManager {
                     };
                     peer_vec.push(FileChunk{file_id: file_id.clone(), index});
                     chunk.requested = true;
+                    controller.on_request((file_id.clone(), index));
                 }
-                total_requested += 1;
             }
         }
     }
@@ -172,10 +218,16 @@ impl FileManager {
         let mut chunks: Vec<FileDataChunk> = Vec::new();
         for chunk in request.chunks {
             if let Some(f) = self.open_files.get_mut(&chunk.file_id) {
-                let start_file_index = chunk.index * CHUNK_SIZE;
-                let remaining_total_file = f.metadata.len() as usize - start_file_index;
-                let end_file_index = start_file_index + remaining_total_file.min(CHUNK_SIZE);
-                let read_bytes = end_file_index - start_file_index;
+                // The index comes from the peer, so it has to be checked against the length of the file
+                let length = f.metadata.len() as usize;
+                let start_file_index = match chunk.index.checked_mul(f.chunk_size).filter(|start| *start <= length) {
+                    Some(start_file_index) => start_file_index,
+                    None => return Err(format!("Tried reading a chunk past the end of the file: ({})", chunk))
+                };
+                let read_bytes = (length - start_file_index).min(f.chunk_size);
+                if self.read_buffer.len() < read_bytes {
+                    self.read_buffer.resize(read_bytes, 0);
+                }
 
                 if let FileType::Reader(reader) = &mut f.file {
                     if let Err(e) = reader.seek(SeekFrom::Start(start_file_index as u64)) {
@@ -209,10 +261,18 @@ impl FileManager {
     pub fn store_file_chunks(&mut self, msg: FileChunks) -> Result<(), String> {
         let mut files_changed = Vec::new();
         for chunk in msg.chunks {
+            if let Some(sender) = self.file_senders.get(&chunk.file_id) {
+                self.congestion.get_mut(sender).unwrap().on_chunk(&(chunk.file_id.clone(), chunk.index));
+            }
             if let Some(f) = self.open_files.get_mut(&chunk.file_id) {
-                let chunk_list = self.receiving_chunks.get_mut(&chunk.file_id).unwrap();
-                if !chunk_list[chunk.index].received {
-                    let index_start = chunk.index * CHUNK_SIZE;
+                // Files which are being sent are open too, and the index comes from the peer
+                let received = match self.receiving_chunks.get(&chunk.file_id).map(|chunk_list| chunk_list.get(chunk.index)) {
+                    Some(Some(receivable)) => receivable.received,
+                    Some(None) => return Err(format!("Received a chunk past the end of the file: ({}[{}])", chunk.file_id, chunk.index)),
+                    None => return Err(format!("Received a chunk of a file which isn't being received: ({})", chunk.file_id))
+                };
+                if !received {
+                    let index_start = chunk.index * f.chunk_size;
                     
                     if let FileType::Writer(writer) = &mut f.file {
                         if let Err(e) = writer.write_chunk(chunk.index, &chunk.data[..]) {
@@ -244,6 +304,11 @@ impl FileManager {
         Ok(())
     }
 
+    /// Time until a requested chunk is considered lost
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.congestion.values().filter_map(|controller| controller.next_timeout()).min()
+    }
+
     fn check_files_done(&mut self, files: Vec<FileId>) {
         for file in files {
             if self.is_file_done(&file) {
@@ -257,7 +322,7 @@ impl FileManager {
                 
                 let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
                 let mbs = open_file.metadata.len() as f64 / 1000f64 / 1000f64  / secs ;
-                self.ui_s.log_info(&format!("Finished file ({}) in {}ms achieving {:.} MB/s", &file[0..10], elapsed.as_millis(), mbs));
+                self.ui_s.log_info(&format!("Finished file ({}) in {}ms achieving {:.} MB/s", file.get(0..10).unwrap_or(&file), elapsed.as_millis(), mbs));
 
                 drop(open_file);
             }
@@ -268,7 +333,7 @@ impl FileManager {
         self.receiving_chunks.get(file_id).unwrap().iter().all(|x| x.received)
     }
 
-    fn open_file(&mut self, file_id: &FileId, path: &Path, create: bool, set_file_length: Option<u64>) -> io::Result<()> {
+    fn open_file(&mut self, file_id: &FileId, path: &Path, create: bool, set_file_length: Option<u64>, chunk_size: usize) -> io::Result<()> {
         if !self.open_files.contains_key(file_id) {
             let file = fs::OpenOptions::new()
             .read(true)
@@ -284,7 +349,7 @@ impl FileManager {
             let file = match create {
                 true => {
                     self.ui_s.log_info(&format!("Opened a file for writing: {}", path.clone().to_str().unwrap()));
-                    FileType::Writer(ChunkWriter::new(file, CHUNK_SIZE, REQUESTED_CHUNK_COUNT))
+                    FileType::Writer(ChunkWriter::new(file, chunk_size, MAX_WINDOW)?)
                 },
                 false => {
                     self.ui_s.log_info(&format!("Opened a file for reading: {}", path.clone().to_str().unwrap()));
@@ -294,7 +359,8 @@ impl FileManager {
 
             self.open_files.insert(file_id.clone(), OpenFile {
                 file,
-                metadata
+                metadata,
+                chunk_size
             });
             self.transfer_statistics.insert(file_id.clone(), TransferStatistics::new());
         }
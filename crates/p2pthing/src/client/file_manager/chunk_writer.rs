@@ -30,15 +30,15 @@ pub(crate) struct ChunkWriter {
 /// This struct stores data in a buffer before writing which has double the size of MAX_PACKET_SIZE * CHUNK_COUNT
 /// this is because packets aren't arriving in order.
 impl ChunkWriter {
-    pub fn new(inner: File, chunk_size: usize, chunk_count: usize) -> ChunkWriter {
-        let mmap = unsafe { MmapMut::map_mut(&inner).unwrap() };
-        ChunkWriter {
+    pub fn new(inner: File, chunk_size: usize, chunk_count: usize) -> io::Result<ChunkWriter> {
+        let mmap = unsafe { MmapMut::map_mut(&inner)? };
+        Ok(ChunkWriter {
             inner,
             written_chunks: Vec::new(),
             mmap,
             chunk_size,
             chunk_count
-        }
+        })
     }
 
     pub fn write_chunk(&mut self, chunk_id: usize, data: &[u8]) -> Result<(), Error> {  
@@ -46,10 +46,16 @@ impl ChunkWriter {
             return Err(Error::InvalidChunkError);
         }
 
+        // The chunk comes from the peer, so it has to fit into its place in the file
+        if data.len() > self.chunk_size {
+            return Err(Error::InvalidChunkError);
+        }
+        let index_start = chunk_id.checked_mul(self.chunk_size).ok_or(Error::InvalidChunkError)?;
+        let index_end = index_start.checked_add(data.len()).ok_or(Error::InvalidChunkError)?;
+        let target = self.mmap.get_mut(index_start..index_end).ok_or(Error::InvalidChunkError)?;
+
         // Write to the circular buffer
-        let index_start = chunk_id * self.chunk_size;
-        let index_end = index_start + data.len();
-        self.mmap[index_start..index_end].copy_from_slice(&data[0..data.len()]);
+        target.copy_from_slice(data);
 
         Ok(())
     }
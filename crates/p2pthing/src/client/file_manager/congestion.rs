@@ -0,0 +1,266 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, time::{Duration, Instant}};
+
+use p2pthing_common::message_type::FileId;
+
+/// The queuing delay the transfer aims for. Above it the window shrinks, so voice and chat don't have to wait behind file chunks.
+const TARGET_DELAY: Duration = Duration::from_millis(50);
+/// How fast the window reacts to the distance from the target
+const GAIN: f64 = 1.0;
+/// The window is in chunks
+const INITIAL_WINDOW: f64 = 4.0;
+const MIN_WINDOW: f64 = 2.0;
+pub const MAX_WINDOW: usize = 2048;
+/// The base delay is the smallest delay of every interval, in the last few intervals
+const BASE_INTERVAL: Duration = Duration::from_secs(60);
+const BASE_HISTORY: usize = 10;
+/// The current delay is the smallest of the last few samples, which filters out the noise
+const CURRENT_FILTER: usize = 4;
+/// A chunk is considered lost, if it hasn't arrived this long after it was requested, before the round trip time is measured
+const INITIAL_LOSS_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_LOSS_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Identifies a chunk of a file
+pub type ChunkId = (FileId, usize);
+
+struct Request {
+    sent: Instant,
+    /// Requests of lost chunks aren't measured, since an answer to the earlier request could arrive
+    retried: bool,
+}
+
+/// LEDBAT style congestion control (RFC 6817) for the chunks requested from a peer. The requests are limited by a window,
+/// which grows while the round trip time is close to the lowest one seen, and shrinks once the queuing delay reaches the target,
+/// so the transfer only uses the bandwidth which the other traffic leaves unused.
+pub struct CongestionController {
+    window: f64,
+    slow_start: bool,
+    in_flight: HashMap<ChunkId, Request>,
+    /// Chunks which have been considered lost, but haven't been requested again yet
+    lost: HashSet<ChunkId>,
+    base_delays: VecDeque<(Instant, Duration)>,
+    current_delays: VecDeque<Duration>,
+    srtt: Option<Duration>,
+    last_decrease: Option<Instant>,
+}
+
+impl CongestionController {
+    pub fn new() -> CongestionController {
+        CongestionController {
+            window: INITIAL_WINDOW,
+            slow_start: true,
+            in_flight: HashMap::new(),
+            lost: HashSet::new(),
+            base_delays: VecDeque::new(),
+            current_delays: VecDeque::new(),
+            srtt: None,
+            last_decrease: None,
+        }
+    }
+
+    /// Whether the window has room for another request
+    pub fn can_request(&self) -> bool {
+        (self.in_flight.len() as f64) < self.window
+    }
+
+    pub fn on_request(&mut self, chunk: ChunkId) {
+        let retried = self.lost.remove(&chunk);
+        self.in_flight.insert(chunk, Request {
+            sent: Instant::now(),
+            retried,
+        });
+    }
+
+    /// A requested chunk has arrived, so measure the delay and adjust the window
+    pub fn on_chunk(&mut self, chunk: &ChunkId) {
+        let request = match self.in_flight.remove(chunk) {
+            Some(request) => request,
+            None => return
+        };
+        if request.retried {
+            return;
+        }
+        self.sample(request.sent.elapsed());
+
+        let queuing_delay = self.queuing_delay();
+        if self.slow_start {
+            if queuing_delay < TARGET_DELAY / 2 {
+                self.window = (self.window + 1.0).min(MAX_WINDOW as f64);
+                return;
+            }
+            self.slow_start = false;
+        }
+        let off_target = (TARGET_DELAY.as_secs_f64() - queuing_delay.as_secs_f64()) / TARGET_DELAY.as_secs_f64();
+        self.window = (self.window + GAIN * off_target / self.window).max(MIN_WINDOW).min(MAX_WINDOW as f64);
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt
+        });
+
+        let now = Instant::now();
+        match self.base_delays.back_mut() {
+            Some((started, delay)) if now.duration_since(*started) < BASE_INTERVAL => *delay = (*delay).min(rtt),
+            _ => {
+                self.base_delays.push_back((now, rtt));
+                if self.base_delays.len() > BASE_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        self.current_delays.push_back(rtt);
+        if self.current_delays.len() > CURRENT_FILTER {
+            self.current_delays.pop_front();
+        }
+    }
+
+    /// How much longer the round trip takes than the lowest one seen, which is the time spent in queues
+    fn queuing_delay(&self) -> Duration {
+        let base = self.base_delays.iter().map(|(_, delay)| *delay).min();
+        let current = self.current_delays.iter().min();
+        match (base, current) {
+            (Some(base), Some(current)) => current.checked_sub(base).unwrap_or(Duration::from_secs(0)),
+            _ => Duration::from_secs(0)
+        }
+    }
+
+    fn loss_timeout(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt * 2).max(MIN_LOSS_TIMEOUT),
+            None => INITIAL_LOSS_TIMEOUT
+        }
+    }
+
+    /// Remove the chunks which haven't arrived in time, they have to be requested again.
+    /// Losses halve the window, at most once every round trip.
+    pub fn take_lost(&mut self) -> Vec<ChunkId> {
+        let timeout = self.loss_timeout();
+        let lost: Vec<ChunkId> = self.in_flight.iter()
+        .filter(|(_, request)| request.sent.elapsed() >= timeout)
+        .map(|(chunk, _)| chunk.clone())
+        .collect();
+        if lost.is_empty() {
+            return lost;
+        }
+
+        for chunk in &lost {
+            self.in_flight.remove(chunk);
+            self.lost.insert(chunk.clone());
+        }
+        let rtt = self.srtt.unwrap_or(INITIAL_LOSS_TIMEOUT);
+        if self.last_decrease.map_or(true, |time| time.elapsed() >= rtt) {
+            self.window = (self.window / 2.0).max(MIN_WINDOW);
+            self.slow_start = false;
+            self.last_decrease = Some(Instant::now());
+        }
+        lost
+    }
+
+    /// Time until the next requested chunk is considered lost
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let timeout = self.loss_timeout();
+        self.in_flight.values()
+        .map(|request| (request.sent + timeout).saturating_duration_since(Instant::now()))
+        .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_DELAY: Duration = Duration::from_millis(20);
+
+    fn chunk(index: usize) -> ChunkId {
+        ("file".to_string(), index)
+    }
+
+    /// Request a chunk and let it arrive after the round trip time
+    fn answer(cc: &mut CongestionController, chunk: ChunkId, rtt: Duration) {
+        cc.on_request(chunk.clone());
+        cc.in_flight.get_mut(&chunk).unwrap().sent -= rtt;
+        cc.on_chunk(&chunk);
+    }
+
+    #[test]
+    fn grows_by_a_chunk_in_slow_start() {
+        let mut cc = CongestionController::new();
+        for i in 0..10 {
+            answer(&mut cc, chunk(i), BASE_DELAY);
+        }
+        assert_eq!(cc.window, INITIAL_WINDOW + 10.0);
+        assert!(cc.slow_start);
+    }
+
+    #[test]
+    fn shrinks_when_the_delay_grows_past_the_target() {
+        let mut cc = CongestionController::new();
+        for i in 0..10 {
+            answer(&mut cc, chunk(i), BASE_DELAY);
+        }
+        let window = cc.window;
+        for i in 10..50 {
+            answer(&mut cc, chunk(i), BASE_DELAY + TARGET_DELAY * 4);
+        }
+        assert!(!cc.slow_start);
+        assert!(cc.window < window);
+
+        for i in 50..1000 {
+            answer(&mut cc, chunk(i), BASE_DELAY + TARGET_DELAY * 4);
+        }
+        assert_eq!(cc.window, MIN_WINDOW);
+    }
+
+    #[test]
+    fn grows_again_once_the_delay_drops() {
+        let mut cc = CongestionController::new();
+        answer(&mut cc, chunk(0), BASE_DELAY);
+        for i in 1..5 {
+            answer(&mut cc, chunk(i), BASE_DELAY + TARGET_DELAY * 2);
+        }
+        assert!(!cc.slow_start);
+
+        let window = cc.window;
+        for i in 5..10 {
+            answer(&mut cc, chunk(i), BASE_DELAY);
+        }
+        assert!(cc.window > window);
+        assert!(!cc.slow_start);
+    }
+
+    #[test]
+    fn halves_the_window_once_every_round_trip_on_losses() {
+        let mut cc = CongestionController::new();
+        for i in 0..10 {
+            answer(&mut cc, chunk(i), BASE_DELAY);
+        }
+        let window = cc.window;
+        for i in 10..13 {
+            cc.on_request(chunk(i));
+            cc.in_flight.get_mut(&chunk(i)).unwrap().sent -= MIN_LOSS_TIMEOUT;
+        }
+        assert_eq!(cc.take_lost().len(), 3);
+        assert_eq!(cc.window, window / 2.0);
+        assert!(!cc.slow_start);
+
+        cc.on_request(chunk(13));
+        cc.in_flight.get_mut(&chunk(13)).unwrap().sent -= MIN_LOSS_TIMEOUT;
+        assert_eq!(cc.take_lost(), vec![chunk(13)]);
+        assert_eq!(cc.window, window / 2.0);
+    }
+
+    #[test]
+    fn doesnt_measure_retried_requests() {
+        let mut cc = CongestionController::new();
+        cc.on_request(chunk(0));
+        cc.in_flight.get_mut(&chunk(0)).unwrap().sent -= INITIAL_LOSS_TIMEOUT;
+        assert_eq!(cc.take_lost(), vec![chunk(0)]);
+        let window = cc.window;
+
+        answer(&mut cc, chunk(0), Duration::from_secs(5));
+        assert!(cc.srtt.is_none());
+        assert_eq!(cc.window, window);
+        assert!(cc.next_timeout().is_none());
+    }
+}
@@ -6,4 +6,6 @@ pub mod udp_connection;
 #[cfg(feature = "audio")]
 pub mod audio;
 
-mod file_manager;
\ No newline at end of file
+mod file_manager;
+mod trust_store;
+mod call_policy_store;
\ No newline at end of file
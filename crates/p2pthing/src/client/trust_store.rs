@@ -0,0 +1,66 @@
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+use p2pthing_common::{encryption::NetworkedPublicKey, identity};
+
+/// Name of the trust store file inside the data directory
+const TRUST_STORE_FILE: &str = "known_peers";
+
+pub enum TrustStatus {
+    /// The peer hasn't been seen before, its key is now stored
+    New,
+    /// The peer's key matches the stored one
+    Trusted,
+    /// The peer announced a different key than the stored one
+    Changed(NetworkedPublicKey),
+}
+
+/// Trust on first use store, which remembers the first key seen for every peer name
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    known_peers: HashMap<String, NetworkedPublicKey>,
+}
+
+impl TrustStore {
+    /// Load the store from the data directory. If it can't be read, start with an empty one.
+    pub fn load() -> TrustStore {
+        let path = identity::data_dir().ok().map(|dir| dir.join(TRUST_STORE_FILE));
+        let known_peers = path.as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| bincode::deserialize(&data[..]).ok())
+            .unwrap_or_default();
+
+        TrustStore {
+            path,
+            known_peers,
+        }
+    }
+
+    /// Compare the key with the stored one, storing it if the peer is new
+    pub fn check(&mut self, name: &str, public_key: &NetworkedPublicKey) -> io::Result<TrustStatus> {
+        match self.known_peers.get(name) {
+            Some(known) if known == public_key => Ok(TrustStatus::Trusted),
+            Some(known) => Ok(TrustStatus::Changed(known.clone())),
+            None => {
+                self.trust(name, public_key)?;
+                Ok(TrustStatus::New)
+            }
+        }
+    }
+
+    /// Store the key for the peer, replacing the previous one
+    pub fn trust(&mut self, name: &str, public_key: &NetworkedPublicKey) -> io::Result<()> {
+        self.known_peers.insert(name.to_string(), public_key.clone());
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "Couldn't find a directory to store the trusted keys in"))
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, bincode::serialize(&self.known_peers).unwrap())
+    }
+}
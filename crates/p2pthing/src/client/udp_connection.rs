@@ -1,10 +1,33 @@
-use std::{io, net::SocketAddr, rc::Rc, time::{Duration, Instant}};
+use std::{collections::VecDeque, io, net::SocketAddr, rc::Rc, time::{Duration, Instant}};
 
 use mio::net::UdpSocket;
-use p2pthing_common::{encryption::{AsymmetricEncryption, NetworkedPublicKey, SymmetricEncryption}, message_type::{MsgEncryption, MsgType, UdpPacket}, statistics::Statistics};
+use p2pthing_common::{encryption::{AsymmetricEncryption, EphemeralKeyExchange, NetworkedPublicKey, NONCE_LENGTH, NoiseHandshake, PADDING_BUCKET_SIZE, SymmetricEncryption, TAG_LENGTH}, message_type::{MsgEncryption, MsgType, Stream, UdpPacket, msg_types::{self, Acknowledgement}}, protocol::Capabilities, statistics::Statistics};
 use serde::Serialize;
 
-use super::connection_manager::{RELIABLE_MESSAGE_DELAY, KEEP_ALIVE_DELAY_MIDCALL, ANNOUNCE_DELAY, KEEP_ALIVE_DELAY, UdpHolder};
+use super::connection_manager::{KEEP_ALIVE_DELAY_MIDCALL, ANNOUNCE_DELAY, KEEP_ALIVE_DELAY, PEER_TIMEOUT, PUNCH_THROUGH_TIMEOUT, UdpHolder};
+
+mod duplicates;
+mod fragmentation;
+mod migration;
+mod pmtu;
+mod reliability;
+mod send_queue;
+mod streams;
+pub use duplicates::DuplicateFilter;
+pub use fragmentation::Reassembler;
+pub use migration::PathValidation;
+pub use pmtu::PmtuDiscovery;
+pub use reliability::{ReceiveStatus, ReliableReceiver, RttEstimator};
+use reliability::{FAST_RETRANSMIT_THRESHOLD, RELIABLE_WINDOW, is_acknowledged};
+pub use send_queue::{DEFAULT_SEND_RATE, Priority, SendQueue};
+pub use streams::{ReceivedMessage, Streams};
+#[cfg(test)]
+mod test_utils;
+
+/// A reliable message is given up on after it has been retransmitted this many times
+const MAX_RETRANSMISSIONS: u32 = 6;
+/// Bytes of a datagram used by everything but the message: the header of a reliable packet, the nonce, the authentication tag and the message type
+const PACKET_OVERHEAD: usize = 65;
 
 #[derive(PartialEq)]
 pub enum UdpConnectionState {
@@ -14,25 +37,71 @@ pub enum UdpConnectionState {
     Connected=1,
     /// The socket is waiting for the server to accept the announce
     Unannounced=2,
-    /// We are waiting for the ui to decide whether we should start connecting
-    Pending=3
+    /// Nothing has been received from the peer for a while, so the punch through is being done again
+    Reconnecting=4,
+    /// The peer couldn't be reached again. Nothing is sent, until the peer answers or is called again.
+    Disconnected=5
+}
+
+/// The handshake used to create the encrypted tunnel with a peer.
+/// The caller decides which one is used, the callee answers either of them.
+#[derive(Clone, Copy, PartialEq)]
+pub enum HandshakeMode {
+    /// Signed ephemeral Diffie-Hellman keys
+    KeyExchange,
+    /// Noise XX handshake
+    Noise
 }
 
 pub struct UdpConnection {
     pub associated_peer: Option<NetworkedPublicKey>,
     pub address: SocketAddr,
+    /// Sent with every packet to the peer, so the peer still finds the connection after our address changes
+    pub connection_id: u64,
+    /// The peer's connection id, taken from its authenticated packets
+    pub peer_connection_id: Option<u64>,
+    /// The new address of the peer, which the connection is moved to once it's validated
+    pub path_validation: Option<PathValidation>,
     pub last_message_sent: Option<Instant>,
+    /// When the last message was received from the other side, or when the punch through started
+    pub last_message_received: Instant,
     pub last_announce: Option<Instant>,
     pub state: UdpConnectionState,
+    /// Id of the next unreliable message
     pub next_msg_id: u32,
-    /// Messages waiting to be confirmed that they arrived
-    pub sent_messages: Vec<UdpHolder>,
-    /// List of message ids, so duplicate messages can be thrown away
-    pub received_messages: Vec<u32>,
+    /// Id of the next reliable message. Reliable messages have their own ids, so they can be acknowledged cumulatively.
+    pub next_reliable_id: u32,
+    /// Reliable messages in flight, waiting to be acknowledged, in the order they were sent
+    pub sent_messages: VecDeque<UdpHolder>,
+    /// Reliable messages waiting for room in the window
+    pub queued_messages: VecDeque<UdpHolder>,
+    pub rtt: RttEstimator,
+    /// Received reliable messages, which are deduplicated and acknowledged
+    pub reliable_receiver: ReliableReceiver,
+    /// Reliable messages are sent and delivered in order, within their stream
+    pub streams: Streams,
+    /// Id of the next packet which has to be split into fragments
+    pub next_fragment_id: u32,
+    pub reassembler: Reassembler,
+    pub pmtu: PmtuDiscovery,
+    /// Received unreliable messages, so duplicated and replayed messages can be thrown away
+    pub received_messages: DuplicateFilter,
     pub sock: Rc<UdpSocket>,
+    /// Datagrams waiting for the socket, sent in the order of their priority
+    pub send_queue: SendQueue,
     pub symmetric_key: Option<SymmetricEncryption>,
+    /// Our half of a started key exchange, which the peer hasn't answered yet
+    pub key_exchange: Option<EphemeralKeyExchange>,
+    /// A Noise handshake which is in progress
+    pub noise_handshake: Option<NoiseHandshake>,
+    /// Our half of a started rekey, which the other side hasn't answered yet
+    pub rekey: Option<EphemeralKeyExchange>,
     /// Is a symmetrically encrypted tunnel created?
     pub upgraded: bool,
+    /// Privacy mode: once upgraded, hide the packet headers and pad the packets, see `UdpPacket::seal`
+    pub sealed: bool,
+    /// The optional features which both sides have, known once the peer's handshake has arrived
+    pub capabilities: Capabilities,
     pub encryption: Rc<AsymmetricEncryption>,
     pub statistics: Statistics
 }
@@ -42,48 +111,48 @@ impl UdpConnection{
         UdpConnection{
             associated_peer: None,
             address,
+            connection_id: rand::random(),
+            peer_connection_id: None,
+            path_validation: None,
             last_message_sent: None,
+            last_message_received: Instant::now(),
             last_announce: None,
             state,
             next_msg_id: 0,
-            sent_messages: vec![],
+            next_reliable_id: 0,
+            sent_messages: VecDeque::new(),
+            queued_messages: VecDeque::new(),
+            rtt: RttEstimator::new(),
+            reliable_receiver: ReliableReceiver::new(),
+            streams: Streams::new(),
+            next_fragment_id: 0,
+            reassembler: Reassembler::new(),
+            pmtu: PmtuDiscovery::new(),
             sock: sock.clone(),
+            send_queue: SendQueue::new(),
             symmetric_key,
-            received_messages: vec![],
+            key_exchange: None,
+            noise_handshake: None,
+            rekey: None,
+            received_messages: DuplicateFilter::new(),
             upgraded: false,
+            sealed: false,
+            capabilities: Capabilities::empty(),
             encryption,
             statistics: Statistics::new()
         }
     }
 
-    pub fn send_udp_message<T: ?Sized>(&mut self, t: MsgType, msg: &T, reliable: bool, custom_id: Option<u32>) where T: Serialize {
+    pub fn send_udp_message<T: ?Sized>(&mut self, t: MsgType, msg: &T, reliable: bool, custom_id: Option<u32>) -> Result<(), String> where T: Serialize {
         match self.upgraded {
-            true => self.send_udp_message_with_asymmetric_key(t, msg, reliable, custom_id).unwrap(),
-            false => self.send_udp_message_with_public_key(t, msg, reliable, custom_id).unwrap()
+            true => self.send_udp_message_with_asymmetric_key(t, msg, reliable, custom_id),
+            false => self.send_udp_message_with_public_key(t, msg, reliable, custom_id)
         }
     }
 
     /// Send a UDP packet encrypted with the symmetric key, which optionally can be reliable
     pub fn send_udp_message_with_asymmetric_key<T: ?Sized>(&mut self, msg_type: MsgType, msg: &T, reliable: bool, custom_id: Option<u32>) -> Result<(), String> where T: Serialize  {
-        let t: u8 = num::ToPrimitive::to_u8(&msg_type).unwrap();
-        let msg = &bincode::serialize(msg).unwrap()[..];
-        let chained: &[u8] = &[&[t], msg].concat()[..];
-
-        let symmetric_key = match &self.symmetric_key{
-            Some(p) => p,
-            None => {
-                return Err("Cannot find symmetric key".into());
-            }
-        };
-        let encrypted = symmetric_key.encrypt(&chained[..]);
-
-        let wrapped = UdpPacket {
-            data: encrypted,
-            reliable,
-            msg_id: self.next_msg_id,
-            upgraded: MsgEncryption::SymmetricKey
-        };
-
+        let wrapped = self.encrypted_packet(&msg_type, msg, reliable)?;
         if let Err(e) =  self.send_udp_packet(msg_type, wrapped, reliable, custom_id) {
             return Err(format!("Error while sending udp packet: {}", e));
         }
@@ -91,6 +160,31 @@ impl UdpConnection{
         Ok(())
     }
 
+    /// Wrap a message encrypted with the symmetric key into a packet
+    fn encrypted_packet<T: ?Sized>(&mut self, msg_type: &MsgType, msg: &T, reliable: bool) -> Result<UdpPacket, String> where T: Serialize  {
+        let t: u8 = num::ToPrimitive::to_u8(msg_type).unwrap();
+        let msg = &bincode::serialize(msg).unwrap()[..];
+        let chained: &[u8] = &[&[t], msg].concat()[..];
+
+        if self.symmetric_key.is_none() {
+            return Err("Cannot find symmetric key".into());
+        }
+        // The data is only encrypted when the packet is sent, since the header it's authenticated with isn't complete yet.
+        // Sealed packets are encrypted together with their header.
+        Ok(UdpPacket {
+            data: chained.to_vec(),
+            reliable,
+            msg_id: 0,
+            stream: None,
+            fragment: None,
+            connection_id: self.packet_connection_id(),
+            upgraded: match self.sealed {
+                true => MsgEncryption::Sealed,
+                false => MsgEncryption::SymmetricKey
+            }
+        })
+    }
+
     /// Send a UDP packet encrypted with the public, which optionally can be reliable
     pub fn send_udp_message_with_public_key<T: ?Sized>(&mut self, msg_type: MsgType, msg: &T, reliable: bool, custom_id: Option<u32>) -> Result<(), String> where T: Serialize  {
         let t: u8 = num::ToPrimitive::to_u8(&msg_type).unwrap();
@@ -103,11 +197,14 @@ impl UdpConnection{
                 return Err("Cannot find udp connection".into());
             }
         };
-        let encrypted = public_key.encrypt(chained);
+        let encrypted = public_key.encrypt(chained).map_err(|e| e.to_string())?;
         let wrapped = UdpPacket {
             data: encrypted,
             reliable,
-            msg_id: self.next_msg_id,
+            msg_id: 0,
+            stream: None,
+            fragment: None,
+            connection_id: self.packet_connection_id(),
             upgraded: MsgEncryption::PublicKey
         };
         if let Err(e) = self.send_udp_packet(msg_type, wrapped, reliable, custom_id){
@@ -126,88 +223,560 @@ impl UdpConnection{
         let packet = UdpPacket {
             data: chained.to_vec(),
             reliable,
-            msg_id: self.next_msg_id,
+            msg_id: 0,
+            stream: None,
+            fragment: None,
+            connection_id: self.packet_connection_id(),
             upgraded: MsgEncryption::Unencrypted
         };
         self.send_udp_packet(msg_type, packet, reliable, custom_id)
     }
 
-    pub fn send_udp_packet(&mut self, msg_type: MsgType, packet: UdpPacket, reliable: bool, custom_id: Option<u32>) -> io::Result<()> {
-        self.next_msg_id += 1;
-        let wrapped_data = &bincode::serialize(&packet).unwrap()[..];
-        if reliable {
-            self.sent_messages.push(UdpHolder{
-                packet,
-                last_send: Instant::now(),
-                sent: Instant::now(),
-                sock: self.sock.clone(),
-                address: self.address,
-                msg_type,
-                custom_id
-            });
+    /// Packets sent to the rendezvous server don't need a connection id, the server never moves
+    fn packet_connection_id(&self) -> Option<u64> {
+        self.associated_peer.as_ref().map(|_| self.connection_id)
+    }
+
+    /// Give the packet its id and send it. Reliable packets are also given their place in their stream,
+    /// and wait in a queue if the window is full.
+    pub fn send_udp_packet(&mut self, msg_type: MsgType, mut packet: UdpPacket, reliable: bool, custom_id: Option<u32>) -> io::Result<()> {
+        if !reliable {
+            packet.msg_id = self.next_msg_id;
+            self.next_msg_id = self.next_msg_id.wrapping_add(1);
+            return self.transmit(&packet, self.address, Priority::of(&msg_type));
         }
 
-        let sent = self.sock.send_to(wrapped_data, self.address)?;
-        self.statistics.sent_bytes(sent as u64);
-        self.last_message_sent = Some(Instant::now());
+        packet.msg_id = self.next_reliable_id;
+        self.next_reliable_id = self.next_reliable_id.wrapping_add(1);
+        packet.stream = Some(self.streams.next_header(Stream::of(&msg_type)));
+        self.queued_messages.push_back(UdpHolder{
+            packet,
+            last_send: Instant::now(),
+            sent: Instant::now(),
+            retransmissions: 0,
+            msg_type,
+            custom_id
+        });
+        self.send_queued_messages()
+    }
+
+    /// Send the queued reliable messages which fit into the window
+    fn send_queued_messages(&mut self) -> io::Result<()> {
+        while let Some(queued) = self.queued_messages.front() {
+            // The oldest unacknowledged message limits how far ahead we can send
+            if let Some(oldest) = self.sent_messages.front() {
+                if queued.packet.msg_id.wrapping_sub(oldest.packet.msg_id) >= RELIABLE_WINDOW {
+                    break;
+                }
+            }
+            let mut holder = self.queued_messages.pop_front().unwrap();
+            holder.last_send = Instant::now();
+            holder.sent = Instant::now();
+            let result = self.transmit(&holder.packet, self.address, Priority::of(&holder.msg_type));
+            self.sent_messages.push_back(holder);
+            result?;
+        }
         Ok(())
     }
 
-    pub fn next_keep_alive(&mut self) -> Duration {
+    /// Write the packet to the socket, encrypting or sealing it first if needed.
+    /// Packets which don't fit into a single datagram are split into fragments.
+    fn transmit(&mut self, packet: &UdpPacket, address: SocketAddr, priority: Priority) -> io::Result<()> {
+        let encrypted;
+        let packet = match packet.upgraded {
+            MsgEncryption::SymmetricKey | MsgEncryption::Sealed => {
+                let key = self.symmetric_key.as_mut()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Cannot find symmetric key"))?;
+                encrypted = match packet.upgraded {
+                    MsgEncryption::Sealed => packet.seal(key),
+                    _ => packet.encrypt(key)
+                };
+                &encrypted
+            }
+            _ => packet
+        };
+
+        let wrapped_data = bincode::serialize(packet).unwrap();
+        if wrapped_data.len() <= self.pmtu.mtu() {
+            return self.send_datagram(wrapped_data, address, priority);
+        }
+        let id = self.next_fragment_id;
+        self.next_fragment_id = self.next_fragment_id.wrapping_add(1);
+        // The fragments of encrypted packets are authenticated one by one, which takes up some of the room
+        let authenticated = matches!(packet.upgraded, MsgEncryption::SymmetricKey | MsgEncryption::Sealed);
+        let mtu = match authenticated {
+            true => self.pmtu.mtu() - NONCE_LENGTH - TAG_LENGTH,
+            false => self.pmtu.mtu()
+        };
+        let fragments = fragmentation::split(packet, id, mtu)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "The message is too large to be sent"))?;
+        for fragment in fragments {
+            let fragment = match authenticated {
+                true => fragment.encrypt_fragment(self.symmetric_key.as_mut().unwrap()),
+                false => fragment
+            };
+            self.send_datagram(bincode::serialize(&fragment).unwrap(), address, priority)?;
+        }
+        Ok(())
+    }
+
+    /// Queue the datagram, and send what the pacing allows
+    fn send_datagram(&mut self, data: Vec<u8>, address: SocketAddr, priority: Priority) -> io::Result<()> {
+        self.send_queue.push(priority, address, data);
+        self.last_message_sent = Some(Instant::now());
+        self.flush()
+    }
+
+    /// Send the queued datagrams, as far as the socket and the pacing allow
+    pub fn flush(&mut self) -> io::Result<()> {
+        let (sent, result) = self.send_queue.flush(&self.sock);
+        self.statistics.sent_bytes(sent as u64);
+        result
+    }
+
+    /// The largest message which fits into a single datagram on the path to the peer
+    pub fn max_message_size(&self) -> usize {
+        match self.sealed {
+            // Sealed packets are padded, so the message has to fit into a single bucket.
+            // The inner packet's header and the padding's length are smaller than the outer overhead.
+            true => PADDING_BUCKET_SIZE - PACKET_OVERHEAD,
+            false => self.pmtu.mtu() - PACKET_OVERHEAD
+        }
+    }
+
+    /// Whether the path MTU to the peer should be discovered. Sealed packets are padded to fixed sizes,
+    /// so they always use the base MTU, which a single padding bucket fits into.
+    pub fn probes_mtu(&self) -> bool {
+        self.upgraded && !self.sealed && self.capabilities.contains(Capabilities::MTU_PROBES) && self.associated_peer.is_some() && self.state == UdpConnectionState::Connected
+    }
+
+    /// Send the next path MTU probe, if it's due. The probe is padded so the datagram has exactly the probed size,
+    /// and it's never split into fragments.
+    pub fn probe_mtu(&mut self) -> io::Result<()> {
+        let (id, size) = match self.pmtu.poll() {
+            Some(probe) => probe,
+            None => return Ok(())
+        };
+        let mut probe = msg_types::MtuProbe {
+            id,
+            padding: vec![]
+        };
+        let unpadded = self.probe_datagram(&probe)?.len();
+        probe.padding = vec![0; size.saturating_sub(unpadded)];
+        let datagram = self.probe_datagram(&probe)?;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1);
+        self.send_datagram(datagram, self.address, Priority::of(&MsgType::MtuProbe))
+    }
+
+    fn probe_datagram(&mut self, probe: &msg_types::MtuProbe) -> io::Result<Vec<u8>> {
+        let t: u8 = num::ToPrimitive::to_u8(&MsgType::MtuProbe).unwrap();
+        let chained = [&[t], &bincode::serialize(probe).unwrap()[..]].concat();
+        let key = self.symmetric_key.as_mut()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Cannot find symmetric key"))?;
+        let packet = UdpPacket {
+            data: chained,
+            reliable: false,
+            msg_id: self.next_msg_id,
+            stream: None,
+            fragment: None,
+            connection_id: Some(self.connection_id),
+            upgraded: MsgEncryption::SymmetricKey
+        };
+        Ok(bincode::serialize(&packet.encrypt(key)).unwrap())
+    }
+
+    /// Start validating the new address of the peer, unless another address is being validated
+    pub fn validate_path(&mut self, address: SocketAddr) -> Result<(), String> {
+        if self.path_validation.is_none() {
+            self.path_validation = Some(PathValidation::new(address));
+        }
+        self.poll_path_validation()
+    }
+
+    /// Send the path challenge if it's due, or give up on the new address once too many challenges have been lost
+    pub fn poll_path_validation(&mut self) -> Result<(), String> {
+        let validation = match &mut self.path_validation {
+            Some(validation) => validation,
+            None => return Ok(())
+        };
+        if validation.failed() {
+            let address = validation.address;
+            self.path_validation = None;
+            return Err(format!("The peer didn't answer the path challenges sent to ({})", address));
+        }
+        if !validation.poll() {
+            return Ok(());
+        }
+        let address = validation.address;
+        let challenge = msg_types::PathChallenge {
+            token: validation.token
+        };
+        let mut packet = self.encrypted_packet(&MsgType::PathChallenge, &challenge, false)?;
+        packet.msg_id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1);
+        self.transmit(&packet, address, Priority::of(&MsgType::PathChallenge)).map_err(|e| e.to_string())
+    }
+
+    /// Move the connection to the address, if the path response answers the challenge sent there.
+    /// The new path can carry datagrams of a different size, so its MTU is discovered again.
+    pub fn migrate(&mut self, address: SocketAddr, response: &msg_types::PathResponse) -> bool {
+        match &self.path_validation {
+            Some(validation) if validation.address == address && validation.token == response.token => {
+                self.address = address;
+                self.path_validation = None;
+                self.pmtu = PmtuDiscovery::new();
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// Switch to the keys of our rekey, now that the other side has answered it.
+    /// Returns false if no rekey has been started, so a replayed answer can't change the keys again.
+    pub fn finish_rekey(&mut self, ack: &msg_types::RekeyAck) -> Result<bool, String> {
+        let rekey = match self.rekey.take() {
+            Some(rekey) => rekey,
+            None => return Ok(false)
+        };
+        let key = self.symmetric_key.as_mut().ok_or("Cannot find symmetric key")?;
+        let (send_secret, recv_secret) = rekey.derive_rekey(&ack.ephemeral_key, true).map_err(|e| e.to_string())?;
+        key.rekey(send_secret, recv_secret);
+        Ok(true)
+    }
+
+    /// Time until the next keep alive (or announcement) has to be sent. Disconnected connections don't send any.
+    pub fn next_keep_alive(&self) -> Option<Duration> {
         let delay = match self.state {
-            UdpConnectionState::MidCall => KEEP_ALIVE_DELAY_MIDCALL,
+            UdpConnectionState::MidCall | UdpConnectionState::Reconnecting => KEEP_ALIVE_DELAY_MIDCALL,
             UdpConnectionState::Connected => KEEP_ALIVE_DELAY,
             UdpConnectionState::Unannounced => ANNOUNCE_DELAY,
-            UdpConnectionState::Pending => Duration::new(u64::MAX, 0)
+            UdpConnectionState::Disconnected => return None
         };
         match self.last_message_sent {
-            Some(last_message_sent) => {
-                (last_message_sent + delay).checked_duration_since(last_message_sent).unwrap_or(Duration::from_secs(0))
-            } 
-            None => Duration::from_secs(0)
+            Some(last_message_sent) => Some((last_message_sent + delay).saturating_duration_since(Instant::now())),
+            None => Some(Duration::from_secs(0))
         }
-        
     }
 
-    pub fn next_resendable(&mut self) -> Option<Duration> {
-        self.sent_messages.sort_by(|a, b| a.last_send.cmp(&b.last_send));
-        match self.sent_messages.get(0) {
-            Some(msg) => {
-                let duration_since = (msg.last_send + RELIABLE_MESSAGE_DELAY).checked_duration_since(msg.last_send);
-                match duration_since {
-                    Some(duration) => Some(duration),
-                    None => Some(Duration::from_secs(0))
+    /// Lists the times until something has to be done on the connection
+    pub fn next_timeouts(&self) -> Vec<Duration> {
+        let mut durations = vec![];
+        durations.extend(self.next_resendable());
+        durations.extend(self.reliable_receiver.next_ack());
+        if self.probes_mtu() {
+            durations.push(self.pmtu.next_timeout());
+        }
+        durations.extend(self.next_keep_alive());
+        durations.extend(self.next_peer_timeout());
+        if let Some(validation) = &self.path_validation {
+            durations.push(validation.next_timeout());
+        }
+        durations.extend(self.send_queue.next_timeout());
+        durations
+    }
+
+    /// Time until the peer is considered lost, or the punch through with it is given up on.
+    /// The rendezvous server's connection doesn't time out, the server is watched through TCP.
+    pub fn next_peer_timeout(&self) -> Option<Duration> {
+        self.associated_peer.as_ref()?;
+        let timeout = match self.state {
+            UdpConnectionState::Connected => PEER_TIMEOUT,
+            UdpConnectionState::MidCall => PUNCH_THROUGH_TIMEOUT,
+            UdpConnectionState::Reconnecting => PEER_TIMEOUT + PUNCH_THROUGH_TIMEOUT,
+            _ => return None
+        };
+        Some((self.last_message_received + timeout).saturating_duration_since(Instant::now()))
+    }
+
+    /// Time until the next reliable message has to be retransmitted
+    pub fn next_resendable(&self) -> Option<Duration> {
+        self.sent_messages.iter()
+        .map(|msg| (msg.last_send + self.rtt.rto(msg.retransmissions)).saturating_duration_since(Instant::now()))
+        .min()
+    }
+
+    /// Retransmit the reliable messages whose timeout has expired, with exponential backoff.
+    /// Returns the messages which have been given up on.
+    pub fn resend_reliable_messages(&mut self) -> Vec<UdpHolder> {
+        let mut given_up = vec![];
+        let mut i = 0;
+        while i < self.sent_messages.len() {
+            let msg = &self.sent_messages[i];
+            if msg.last_send.elapsed() < self.rtt.rto(msg.retransmissions) {
+                i += 1;
+                continue;
+            }
+            if msg.retransmissions >= MAX_RETRANSMISSIONS {
+                let msg = self.sent_messages.remove(i).unwrap();
+                // The peer can't acknowledge anything past a message which never arrives,
+                // so it's replaced with a notice under the same id, which tells the peer to move on
+                if let Some(notice) = self.skip_notice(&msg) {
+                    self.transmit(&notice.packet, self.address, Priority::of(&notice.msg_type)).ok();
+                    self.sent_messages.insert(i, notice);
+                    i += 1;
                 }
+                given_up.push(msg);
+                continue;
+            }
+            self.retransmit(i);
+            i += 1;
+        }
+        // Giving up can make room in the window
+        self.send_queued_messages().ok();
+        given_up
+    }
+
+    /// The notice which takes the place of a message that has been given up on, also in its stream,
+    /// so the later messages of the stream don't wait for it forever. The notices themselves aren't replaced,
+    /// and the rendezvous server doesn't acknowledge anything.
+    fn skip_notice(&self, msg: &UdpHolder) -> Option<UdpHolder> {
+        if matches!(msg.msg_type, MsgType::Skipped) {
+            return None;
+        }
+        let peer = self.associated_peer.as_ref()?;
+        let skipped = msg_types::Skipped {
+            msg_type: num::ToPrimitive::to_u8(&msg.msg_type).unwrap()
+        };
+        let t: u8 = num::ToPrimitive::to_u8(&MsgType::Skipped).unwrap();
+        let chained = [&[t], &bincode::serialize(&skipped).unwrap()[..]].concat();
+        // Messages encrypted with the symmetric key are only encrypted when they are transmitted
+        let data = match msg.packet.upgraded {
+            MsgEncryption::PublicKey => peer.encrypt(&chained).ok()?,
+            _ => chained
+        };
+        Some(UdpHolder {
+            packet: UdpPacket {
+                data,
+                ..msg.packet.clone()
             },
-            None => None
+            last_send: Instant::now(),
+            sent: Instant::now(),
+            retransmissions: 0,
+            msg_type: MsgType::Skipped,
+            custom_id: None
+        })
+    }
+
+    fn retransmit(&mut self, i: usize) {
+        let packet = self.sent_messages[i].packet.clone();
+        let priority = Priority::of(&self.sent_messages[i].msg_type);
+        self.transmit(&packet, self.address, priority).ok();
+        let msg = &mut self.sent_messages[i];
+        msg.last_send = Instant::now();
+        msg.retransmissions += 1;
+    }
+
+    /// Acknowledge the received reliable messages, if it's due
+    pub fn send_acknowledgement(&mut self) -> Result<(), String> {
+        match self.reliable_receiver.take_ack() {
+            Some(ack) => self.send_udp_message(MsgType::MessageConfirmation, &ack, false, None),
+            None => Ok(())
         }
     }
 
-    pub fn resend_reliable_messages(&mut self) {
-        for packet in self.sent_messages.iter_mut() {
-            if packet.last_send.elapsed() > RELIABLE_MESSAGE_DELAY {
-                packet.resend();
-                self.last_message_sent = Some(Instant::now());
+    /// Remove the acknowledged messages, and return them. Also measures the round trip time,
+    /// and retransmits the messages which have been skipped by the acknowledgement.
+    pub fn on_acknowledgement(&mut self, ack: &Acknowledgement) -> Vec<UdpHolder> {
+        let mut acknowledged = vec![];
+        let mut i = 0;
+        while i < self.sent_messages.len() {
+            match is_acknowledged(ack, self.sent_messages[i].packet.msg_id) {
+                true => acknowledged.push(self.sent_messages.remove(i).unwrap()),
+                false => i += 1
             }
         }
+
+        // Measure the newest message which hasn't been retransmitted, if there is one
+        if let Some(msg) = acknowledged.iter().max_by_key(|msg| (msg.retransmissions == 0, msg.sent)) {
+            if let Some(rtt) = self.rtt.measure(msg.sent, msg.retransmissions) {
+                self.statistics.new_ping(rtt);
+            }
+        }
+
+        // Fast retransmit: the messages were most likely lost, if several later ones have arrived
+        if ack.received != 0 {
+            let newest = ack.next_id.wrapping_add(64 - ack.received.leading_zeros());
+            for i in 0..self.sent_messages.len() {
+                let msg = &self.sent_messages[i];
+                let behind = newest.wrapping_sub(msg.packet.msg_id);
+                if msg.retransmissions == 0 && (FAST_RETRANSMIT_THRESHOLD..RELIABLE_WINDOW).contains(&behind) {
+                    self.retransmit(i);
+                }
+            }
+        }
+
+        self.send_queued_messages().ok();
+        acknowledged
+    }
+
+    /// Authenticate a fragment before it's stored, only the handshake can be fragmented without encryption
+    pub fn decrypt_fragment(&mut self, fragment: UdpPacket) -> Result<UdpPacket, String> {
+        match fragment.upgraded {
+            MsgEncryption::SymmetricKey | MsgEncryption::Sealed => match &mut self.symmetric_key {
+                Some(key) => fragment.decrypt_fragment(key).map_err(|e| e.to_string()),
+                None => Err("Cannot find symmetric key".into())
+            },
+            _ if self.upgraded => Err("Unencrypted fragment, the keys have already been exchanged".into()),
+            _ => Ok(fragment)
+        }
     }
 
-    pub fn send_confirmation(&mut self, id: u32) {
-        self.send_udp_message(MsgType::MessageConfirmation, &id, false, None);
+    /// Recover the real packet, if it has been sealed
+    pub fn unseal(&mut self, packet: UdpPacket) -> Result<UdpPacket, String> {
+        match packet.upgraded {
+            MsgEncryption::Sealed => match &mut self.symmetric_key {
+                Some(key) => packet.unseal(key).map_err(|e| e.to_string()),
+                None => Err("Cannot find symmetric key".into())
+            },
+            _ => Ok(packet)
+        }
     }
 
-    pub fn decrypt(&self, packet: UdpPacket) -> Result<Vec<u8>, ()> {
+    pub fn decrypt(&mut self, packet: UdpPacket) -> Result<Vec<u8>, String> {
         match packet.upgraded {
             MsgEncryption::SymmetricKey => {
-                match &self.symmetric_key {
-                    Some(key) => Ok(key.decrypt(&packet.data[..])),
+                match &mut self.symmetric_key {
+                    Some(key) => packet.decrypt(key).map_err(|e| e.to_string()),
                     None => {
-                        return Err(())
+                        return Err("Cannot find symmetric key".into())
                     }
                 }
             },
-            MsgEncryption::PublicKey => Ok(self.encryption.decrypt(&packet.data[..])),
+            MsgEncryption::PublicKey => self.encryption.decrypt(&packet.data[..]).map_err(|e| e.to_string()),
+            // The packet has already been decrypted by `unseal`
+            MsgEncryption::Sealed => Ok(packet.data),
             MsgEncryption::Unencrypted => Ok(packet.data)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::{connection, peer_connection};
+
+    #[test]
+    fn keeps_alive_after_the_delay() {
+        let mut conn = connection(UdpConnectionState::Connected);
+        assert_eq!(conn.next_keep_alive(), Some(Duration::from_secs(0)));
+        conn.last_message_sent = Some(Instant::now());
+        let remaining = conn.next_keep_alive().unwrap();
+        assert!(remaining <= KEEP_ALIVE_DELAY && remaining > KEEP_ALIVE_DELAY / 2);
+        conn.last_message_sent = Some(Instant::now() - KEEP_ALIVE_DELAY * 2);
+        assert_eq!(conn.next_keep_alive(), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn disconnected_connections_dont_time_out() {
+        let mut disconnected = connection(UdpConnectionState::Disconnected);
+        disconnected.last_message_sent = Some(Instant::now());
+        assert_eq!(disconnected.next_keep_alive(), None);
+        assert!(disconnected.next_timeouts().is_empty());
+
+        let mut connected = connection(UdpConnectionState::MidCall);
+        connected.last_message_sent = Some(Instant::now());
+        let timeouts: Vec<_> = [disconnected, connected].iter().flat_map(|c| c.next_timeouts()).collect();
+        assert!(timeouts.iter().min().unwrap() <= &KEEP_ALIVE_DELAY_MIDCALL);
+    }
+
+    #[test]
+    fn migrates_once_the_challenge_is_answered() {
+        let mut conn = peer_connection();
+        let old_address = conn.address;
+        let new_address = "127.0.0.1:10".parse().unwrap();
+        conn.validate_path(new_address).unwrap();
+        let token = conn.path_validation.as_ref().unwrap().token;
+
+        // Responses with the wrong token, or from another address don't move the connection
+        assert!(!conn.migrate(new_address, &msg_types::PathResponse { token: token.wrapping_add(1) }));
+        assert!(!conn.migrate("127.0.0.1:11".parse().unwrap(), &msg_types::PathResponse { token }));
+        assert_eq!(conn.address, old_address);
+
+        assert!(conn.migrate(new_address, &msg_types::PathResponse { token }));
+        assert_eq!(conn.address, new_address);
+        assert!(conn.path_validation.is_none());
+        // A replayed response can't move it again
+        assert!(!conn.migrate(new_address, &msg_types::PathResponse { token }));
+    }
+
+    #[test]
+    fn gives_up_on_a_new_address_which_doesnt_answer() {
+        let mut conn = peer_connection();
+        let old_address = conn.address;
+        conn.validate_path("127.0.0.1:10".parse().unwrap()).unwrap();
+        let mut attempts = 1;
+        let result = loop {
+            conn.path_validation.as_mut().unwrap().expire();
+            match conn.poll_path_validation() {
+                Ok(()) => attempts += 1,
+                Err(e) => break e
+            }
+        };
+        assert!(result.contains("127.0.0.1:10"));
+        assert_eq!(attempts, 3);
+        assert!(conn.path_validation.is_none());
+        assert_eq!(conn.address, old_address);
+    }
+
+    #[test]
+    fn later_messages_arrive_after_giving_up_on_one() {
+        let mut conn = peer_connection();
+        let mut receiver = ReliableReceiver::new();
+        for _ in 0..RELIABLE_WINDOW * 2 {
+            conn.send_udp_message(MsgType::ChatMessage, &(), true, None).unwrap();
+        }
+        // The first message is never acknowledged
+        let lost = conn.sent_messages.front_mut().unwrap();
+        lost.retransmissions = MAX_RETRANSMISSIONS;
+        lost.last_send -= Duration::from_secs(120);
+        let given_up = conn.resend_reliable_messages();
+        assert_eq!(given_up.len(), 1);
+        let notice = conn.sent_messages.front().unwrap();
+        assert_eq!(notice.packet.msg_id, given_up[0].packet.msg_id);
+        assert_eq!(notice.packet.data[0], MsgType::Skipped as u8);
+        // It also takes the lost message's place in its stream
+        assert_eq!(notice.packet.stream.unwrap().seq, given_up[0].packet.stream.unwrap().seq);
+
+        // The peer receives the notice instead of the lost message, and everything after it
+        while !conn.sent_messages.is_empty() {
+            let ids: Vec<u32> = conn.sent_messages.iter().map(|msg| msg.packet.msg_id).collect();
+            for id in ids {
+                assert!(matches!(receiver.check(id), ReceiveStatus::New), "message {} was refused", id);
+                receiver.update(id);
+            }
+            receiver.request_ack();
+            conn.on_acknowledgement(&receiver.take_ack().unwrap());
+        }
+        assert!(conn.queued_messages.is_empty());
+        assert_eq!(conn.next_reliable_id, RELIABLE_WINDOW * 2);
+    }
+
+    #[test]
+    fn a_replayed_rekey_answer_doesnt_change_the_keys() {
+        let secret = [7u8; 32];
+        let mut conn = peer_connection();
+        conn.symmetric_key = Some(SymmetricEncryption::new_from_secret(&secret));
+        let mut peer_key = SymmetricEncryption::new_from_secret(&secret);
+
+        let rekey = EphemeralKeyExchange::new();
+        let our_public_key = rekey.public_key();
+        conn.rekey = Some(rekey);
+        let answer = EphemeralKeyExchange::new();
+        let ack = msg_types::RekeyAck {
+            ephemeral_key: answer.public_key()
+        };
+        let (send_secret, recv_secret) = answer.derive_rekey(&our_public_key, false).unwrap();
+        peer_key.stage_rekey(send_secret, recv_secret);
+
+        // A message sent before the peer saw our switch
+        let in_flight = peer_key.encrypt(b"before");
+        assert_eq!(conn.finish_rekey(&ack), Ok(true));
+        assert_eq!(conn.finish_rekey(&ack), Ok(false));
+
+        // Both sides still agree on the keys, and the old key is still accepted for a while
+        let key = conn.symmetric_key.as_mut().unwrap();
+        assert_eq!(peer_key.decrypt(&key.encrypt(b"after")).unwrap(), b"after");
+        assert_eq!(key.decrypt(&peer_key.encrypt(b"answer")).unwrap(), b"answer");
+        assert_eq!(key.decrypt(&in_flight).unwrap(), b"before");
+    }
+}
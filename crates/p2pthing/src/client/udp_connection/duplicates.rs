@@ -0,0 +1,95 @@
+use p2pthing_common::encryption::ReplayWindow;
+
+/// Detects duplicated and replayed unreliable messages, with a sliding window anchored at the highest message id seen.
+/// Message ids wrap around, so they are extended to 64 bit counters, taking the one closest to the highest counter.
+/// Messages which are older than the window are rejected, since it's impossible to tell whether they have been received before.
+pub struct DuplicateFilter {
+    window: ReplayWindow,
+    highest: Option<u64>,
+}
+
+impl DuplicateFilter {
+    pub fn new() -> DuplicateFilter {
+        DuplicateFilter {
+            window: ReplayWindow::new(),
+            highest: None,
+        }
+    }
+
+    /// The counter of the message id, or `None` if it would be from before the first message
+    fn extend(&self, id: u32) -> Option<u64> {
+        match self.highest {
+            Some(highest) => {
+                let distance = id.wrapping_sub(highest as u32) as i32 as i64;
+                let counter = highest as i64 + distance;
+                if counter < 0 { None } else { Some(counter as u64) }
+            }
+            None => Some(id as u64)
+        }
+    }
+
+    /// Check whether the message is new, without remembering it
+    pub fn check(&self, id: u32) -> bool {
+        self.extend(id).map_or(false, |counter| self.window.check(counter))
+    }
+
+    /// Remember a message which has been checked and authenticated
+    pub fn update(&mut self, id: u32) {
+        if let Some(counter) = self.extend(id) {
+            if self.window.update(counter) && self.highest.map_or(true, |highest| counter > highest) {
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut filter = DuplicateFilter::new();
+        assert!(filter.check(10));
+        filter.update(10);
+        assert!(!filter.check(10));
+        assert!(filter.check(9));
+        assert!(filter.check(11));
+    }
+
+    #[test]
+    fn only_remembers_updated_messages() {
+        let mut filter = DuplicateFilter::new();
+        filter.update(10);
+        // Checking doesn't remember the message, it has to be authenticated first
+        assert!(filter.check(12));
+        assert!(filter.check(12));
+    }
+
+    #[test]
+    fn extends_ids_across_the_wrap() {
+        let mut filter = DuplicateFilter::new();
+        filter.update(u32::MAX - 1);
+        filter.update(u32::MAX);
+        assert_eq!(filter.extend(0), Some(u32::MAX as u64 + 1));
+        filter.update(0);
+        filter.update(1);
+        assert_eq!(filter.highest, Some(u32::MAX as u64 + 2));
+        // The messages from before the wrap are still known
+        assert!(!filter.check(u32::MAX));
+        assert!(!filter.check(u32::MAX - 1));
+        assert!(!filter.check(0));
+        assert!(filter.check(u32::MAX - 2));
+        assert!(filter.check(2));
+    }
+
+    #[test]
+    fn rejects_ids_from_before_the_first_message() {
+        let mut filter = DuplicateFilter::new();
+        filter.update(1);
+        // Going back from 1 would need a negative counter
+        assert_eq!(filter.extend(u32::MAX), None);
+        assert!(!filter.check(u32::MAX));
+        assert!(filter.check(0));
+    }
+}
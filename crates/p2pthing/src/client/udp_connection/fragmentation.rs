@@ -0,0 +1,221 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use p2pthing_common::message_type::{FragmentHeader, UdpPacket};
+
+/// A packet can't be split into more fragments than this
+const MAX_FRAGMENTS: u16 = 2048;
+/// Incomplete packets are thrown away after this long
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum amount of memory used by the incomplete packets of a connection
+const MAX_REASSEMBLY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Split the packet into fragments which fit into datagrams of the MTU. Returns `None` if the packet is too large to be sent.
+pub fn split(packet: &UdpPacket, id: u32, mtu: usize) -> Option<Vec<UdpPacket>> {
+    let fragment = |data: Vec<u8>, index: u16, count: u16| UdpPacket {
+        data,
+        reliable: packet.reliable,
+        msg_id: packet.msg_id,
+        stream: packet.stream,
+        fragment: Some(FragmentHeader {
+            id,
+            index,
+            count
+        }),
+        connection_id: packet.connection_id,
+        upgraded: packet.upgraded.clone()
+    };
+    let overhead = bincode::serialized_size(&fragment(vec![], 0, 0)).unwrap() as usize;
+    let fragment_size = mtu - overhead;
+    let count = (packet.data.len() + fragment_size - 1) / fragment_size;
+    if count > MAX_FRAGMENTS as usize {
+        return None;
+    }
+
+    Some(packet.data.chunks(fragment_size)
+    .enumerate()
+    .map(|(index, data)| fragment(data.to_vec(), index as u16, count as u16))
+    .collect())
+}
+
+/// The fragments of a packet which have arrived so far
+struct PartialPacket {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: u16,
+    size: usize,
+    started: Instant,
+}
+
+/// Puts the fragmented packets of a connection back together
+pub struct Reassembler {
+    packets: HashMap<u32, PartialPacket>,
+    /// Memory used by all of the incomplete packets
+    size: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler {
+            packets: HashMap::new(),
+            size: 0,
+        }
+    }
+
+    /// Store a fragment, returning the whole packet once all of its fragments have arrived
+    pub fn add(&mut self, header: FragmentHeader, fragment: UdpPacket) -> Result<Option<UdpPacket>, String> {
+        if header.count == 0 || header.count > MAX_FRAGMENTS || header.index >= header.count {
+            return Err("Invalid fragment header".into());
+        }
+        self.remove_expired();
+
+        if let Some(packet) = self.packets.get(&header.id) {
+            if packet.fragments.len() != header.count as usize {
+                return Err("The fragment count doesn't match the earlier fragments".into());
+            }
+            if packet.fragments[header.index as usize].is_some() {
+                return Ok(None);
+            }
+        }
+
+        // Make room by throwing away the oldest incomplete packets
+        while self.size + fragment.data.len() > MAX_REASSEMBLY_SIZE {
+            let oldest = *self.packets.iter()
+            .min_by_key(|(_, packet)| packet.started)
+            .map(|(id, _)| id)
+            .unwrap();
+            self.remove(oldest);
+        }
+
+        self.size += fragment.data.len();
+        let packet = self.packets.entry(header.id).or_insert_with(|| PartialPacket {
+            fragments: vec![None; header.count as usize],
+            received: 0,
+            size: 0,
+            started: Instant::now(),
+        });
+        packet.size += fragment.data.len();
+        packet.received += 1;
+        packet.fragments[header.index as usize] = Some(fragment.data);
+        if packet.received < header.count {
+            return Ok(None);
+        }
+
+        let packet = self.remove(header.id);
+        Ok(Some(UdpPacket {
+            data: packet.fragments.into_iter().flatten().flatten().collect(),
+            reliable: fragment.reliable,
+            msg_id: fragment.msg_id,
+            stream: fragment.stream,
+            fragment: None,
+            connection_id: fragment.connection_id,
+            upgraded: fragment.upgraded
+        }))
+    }
+
+    fn remove(&mut self, id: u32) -> PartialPacket {
+        let packet = self.packets.remove(&id).unwrap();
+        self.size -= packet.size;
+        packet
+    }
+
+    fn remove_expired(&mut self) {
+        let expired: Vec<u32> = self.packets.iter()
+        .filter(|(_, packet)| packet.started.elapsed() >= REASSEMBLY_TIMEOUT)
+        .map(|(id, _)| *id)
+        .collect();
+        for id in expired {
+            self.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::udp_connection::test_utils::{fragment_header, packet};
+
+    use super::*;
+
+    const MTU: usize = 1200;
+
+    #[test]
+    fn reassembles_split_packets() {
+        let original = packet(MTU * 4);
+        let fragments = split(&original, 1, MTU).unwrap();
+        assert_eq!(fragments.len(), 5);
+        for fragment in fragments.iter() {
+            assert!(bincode::serialize(fragment).unwrap().len() <= MTU);
+        }
+
+        let mut reassembler = Reassembler::new();
+        let mut whole = None;
+        // The fragments can arrive in any order
+        for fragment in fragments.into_iter().rev() {
+            assert!(whole.is_none());
+            whole = reassembler.add(fragment.fragment.unwrap(), fragment).unwrap();
+        }
+        let whole = whole.unwrap();
+        assert_eq!(whole.data, original.data);
+        assert_eq!(whole.msg_id, original.msg_id);
+        assert!(whole.fragment.is_none());
+        assert_eq!(reassembler.size, 0);
+    }
+
+    #[test]
+    fn ignores_duplicate_fragments() {
+        let fragments = split(&packet(MTU * 2), 1, MTU).unwrap();
+        let mut reassembler = Reassembler::new();
+        let first = fragments[0].clone();
+        assert!(reassembler.add(first.fragment.unwrap(), first.clone()).unwrap().is_none());
+        assert!(reassembler.add(first.fragment.unwrap(), first).unwrap().is_none());
+        assert_eq!(reassembler.packets[&1].received, 1);
+    }
+
+    #[test]
+    fn refuses_packets_with_too_many_fragments() {
+        let fragment_size = split(&packet(MTU * 2), 1, MTU).unwrap()[0].data.len();
+        assert!(split(&packet(fragment_size * MAX_FRAGMENTS as usize), 1, MTU).is_some());
+        assert!(split(&packet(fragment_size * MAX_FRAGMENTS as usize * 2), 1, MTU).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.add(fragment_header(1, 0, 0), packet(10)).is_err());
+        assert!(reassembler.add(fragment_header(1, 2, 2), packet(10)).is_err());
+        assert!(reassembler.add(fragment_header(1, 0, MAX_FRAGMENTS + 1), packet(10)).is_err());
+
+        assert!(reassembler.add(fragment_header(1, 0, 2), packet(10)).unwrap().is_none());
+        // The count has to match the earlier fragments
+        assert!(reassembler.add(fragment_header(1, 1, 3), packet(10)).is_err());
+        assert_eq!(reassembler.size, 10);
+    }
+
+    #[test]
+    fn throws_away_expired_packets() {
+        let mut reassembler = Reassembler::new();
+        reassembler.add(fragment_header(1, 0, 2), packet(10)).unwrap();
+        reassembler.packets.get_mut(&1).unwrap().started = Instant::now() - REASSEMBLY_TIMEOUT;
+
+        reassembler.add(fragment_header(2, 0, 2), packet(10)).unwrap();
+        assert!(!reassembler.packets.contains_key(&1));
+        assert_eq!(reassembler.size, 10);
+        // The late fragment starts a new packet, instead of completing the expired one
+        assert!(reassembler.add(fragment_header(1, 1, 2), packet(10)).unwrap().is_none());
+    }
+
+    #[test]
+    fn limits_the_memory_used() {
+        let mut reassembler = Reassembler::new();
+        let size = MAX_REASSEMBLY_SIZE / 4;
+        for id in 0..4 {
+            reassembler.add(fragment_header(id, 0, 2), packet(size)).unwrap();
+        }
+        assert_eq!(reassembler.size, MAX_REASSEMBLY_SIZE);
+
+        // The oldest packet makes room for the new one
+        reassembler.packets.get_mut(&2).unwrap().started -= Duration::from_secs(1);
+        reassembler.add(fragment_header(4, 0, 2), packet(size)).unwrap();
+        assert!(!reassembler.packets.contains_key(&2));
+        assert_eq!(reassembler.packets.len(), 4);
+        assert_eq!(reassembler.size, MAX_REASSEMBLY_SIZE);
+    }
+}
@@ -0,0 +1,91 @@
+use std::{net::SocketAddr, time::{Duration, Instant}};
+
+/// A challenge is sent again, if it isn't answered in time
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(1);
+/// The new address is given up on, once this many challenges have been lost
+const MAX_CHALLENGES: u32 = 3;
+
+/// Checks that the peer can be reached at its new address, before the connection is moved there (RFC 9000, section 8.2).
+/// An authenticated packet from a new address isn't enough, since anyone who sees it could send it again from anywhere.
+pub struct PathValidation {
+    pub address: SocketAddr,
+    /// The peer has to send this back, which it can only do if it receives the challenge at the new address
+    pub token: u64,
+    sent: Option<Instant>,
+    attempts: u32,
+}
+
+impl PathValidation {
+    pub fn new(address: SocketAddr) -> PathValidation {
+        PathValidation {
+            address,
+            token: rand::random(),
+            sent: None,
+            attempts: 0,
+        }
+    }
+
+    /// Whether the challenge has to be sent, because it hasn't been yet, or it has been lost
+    pub fn poll(&mut self) -> bool {
+        match self.sent {
+            Some(sent) if sent.elapsed() < CHALLENGE_TIMEOUT => false,
+            _ => {
+                self.sent = Some(Instant::now());
+                self.attempts += 1;
+                true
+            }
+        }
+    }
+
+    /// Whether the new address should be given up on
+    pub fn failed(&self) -> bool {
+        self.attempts >= MAX_CHALLENGES && self.next_timeout() == Duration::from_secs(0)
+    }
+
+    /// Time until `poll` has to be called again
+    pub fn next_timeout(&self) -> Duration {
+        match self.sent {
+            Some(sent) => (sent + CHALLENGE_TIMEOUT).saturating_duration_since(Instant::now()),
+            None => Duration::from_secs(0)
+        }
+    }
+}
+
+#[cfg(test)]
+impl PathValidation {
+    /// Let the challenge in flight time out
+    pub fn expire(&mut self) {
+        if let Some(sent) = &mut self.sent {
+            *sent -= CHALLENGE_TIMEOUT;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_the_challenge_once_until_it_times_out() {
+        let mut validation = PathValidation::new("127.0.0.1:9".parse().unwrap());
+        assert_eq!(validation.next_timeout(), Duration::from_secs(0));
+        assert!(validation.poll());
+        assert!(!validation.poll());
+        assert!(validation.next_timeout() > Duration::from_secs(0));
+
+        validation.expire();
+        assert!(validation.poll());
+        assert_eq!(validation.attempts, 2);
+    }
+
+    #[test]
+    fn gives_up_after_the_last_challenge_is_lost() {
+        let mut validation = PathValidation::new("127.0.0.1:9".parse().unwrap());
+        for _ in 0..MAX_CHALLENGES {
+            assert!(!validation.failed());
+            assert!(validation.poll());
+            validation.expire();
+        }
+        assert!(validation.failed());
+    }
+}
@@ -0,0 +1,214 @@
+use std::{cmp, time::{Duration, Instant}};
+
+/// Every path is expected to carry datagrams of this size: the minimum MTU of IPv6, without the IPv6 and UDP headers
+pub const BASE_MTU: usize = 1232;
+/// The largest datagram which is probed for: the MTU of Ethernet, without the IPv4 and UDP headers
+const MAX_MTU: usize = 1472;
+/// The search stops once the largest working and the smallest failing size are this close
+const SEARCH_PRECISION: usize = 16;
+/// A probe is considered lost, if it isn't answered in time
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// A size is considered too large, once this many probes of it have been lost
+const MAX_PROBES: u32 = 3;
+/// The path can change, so it's searched again this long after the last search has finished
+const REVALIDATION_DELAY: Duration = Duration::from_secs(10 * 60);
+
+struct Probe {
+    /// Every attempt of the same size has the same id, so a late answer still counts
+    id: u32,
+    size: usize,
+    sent: Instant,
+    attempts: u32,
+}
+
+/// Path MTU discovery (RFC 8899): probes padded to a size are sent, and the largest size which gets answered is found with a binary search.
+/// The probes are only useful if the datagrams aren't fragmented on the way, which is the case on most paths.
+pub struct PmtuDiscovery {
+    /// The largest datagram size which is known to get through
+    mtu: usize,
+    /// Datagrams of this size or larger are known not to get through
+    too_large: usize,
+    probe: Option<Probe>,
+    next_probe_id: u32,
+    searching: bool,
+    /// When the next search starts, unless one is in progress
+    next_search: Instant,
+}
+
+impl PmtuDiscovery {
+    pub fn new() -> PmtuDiscovery {
+        PmtuDiscovery {
+            mtu: BASE_MTU,
+            too_large: MAX_MTU + 1,
+            probe: None,
+            next_probe_id: 0,
+            searching: false,
+            next_search: Instant::now(),
+        }
+    }
+
+    /// The largest datagram which can be sent without it being fragmented or dropped
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// The next probe to send, if one is due. Returns its id and the size it has to be padded to.
+    pub fn poll(&mut self) -> Option<(u32, usize)> {
+        if let Some(probe) = &mut self.probe {
+            if probe.sent.elapsed() < PROBE_TIMEOUT {
+                return None;
+            }
+            if probe.attempts < MAX_PROBES {
+                probe.attempts += 1;
+                probe.sent = Instant::now();
+                return Some((probe.id, probe.size));
+            }
+            let size = probe.size;
+            self.probe = None;
+            if size <= self.mtu {
+                // The path has changed, and it doesn't carry the size it used to
+                self.mtu = BASE_MTU;
+            }
+            self.too_large = size;
+        }
+
+        if !self.searching {
+            if Instant::now() < self.next_search {
+                return None;
+            }
+            self.searching = true;
+            self.too_large = MAX_MTU + 1;
+            // Make sure the current size still works, before looking for a larger one
+            if self.mtu > BASE_MTU {
+                return Some(self.start_probe(self.mtu));
+            }
+        }
+        if self.too_large - self.mtu <= SEARCH_PRECISION {
+            self.searching = false;
+            self.next_search = Instant::now() + REVALIDATION_DELAY;
+            return None;
+        }
+        Some(self.start_probe((self.mtu + self.too_large) / 2))
+    }
+
+    fn start_probe(&mut self, size: usize) -> (u32, usize) {
+        let id = self.next_probe_id;
+        self.next_probe_id = self.next_probe_id.wrapping_add(1);
+        self.probe = Some(Probe {
+            id,
+            size,
+            sent: Instant::now(),
+            attempts: 1,
+        });
+        (id, size)
+    }
+
+    /// The peer answered a probe. Returns true if the MTU has grown.
+    pub fn on_ack(&mut self, id: u32) -> bool {
+        match &self.probe {
+            Some(probe) if probe.id == id => {
+                let size = probe.size;
+                self.probe = None;
+                let grown = size > self.mtu;
+                self.mtu = cmp::max(self.mtu, size);
+                grown
+            }
+            _ => false
+        }
+    }
+
+    /// Time until `poll` has to be called again
+    pub fn next_timeout(&self) -> Duration {
+        match &self.probe {
+            Some(probe) => (probe.sent + PROBE_TIMEOUT).saturating_duration_since(Instant::now()),
+            None if self.searching => Duration::from_secs(0),
+            None => self.next_search.saturating_duration_since(Instant::now())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Let the probe in flight time out
+    fn expire(pmtu: &mut PmtuDiscovery) {
+        if let Some(probe) = &mut pmtu.probe {
+            probe.sent -= PROBE_TIMEOUT;
+        }
+    }
+
+    /// Lose every attempt of the probe, returning the next probe
+    fn lose(pmtu: &mut PmtuDiscovery) -> Option<(u32, usize)> {
+        for _ in 1..MAX_PROBES {
+            expire(pmtu);
+            assert!(pmtu.poll().is_some());
+        }
+        expire(pmtu);
+        pmtu.poll()
+    }
+
+    #[test]
+    fn grows_when_a_probe_is_answered() {
+        let mut pmtu = PmtuDiscovery::new();
+        let (id, size) = pmtu.poll().unwrap();
+        assert_eq!(size, (pmtu.mtu + pmtu.too_large) / 2);
+        assert!(pmtu.poll().is_none());
+        assert!(pmtu.on_ack(id));
+        assert_eq!(pmtu.mtu(), size);
+        // Answers are only counted once
+        assert!(!pmtu.on_ack(id));
+    }
+
+    #[test]
+    fn a_late_answer_to_a_repeated_probe_counts() {
+        let mut pmtu = PmtuDiscovery::new();
+        let (id, size) = pmtu.poll().unwrap();
+        expire(&mut pmtu);
+        assert_eq!(pmtu.poll(), Some((id, size)));
+        assert!(pmtu.on_ack(id));
+    }
+
+    #[test]
+    fn looks_for_a_smaller_size_after_a_loss() {
+        let mut pmtu = PmtuDiscovery::new();
+        let (_, size) = pmtu.poll().unwrap();
+        let (_, smaller) = lose(&mut pmtu).unwrap();
+        assert_eq!(smaller, (BASE_MTU + size) / 2);
+        assert_eq!(pmtu.mtu(), BASE_MTU);
+    }
+
+    #[test]
+    fn finds_the_path_mtu() {
+        let path_mtu = 1400;
+        let mut pmtu = PmtuDiscovery::new();
+        let mut next = pmtu.poll();
+        while let Some((id, size)) = next {
+            next = match size <= path_mtu {
+                true => {
+                    pmtu.on_ack(id);
+                    pmtu.poll()
+                }
+                false => lose(&mut pmtu)
+            };
+        }
+        assert!(pmtu.mtu() <= path_mtu && pmtu.mtu() > path_mtu - SEARCH_PRECISION);
+        assert!(!pmtu.searching);
+        assert!(pmtu.next_timeout() > REVALIDATION_DELAY - Duration::from_secs(1));
+    }
+
+    #[test]
+    fn falls_back_to_the_base_mtu_when_the_path_changes() {
+        let mut pmtu = PmtuDiscovery::new();
+        let (id, size) = pmtu.poll().unwrap();
+        pmtu.on_ack(id);
+        pmtu.searching = false;
+        pmtu.next_search = Instant::now();
+
+        // The revalidation checks the current size first
+        let (_, revalidated) = pmtu.poll().unwrap();
+        assert_eq!(revalidated, size);
+        lose(&mut pmtu);
+        assert_eq!(pmtu.mtu(), BASE_MTU);
+    }
+}
@@ -0,0 +1,271 @@
+use std::{cmp, time::{Duration, Instant}};
+
+use p2pthing_common::message_type::msg_types::Acknowledgement;
+
+/// Maximum amount of reliable messages in flight. The selective acknowledgement bitmap covers all of them.
+pub const RELIABLE_WINDOW: u32 = 64;
+/// Retransmission timeout used before the first round trip has been measured
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+/// How long an in-order message can wait for its acknowledgement, so several can be acknowledged at once
+const ACK_DELAY: Duration = Duration::from_millis(25);
+/// A message is retransmitted right away, once this many later messages have been acknowledged
+pub const FAST_RETRANSMIT_THRESHOLD: u32 = 3;
+
+/// Round trip time estimation and retransmission timeout calculation, as in TCP (RFC 6298)
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: INITIAL_RTO,
+        }
+    }
+
+    /// Update the estimation with a measured round trip. Retransmitted messages shouldn't be measured,
+    /// since it's unknown which transmission the acknowledgement belongs to.
+    pub fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let difference = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + difference) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = cmp::min(cmp::max(srtt + self.rttvar * 4, MIN_RTO), MAX_RTO);
+    }
+
+    /// Measure the round trip of an acknowledged message, returning it if it has been measured.
+    /// Karn's algorithm: retransmitted messages aren't measured.
+    pub fn measure(&mut self, sent: Instant, retransmissions: u32) -> Option<Duration> {
+        if retransmissions > 0 {
+            return None;
+        }
+        let rtt = sent.elapsed();
+        self.sample(rtt);
+        Some(rtt)
+    }
+
+    /// The retransmission timeout of a message, doubled for every time it has already been retransmitted
+    pub fn rto(&self, retransmissions: u32) -> Duration {
+        cmp::min(self.rto * 2u32.saturating_pow(retransmissions), MAX_RTO)
+    }
+}
+
+pub enum ReceiveStatus {
+    /// The message hasn't been received before
+    New,
+    /// The message has already been received, its acknowledgement was probably lost
+    Duplicate,
+    /// The message is too far ahead, the sender never sends it before the earlier ones are acknowledged
+    OutOfWindow,
+}
+
+/// Tracks which reliable messages have been received, and when they should be acknowledged.
+/// Message ids wrap around, so they are always compared relative to the next expected one.
+pub struct ReliableReceiver {
+    /// Every message before this one has been received
+    next_id: u32,
+    /// Bit `i` is set if message `next_id + 1 + i` has been received
+    received: u64,
+    /// When the next acknowledgement has to be sent
+    ack_deadline: Option<Instant>,
+}
+
+impl ReliableReceiver {
+    pub fn new() -> ReliableReceiver {
+        ReliableReceiver {
+            next_id: 0,
+            received: 0,
+            ack_deadline: None,
+        }
+    }
+
+    /// Check whether the message is new, without remembering it
+    pub fn check(&self, id: u32) -> ReceiveStatus {
+        match id.wrapping_sub(self.next_id) {
+            0 => ReceiveStatus::New,
+            distance if distance > u32::MAX / 2 => ReceiveStatus::Duplicate,
+            distance if distance > RELIABLE_WINDOW => ReceiveStatus::OutOfWindow,
+            distance if self.received & (1 << (distance - 1)) != 0 => ReceiveStatus::Duplicate,
+            _ => ReceiveStatus::New,
+        }
+    }
+
+    /// Remember a message which has been checked and authenticated, and schedule its acknowledgement
+    pub fn update(&mut self, id: u32) {
+        match id.wrapping_sub(self.next_id) {
+            0 => {
+                // Move past every message which has already arrived out of order
+                self.next_id = self.next_id.wrapping_add(1);
+                loop {
+                    let received = self.received & 1 != 0;
+                    self.received >>= 1;
+                    if !received {
+                        break;
+                    }
+                    self.next_id = self.next_id.wrapping_add(1);
+                }
+                self.schedule_ack(Instant::now() + ACK_DELAY);
+            }
+            distance if distance <= RELIABLE_WINDOW => {
+                self.received |= 1 << (distance - 1);
+                // A gap tells the sender about a loss, so it's acknowledged right away
+                self.schedule_ack(Instant::now());
+            }
+            _ => {}
+        }
+    }
+
+    /// Acknowledge right away, e.g. because a duplicate shows that the last acknowledgement was lost
+    pub fn request_ack(&mut self) {
+        self.schedule_ack(Instant::now());
+    }
+
+    fn schedule_ack(&mut self, deadline: Instant) {
+        self.ack_deadline = Some(match self.ack_deadline {
+            Some(current) => cmp::min(current, deadline),
+            None => deadline
+        });
+    }
+
+    /// Time until the next acknowledgement has to be sent
+    pub fn next_ack(&self) -> Option<Duration> {
+        self.ack_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// The acknowledgement to send, if it's due
+    pub fn take_ack(&mut self) -> Option<Acknowledgement> {
+        match self.ack_deadline {
+            Some(deadline) if deadline <= Instant::now() => {
+                self.ack_deadline = None;
+                Some(Acknowledgement {
+                    next_id: self.next_id,
+                    received: self.received
+                })
+            }
+            _ => None
+        }
+    }
+}
+
+/// Whether the acknowledgement covers the message
+pub fn is_acknowledged(ack: &Acknowledgement, id: u32) -> bool {
+    match id.wrapping_sub(ack.next_id) {
+        0 => false,
+        distance if distance > u32::MAX / 2 => true,
+        distance if distance > RELIABLE_WINDOW => false,
+        distance => ack.received & (1 << (distance - 1)) != 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::udp_connection::test_utils::ack;
+
+    use super::*;
+
+    #[test]
+    fn acknowledges_selectively() {
+        let ack = ack(10, 0b101);
+        assert!(is_acknowledged(&ack, 9));
+        assert!(!is_acknowledged(&ack, 10));
+        assert!(is_acknowledged(&ack, 11));
+        assert!(!is_acknowledged(&ack, 12));
+        assert!(is_acknowledged(&ack, 13));
+        assert!(!is_acknowledged(&ack, 10 + RELIABLE_WINDOW + 1));
+    }
+
+    #[test]
+    fn acknowledges_across_the_wrap() {
+        let ack = ack(1, 0b1);
+        assert!(is_acknowledged(&ack, u32::MAX));
+        assert!(is_acknowledged(&ack, 0));
+        assert!(!is_acknowledged(&ack, 1));
+        assert!(is_acknowledged(&ack, 2));
+    }
+
+    #[test]
+    fn receiver_reports_gaps() {
+        let mut receiver = ReliableReceiver::new();
+        receiver.update(0);
+        receiver.update(2);
+        receiver.update(3);
+        assert!(matches!(receiver.check(0), ReceiveStatus::Duplicate));
+        assert!(matches!(receiver.check(1), ReceiveStatus::New));
+        assert!(matches!(receiver.check(2), ReceiveStatus::Duplicate));
+        assert!(matches!(receiver.check(1 + RELIABLE_WINDOW + 1), ReceiveStatus::OutOfWindow));
+
+        // A gap is acknowledged right away
+        let sent = receiver.take_ack().unwrap();
+        assert_eq!(sent.next_id, 1);
+        assert_eq!(sent.received, 0b11);
+
+        // Filling the gap moves past the messages which arrived out of order
+        receiver.update(1);
+        assert_eq!(receiver.next_id, 4);
+        assert_eq!(receiver.received, 0);
+    }
+
+    #[test]
+    fn receiver_wraps_around() {
+        let mut receiver = ReliableReceiver::new();
+        receiver.next_id = u32::MAX;
+        receiver.update(0);
+        receiver.update(u32::MAX);
+        assert_eq!(receiver.next_id, 1);
+        assert!(matches!(receiver.check(u32::MAX), ReceiveStatus::Duplicate));
+        assert!(matches!(receiver.check(1), ReceiveStatus::New));
+    }
+
+    #[test]
+    fn rto_follows_the_round_trip() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto(0), INITIAL_RTO);
+
+        rtt.sample(Duration::from_millis(100));
+        assert_eq!(rtt.srtt, Some(Duration::from_millis(100)));
+        // srtt + 4 * rttvar, where rttvar starts at half of the first sample
+        assert_eq!(rtt.rto(0), Duration::from_millis(300));
+
+        rtt.sample(Duration::from_millis(100));
+        assert_eq!(rtt.rto(0), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn rto_is_clamped_and_backs_off() {
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::from_millis(1));
+        assert_eq!(rtt.rto(0), MIN_RTO);
+        assert_eq!(rtt.rto(1), MIN_RTO * 2);
+        assert_eq!(rtt.rto(2), MIN_RTO * 4);
+        assert_eq!(rtt.rto(40), MAX_RTO);
+
+        rtt.sample(Duration::from_secs(100));
+        assert_eq!(rtt.rto(0), MAX_RTO);
+    }
+
+    #[test]
+    fn retransmitted_messages_are_not_measured() {
+        let mut rtt = RttEstimator::new();
+        let sent = Instant::now();
+        assert_eq!(rtt.measure(sent, 1), None);
+        assert_eq!(rtt.srtt, None);
+        assert_eq!(rtt.rto(0), INITIAL_RTO);
+
+        assert!(rtt.measure(sent, 0).is_some());
+        assert!(rtt.srtt.is_some());
+    }
+}
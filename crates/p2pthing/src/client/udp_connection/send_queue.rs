@@ -0,0 +1,230 @@
+use std::{collections::VecDeque, io, net::SocketAddr, time::{Duration, Instant}};
+
+use mio::net::UdpSocket;
+use p2pthing_common::message_type::MsgType;
+
+/// The datagrams of a class are thrown away, oldest first, once this much of them is waiting
+const MAX_QUEUE_SIZE: usize = 1024 * 1024;
+/// Voice which couldn't be sent in time is thrown away, the receiver has already played something else instead
+const REALTIME_DEADLINE: Duration = Duration::from_millis(100);
+/// Bytes the bucket can hold, so short bursts are sent right away
+const BUCKET_SIZE: f64 = 64.0 * 1024.0;
+/// Bytes per second, unless it's set with the `SEND_RATE` environment variable
+pub const DEFAULT_SEND_RATE: usize = 100 * 1000 * 1000 / 8;
+
+/// Traffic classes of the outgoing datagrams. A class is only sent, once the higher ones are empty.
+#[derive(Clone, Copy)]
+pub enum Priority {
+    /// Voice, which is useless if it's late
+    Realtime=0,
+    /// Control and chat messages, which are small and waited for
+    Interactive=1,
+    /// File data, which gets the bandwidth which is left
+    Bulk=2
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+
+    pub fn of(msg_type: &MsgType) -> Priority {
+        match msg_type {
+            MsgType::OpusPacket => Priority::Realtime,
+            MsgType::FileChunks => Priority::Bulk,
+            _ => Priority::Interactive
+        }
+    }
+}
+
+struct Datagram {
+    data: Vec<u8>,
+    address: SocketAddr,
+    queued: Instant,
+}
+
+/// Datagrams waiting to be sent on a connection. They are sent in the order of their priority, and paced by a token bucket,
+/// so a burst of file chunks is spread out, instead of filling the queues of the network in front of the voice packets.
+pub struct SendQueue {
+    queues: [VecDeque<Datagram>; Priority::COUNT],
+    sizes: [usize; Priority::COUNT],
+    /// Bytes per second
+    pub rate: usize,
+    tokens: f64,
+    last_refill: Instant,
+    /// The socket didn't accept the last datagram, so the queue waits for it to become writable
+    blocked: bool,
+}
+
+impl SendQueue {
+    pub fn new() -> SendQueue {
+        SendQueue {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            sizes: [0; Priority::COUNT],
+            rate: DEFAULT_SEND_RATE,
+            tokens: BUCKET_SIZE,
+            last_refill: Instant::now(),
+            blocked: false,
+        }
+    }
+
+    /// Queue a datagram, throwing away the oldest ones of its class if the queue is full
+    pub fn push(&mut self, priority: Priority, address: SocketAddr, data: Vec<u8>) {
+        let class = priority as usize;
+        self.sizes[class] += data.len();
+        self.queues[class].push_back(Datagram {
+            data,
+            address,
+            queued: Instant::now(),
+        });
+        while self.sizes[class] > MAX_QUEUE_SIZE {
+            self.pop(class);
+        }
+    }
+
+    fn pop(&mut self, class: usize) -> Option<Datagram> {
+        let datagram = self.queues[class].pop_front()?;
+        self.sizes[class] -= datagram.data.len();
+        Some(datagram)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(BUCKET_SIZE);
+        self.last_refill = now;
+    }
+
+    /// Send the queued datagrams, while the socket accepts them and the bucket has tokens.
+    /// Voice isn't held back by the bucket, but it still uses up the tokens.
+    /// Returns the amount of bytes sent, even if an error stopped the sending.
+    pub fn flush(&mut self, sock: &UdpSocket) -> (usize, io::Result<()>) {
+        self.refill();
+        self.blocked = false;
+        let mut sent = 0;
+        while let Some(class) = self.queues.iter().position(|queue| !queue.is_empty()) {
+            let datagram = self.queues[class].front().unwrap();
+            if class == Priority::Realtime as usize && datagram.queued.elapsed() > REALTIME_DEADLINE {
+                self.pop(class);
+                continue;
+            }
+            if class != Priority::Realtime as usize && self.tokens <= 0.0 {
+                break;
+            }
+            match sock.send_to(&datagram.data[..], datagram.address) {
+                Ok(size) => {
+                    self.tokens -= size as f64;
+                    sent += size;
+                    self.pop(class);
+                }
+                // The socket's buffer is full, the rest is sent once it's writable again
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.blocked = true;
+                    break;
+                }
+                Err(e) => {
+                    self.pop(class);
+                    return (sent, Err(e));
+                }
+            }
+        }
+        (sent, Ok(()))
+    }
+
+    /// Time until the bucket has tokens for the waiting datagrams
+    pub fn next_timeout(&self) -> Option<Duration> {
+        if self.blocked || self.queues.iter().all(|queue| queue.is_empty()) {
+            return None;
+        }
+        let missing = (-self.tokens).max(0.0) + 1.0;
+        let refilled = self.last_refill + Duration::from_secs_f64(missing / self.rate as f64);
+        Some(refilled.saturating_duration_since(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sockets() -> (UdpSocket, std::net::UdpSocket) {
+        let sock = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        (sock, receiver)
+    }
+
+    fn receive(receiver: &std::net::UdpSocket) -> Vec<u8> {
+        let mut buf = [0; 2048];
+        let size = receiver.recv(&mut buf).unwrap();
+        buf[..size].to_vec()
+    }
+
+    #[test]
+    fn sends_the_higher_priorities_first() {
+        let (sock, receiver) = sockets();
+        let address = receiver.local_addr().unwrap();
+        let mut queue = SendQueue::new();
+        queue.push(Priority::Bulk, address, vec![2]);
+        queue.push(Priority::Interactive, address, vec![1]);
+        queue.push(Priority::Bulk, address, vec![3]);
+        queue.push(Priority::Realtime, address, vec![0]);
+        assert_eq!(queue.flush(&sock).0, 4);
+        for expected in 0..4 {
+            assert_eq!(receive(&receiver), vec![expected]);
+        }
+        assert_eq!(queue.next_timeout(), None);
+    }
+
+    #[test]
+    fn paces_with_the_token_bucket() {
+        let (sock, receiver) = sockets();
+        let address = receiver.local_addr().unwrap();
+        let mut queue = SendQueue::new();
+        queue.rate = 1000;
+        // The bucket is in debt after an earlier burst
+        queue.tokens = -100.0;
+        queue.push(Priority::Bulk, address, vec![0; 500]);
+        queue.push(Priority::Interactive, address, vec![0; 500]);
+        assert_eq!(queue.flush(&sock).0, 0);
+        let timeout = queue.next_timeout().unwrap();
+        assert!(timeout > Duration::from_millis(90) && timeout <= Duration::from_millis(101));
+
+        // Voice isn't held back, but uses up the tokens
+        queue.push(Priority::Realtime, address, vec![0; 100]);
+        assert_eq!(queue.flush(&sock).0, 100);
+        assert!(queue.next_timeout().unwrap() > Duration::from_millis(190));
+
+        // Once the debt is paid, the bucket has enough for one datagram at a time
+        queue.last_refill -= Duration::from_millis(600);
+        assert_eq!(queue.flush(&sock).0, 500);
+        assert_eq!(queue.sizes, [0, 0, 500]);
+        queue.last_refill -= Duration::from_millis(600);
+        assert_eq!(queue.flush(&sock).0, 500);
+        assert_eq!(queue.next_timeout(), None);
+    }
+
+    #[test]
+    fn throws_away_late_voice() {
+        let (sock, receiver) = sockets();
+        let address = receiver.local_addr().unwrap();
+        let mut queue = SendQueue::new();
+        queue.push(Priority::Realtime, address, vec![0]);
+        queue.queues[Priority::Realtime as usize][0].queued -= REALTIME_DEADLINE * 2;
+        queue.push(Priority::Realtime, address, vec![1]);
+        assert_eq!(queue.flush(&sock).0, 1);
+        assert_eq!(receive(&receiver), vec![1]);
+    }
+
+    #[test]
+    fn throws_away_the_oldest_datagrams_of_a_full_class() {
+        let address = "127.0.0.1:9".parse().unwrap();
+        let mut queue = SendQueue::new();
+        let count = MAX_QUEUE_SIZE / 1000 + 10;
+        for i in 0..count {
+            queue.push(Priority::Bulk, address, vec![(i % 256) as u8; 1000]);
+        }
+        queue.push(Priority::Interactive, address, vec![0; 1000]);
+        assert!(queue.sizes[Priority::Bulk as usize] <= MAX_QUEUE_SIZE);
+        assert_eq!(queue.queues[Priority::Bulk as usize].back().unwrap().data[0], ((count - 1) % 256) as u8);
+        assert_eq!(queue.queues[Priority::Bulk as usize].len(), MAX_QUEUE_SIZE / 1000);
+        assert_eq!(queue.sizes[Priority::Interactive as usize], 1000);
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use p2pthing_common::message_type::{Stream, StreamHeader};
+
+/// A reliable message which has been decrypted, but might have to wait for the earlier messages of its stream
+pub struct ReceivedMessage {
+    pub data: Vec<u8>,
+    /// Whether the message came through the encrypted tunnel
+    pub encrypted: bool,
+}
+
+/// Delivers the messages of a single stream in the order they were sent
+struct StreamReceiver {
+    /// The next message to deliver
+    next_seq: u32,
+    /// Messages which arrived before an earlier one. The reliable window limits how many there can be.
+    buffer: HashMap<u32, ReceivedMessage>,
+}
+
+impl StreamReceiver {
+    fn new() -> StreamReceiver {
+        StreamReceiver {
+            next_seq: 0,
+            buffer: HashMap::new(),
+        }
+    }
+}
+
+/// Sequence numbers and reordering buffers of the streams of a connection.
+/// A missing message only holds back the later messages of its own stream.
+pub struct Streams {
+    next_seq: [u32; Stream::COUNT],
+    receivers: [StreamReceiver; Stream::COUNT],
+}
+
+impl Streams {
+    pub fn new() -> Streams {
+        Streams {
+            next_seq: [0; Stream::COUNT],
+            receivers: [StreamReceiver::new(), StreamReceiver::new(), StreamReceiver::new()],
+        }
+    }
+
+    /// Give the next position in the stream to a message which is being sent
+    pub fn next_header(&mut self, stream: Stream) -> StreamHeader {
+        let seq = &mut self.next_seq[stream as usize];
+        let header = StreamHeader {
+            stream,
+            seq: *seq
+        };
+        *seq = seq.wrapping_add(1);
+        header
+    }
+
+    /// Take a received message, returning every message of its stream which can now be delivered, in order.
+    /// Messages are expected to be deduplicated already.
+    pub fn receive(&mut self, header: StreamHeader, msg: ReceivedMessage) -> Vec<ReceivedMessage> {
+        let receiver = &mut self.receivers[header.stream as usize];
+        match header.seq.wrapping_sub(receiver.next_seq) {
+            0 => {}
+            // Already delivered
+            distance if distance > u32::MAX / 2 => return vec![],
+            _ => {
+                receiver.buffer.insert(header.seq, msg);
+                return vec![];
+            }
+        }
+
+        let mut deliverable = vec![msg];
+        receiver.next_seq = receiver.next_seq.wrapping_add(1);
+        while let Some(msg) = receiver.buffer.remove(&receiver.next_seq) {
+            deliverable.push(msg);
+            receiver.next_seq = receiver.next_seq.wrapping_add(1);
+        }
+        deliverable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p2pthing_common::message_type::MsgType;
+
+    use super::*;
+
+    fn header(stream: Stream, seq: u32) -> StreamHeader {
+        StreamHeader {
+            stream,
+            seq
+        }
+    }
+
+    fn message(tag: u8) -> ReceivedMessage {
+        ReceivedMessage {
+            data: vec![tag],
+            encrypted: true
+        }
+    }
+
+    fn tags(messages: Vec<ReceivedMessage>) -> Vec<u8> {
+        messages.into_iter().map(|msg| msg.data[0]).collect()
+    }
+
+    #[test]
+    fn numbers_each_stream_separately() {
+        let mut streams = Streams::new();
+        assert_eq!(streams.next_header(Stream::Chat).seq, 0);
+        assert_eq!(streams.next_header(Stream::Chat).seq, 1);
+        assert_eq!(streams.next_header(Stream::Control).seq, 0);
+    }
+
+    #[test]
+    fn delivers_in_order() {
+        let mut streams = Streams::new();
+        assert_eq!(tags(streams.receive(header(Stream::Chat, 0), message(0))), vec![0]);
+        assert_eq!(tags(streams.receive(header(Stream::Chat, 1), message(1))), vec![1]);
+        // Already delivered
+        assert!(streams.receive(header(Stream::Chat, 1), message(1)).is_empty());
+    }
+
+    #[test]
+    fn buffers_messages_which_arrive_early() {
+        let mut streams = Streams::new();
+        assert!(streams.receive(header(Stream::Chat, 2), message(2)).is_empty());
+        assert!(streams.receive(header(Stream::Chat, 1), message(1)).is_empty());
+        // Other streams aren't held back
+        assert_eq!(tags(streams.receive(header(Stream::Control, 0), message(10))), vec![10]);
+        assert_eq!(tags(streams.receive(header(Stream::Chat, 0), message(0))), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn moves_past_a_skipped_message() {
+        let mut streams = Streams::new();
+        assert!(streams.receive(header(Stream::Chat, 1), message(1)).is_empty());
+        // The notice of the given up message takes its place in the stream
+        let skipped = ReceivedMessage {
+            data: vec![MsgType::Skipped as u8],
+            encrypted: true
+        };
+        let delivered = streams.receive(header(Stream::Chat, 0), skipped);
+        assert_eq!(tags(delivered), vec![MsgType::Skipped as u8, 1]);
+        assert_eq!(tags(streams.receive(header(Stream::Chat, 2), message(2))), vec![2]);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut streams = Streams::new();
+        streams.receivers[Stream::Chat as usize].next_seq = u32::MAX;
+        assert!(streams.receive(header(Stream::Chat, 0), message(0)).is_empty());
+        assert_eq!(tags(streams.receive(header(Stream::Chat, u32::MAX), message(1))), vec![1, 0]);
+    }
+}
@@ -0,0 +1,52 @@
+//! Builders shared by the unit tests of the connection's parts
+
+use std::rc::Rc;
+
+use mio::net::UdpSocket;
+use p2pthing_common::{encryption::{AsymmetricEncryption, SymmetricEncryption}, message_type::{FragmentHeader, MsgEncryption, UdpPacket, msg_types::Acknowledgement}};
+
+use super::{UdpConnection, UdpConnectionState};
+
+pub fn ack(next_id: u32, received: u64) -> Acknowledgement {
+    Acknowledgement {
+        next_id,
+        received
+    }
+}
+
+/// An unencrypted reliable packet carrying `size` bytes of data
+pub fn packet(size: usize) -> UdpPacket {
+    UdpPacket {
+        data: (0..size).map(|i| i as u8).collect(),
+        reliable: true,
+        msg_id: 7,
+        stream: None,
+        fragment: None,
+        connection_id: Some(3),
+        upgraded: MsgEncryption::Unencrypted
+    }
+}
+
+pub fn fragment_header(id: u32, index: u16, count: u16) -> FragmentHeader {
+    FragmentHeader {
+        id,
+        index,
+        count
+    }
+}
+
+/// A connection to an unused local address, on its own socket
+pub fn connection(state: UdpConnectionState) -> UdpConnection {
+    let sock = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = "127.0.0.1:9".parse().unwrap();
+    UdpConnection::new(state, address, Rc::new(sock), None, Rc::new(AsymmetricEncryption::new()))
+}
+
+/// A connection to a peer, whose keys have already been exchanged
+pub fn peer_connection() -> UdpConnection {
+    let mut conn = connection(UdpConnectionState::Connected);
+    conn.associated_peer = Some(AsymmetricEncryption::new().get_public_key());
+    conn.symmetric_key = Some(SymmetricEncryption::new());
+    conn.upgraded = true;
+    conn
+}
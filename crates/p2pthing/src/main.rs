@@ -4,10 +4,10 @@ mod server;
 
 #[cfg(any(feature = "tui", feature = "gui"))]
 use client::client::start_client;
-use p2pthing_common::ui::UIType;
+use p2pthing_common::{identity, ui::UIType};
 use server::rendezvous_server::RendezvousServer;
 
-use std::env;
+use std::{env, path::Path};
 
 pub fn main() {
     let args: Vec::<String> = env::args().collect();
@@ -45,6 +45,9 @@ pub fn main() {
         3 if args[1].starts_with("s") => {
             println!("Tried running as server with custom IP, but this is not supported");
         }
+        3 | 4 if args[1] == "key" => {
+            key_command(&args[2..]);
+        }
         _ => {
             println!("Invalid args."); //TODO: Display help
         }
@@ -62,4 +65,23 @@ fn init_server() {
     println!("Starting as server");
     #[cfg(feature = "server")]
     let _ = RendezvousServer::start_server();
+}
+
+/// Manage the identity key stored in the data directory
+fn key_command(args: &[String]) {
+    let result = match (args[0].as_str(), args.get(1)) {
+        ("generate", None) => identity::generate_identity()
+            .map(|identity| println!("Generated a new identity: {}", identity.get_public_key())),
+        ("import", Some(file)) => identity::import_identity(Path::new(file))
+            .map(|identity| println!("Imported identity: {}", identity.get_public_key())),
+        ("export", Some(file)) => identity::export_identity(Path::new(file))
+            .map(|_| println!("Exported identity to: {}", file)),
+        _ => {
+            println!("Invalid args. Usage: key generate | key import <file> | key export <file>");
+            return;
+        }
+    };
+    if let Err(e) = result {
+        println!("{}", e);
+    }
 }
\ No newline at end of file
@@ -2,7 +2,8 @@ use std::{collections::HashMap, env, net::SocketAddr, str::FromStr};
 //use scrap;
 use mio::{Interest, Poll, Token, net::UdpSocket};
 use mio::net::{TcpListener, TcpStream};
-use p2pthing_common::encryption::{AsymmetricEncryption, SymmetricEncryption};
+use p2pthing_common::encryption::{AsymmetricEncryption, EphemeralKeyExchange, NetworkedPublicKey, SymmetricEncryption};
+use p2pthing_common::{framing::FrameReader, identity};
 use p2pthing_common::message_type::{MsgType, Peer, msg_types};
 
 mod event_loop;
@@ -22,18 +23,35 @@ pub struct RendezvousServer {
     udp_listener: UdpSocket,
     addresses: HashMap<SocketAddr, Token>,
     tcp_connections: HashMap<Token, TcpStream>,
-    /// List of pending symmetric keys
-    sym_keys: HashMap<SocketAddr, SymmetricEncryption>,
+    /// The frames which have only partly arrived on each connection
+    frame_readers: HashMap<Token, FrameReader>,
+    /// List of ephemeral keys sent to peers, which haven't answered the key exchange yet
+    key_exchanges: HashMap<SocketAddr, EphemeralKeyExchange>,
+    /// List of pending symmetric keys, with the public key that signed the key exchange
+    sym_keys: HashMap<SocketAddr, (NetworkedPublicKey, SymmetricEncryption)>,
+    /// List of challenges sent in response to UDP announcements, which haven't been answered yet
+    udp_challenges: HashMap<NetworkedPublicKey, (SocketAddr, Vec<u8>)>,
     /// List of announced peers
     peers: Vec<Peer>,
     /// List of ongoing calls
     calls: Vec<CallRequest>,
+    /// Peers whose connection failed while sending to them, which still have to be disconnected
+    failed_peers: Vec<SocketAddr>,
     encryption: AsymmetricEncryption,
     next_msg_id: u32
 }
 
 impl RendezvousServer {
     pub fn start_server(){
+        let encryption = match identity::load_or_generate_server() {
+            Ok(encryption) => encryption,
+            Err(e) => {
+                println!("Failed to load the server's identity key: {}", e);
+                return;
+            }
+        };
+        println!("Server fingerprint: {}", encryption.get_public_key().fingerprint());
+
         let poll = Poll::new().unwrap();
         let mut next_token = 0;
 
@@ -53,8 +71,6 @@ impl RendezvousServer {
         poll.registry().register(&mut udp_listener, Token(next_token), Interest::READABLE).unwrap();
         next_token += 1;
         
-        let encryption = AsymmetricEncryption::new();
-        
         let mut s = RendezvousServer {
             poll,
             next_token,
@@ -62,9 +78,13 @@ impl RendezvousServer {
             udp_listener,
             addresses: HashMap::new(),
             tcp_connections: HashMap::new(),
+            frame_readers: HashMap::new(),
+            key_exchanges: HashMap::new(),
             sym_keys: HashMap::new(),
+            udp_challenges: HashMap::new(),
             peers: Vec::new(),
             calls: Vec::new(),
+            failed_peers: Vec::new(),
             encryption,
             next_msg_id: 0,
         };
@@ -75,26 +95,24 @@ impl RendezvousServer {
         println!("Peer ({}) disconnected", addr);
 
         // Notify other clients
-        let sock = self.tcp_connections.get(&token).unwrap().peer_addr().unwrap();
-        let peer = self.peers.iter_mut().find(|x| x.addr.unwrap() == addr);
+        let peer = self.peers.iter().position(|x| x.addr.unwrap() == addr);
         match peer {
-            Some(peer) => {
-                let p_key = peer.public_key.clone();
-                for c in self.tcp_connections.values_mut() {
-                    if c.peer_addr().unwrap() != sock // Only broadcast to other clients
-                    {
-                        RendezvousServer::send_tcp_message(c, MsgType::Disconnect, &msg_types::Disconnect{public_key: p_key.clone()});
-                    }
+            Some(i) => {
+                let p_key = self.peers.remove(i).public_key;
+                self.udp_challenges.remove(&p_key);
+                let others = self.peers.iter().map(|p| p.addr.unwrap()).collect::<Vec<_>>();
+                for other in others {
+                    self.send_tcp_message(other, MsgType::Disconnect, &msg_types::Disconnect{public_key: p_key.clone()});
                 }
-                self.peers.iter()
-                .position(|p| p.addr.unwrap() == addr)
-                .map(|i| self.peers.remove(i));
             }
             None => {} // The peer wasn't announced
         }
         // Remove from database
+        self.key_exchanges.remove(&addr);
+        self.sym_keys.remove(&addr);
         self.addresses.remove(&addr);
         self.tcp_connections.remove(&token);
+        self.frame_readers.remove(&token);
     }
     
     
@@ -1,7 +1,7 @@
-use std::io::{self, Read};
+use std::{io, net::SocketAddr};
 
 use mio::{Events, Interest, Token};
-use p2pthing_common::message_type::{MsgType, msg_types::AnnounceRequest};
+use p2pthing_common::{encryption::EphemeralKeyExchange, framing::FrameReader, message_type::{MsgType, msg_types::AnnounceRequest}, protocol::{Capabilities, PROTOCOL_VERSION, ProtocolError}};
 
 
 use super::RendezvousServer;
@@ -26,6 +26,7 @@ impl RendezvousServer {
                         self.read_tcp_events(token);
                     }
                 }
+                self.disconnect_failed_peers();
             }
         }
     }
@@ -34,18 +35,30 @@ impl RendezvousServer {
         loop {
             match self.tcp_listener.accept() {
                 Ok((mut sock, addr)) => {
-                    println!("Peer ({}) connected", sock.peer_addr().unwrap());
+                    println!("Peer ({}) connected", addr);
                     let token = Token(self.next_token);
                     self.next_token += 1;
 
                     self.poll.registry().register(&mut sock, token, Interest::READABLE).unwrap();
 
+                    let key_exchange = EphemeralKeyExchange::new();
                     let announce_request = AnnounceRequest {
                         public_key: self.encryption.get_public_key(),
+                        ephemeral_key: key_exchange.public_key(),
+                        signature: key_exchange.sign(&self.encryption, None),
+                        version: PROTOCOL_VERSION,
+                        // The server doesn't have optional features yet
+                        capabilities: Capabilities::empty()
                     };
-                    RendezvousServer::send_tcp_message(&mut sock, MsgType::AnnounceRequest, &announce_request);
+                    if let Err(e) = RendezvousServer::send_raw_tcp_message(&mut sock, MsgType::AnnounceRequest, &announce_request) {
+                        println!("Peer ({}) disconnected: {}", addr, e);
+                        let _ = self.poll.registry().deregister(&mut sock);
+                        continue;
+                    }
+                    self.key_exchanges.insert(addr, key_exchange);
 
                     self.tcp_connections.insert(token, sock);
+                    self.frame_readers.insert(token, FrameReader::new());
                     self.addresses.insert(addr, token);
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -56,6 +69,15 @@ impl RendezvousServer {
         }
     }
 
+    /// Disconnecting a peer notifies the others, which can fail too, so this runs until no failed peers are left
+    fn disconnect_failed_peers(&mut self) {
+        while let Some(addr) = self.failed_peers.pop() {
+            if let Some(token) = self.addresses.get(&addr).copied() {
+                self.on_disconnect(addr, token);
+            }
+        }
+    }
+
     fn read_udp_events(&mut self) {
         loop {
             let mut buf = [0; 65536];
@@ -72,28 +94,44 @@ impl RendezvousServer {
     }
 
     fn read_tcp_events(&mut self, token: Token) {
-        let mut msg_size = [0u8; 8];
+        let addr = match self.addresses.iter().find(|(_, t)| **t == token) {
+            Some((addr, _)) => *addr,
+            None => return
+        };
+        match self.read_tcp_frames(addr, token) {
+            Ok(()) => {}
+            Err(ProtocolError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => self.on_disconnect(addr, token),
+            // A malformed frame can't be skipped, since it may have desynchronized the stream
+            Err(e) => {
+                println!("Disconnecting peer ({}): {}", addr, e);
+                self.on_disconnect(addr, token);
+            }
+        }
+    }
+
+    /// Read from the socket until it would block, handling every frame which has fully arrived
+    fn read_tcp_frames(&mut self, addr: SocketAddr, token: Token) -> Result<(), ProtocolError> {
         loop {
-            let sock = self.tcp_connections.get_mut(&token).unwrap();
-            let addr = sock.peer_addr().unwrap();
-            match sock.read(&mut msg_size) {
-                Ok(0) => {
-                    self.on_disconnect(addr, token);
-                    break;
-                }
-                Ok(_) => {
-                    self.read_tcp_message(&msg_size, token);
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // Socket is not ready anymore, stop reading
-                    break;
+            let (sock, reader) = match (self.tcp_connections.get_mut(&token), self.frame_readers.get_mut(&token)) {
+                (Some(sock), Some(reader)) => (sock, reader),
+                // The peer has been disconnected while handling a frame
+                _ => return Ok(())
+            };
+            match reader.read_from(sock) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into())
+            }
+            loop {
+                let frame = match self.frame_readers.get_mut(&token) {
+                    Some(reader) => reader.next_frame()?,
+                    None => return Ok(())
+                };
+                match frame {
+                    Some(frame) => self.read_tcp_message(addr, &frame)?,
+                    None => break
                 }
-                e => {
-                    println!("Peer disconnected with an error={:?}", e);
-                    self.on_disconnect(addr, token);
-                    break;
-                },
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -1,86 +1,137 @@
 use std::{net::SocketAddr};
 
-use mio::Token;
-use p2pthing_common::{encryption::SymmetricEncryption, message_type::{MsgType, Peer, msg_types::{self, AnnouncePublic, AnnounceSecret, Call, CallResponse}}, read_exact};
+use p2pthing_common::{encryption::EphemeralKeyExchange, framing, message_type::{MsgType, Peer, msg_types::{self, AnnouncePublic, Call, CallResponse, KeyExchange, Rekey, RekeyAck}}, protocol::{self, Capabilities, Negotiated, ProtocolError}};
 
 use super::{CallRequest, RendezvousServer};
 
 impl RendezvousServer {
-    pub fn read_tcp_message(&mut self, msg_size: &[u8], token: Token) {
-        let sock = self.tcp_connections.get_mut(&token).unwrap();
-        let addr = sock.peer_addr().unwrap();
-
-        let msg_size: u64 = bincode::deserialize(msg_size).unwrap();
-        let mut encrypted = vec![0; msg_size as usize];
-        read_exact(sock, &mut encrypted[..]);
-
-        let mut msg = match self.sym_keys.get(&addr) {
-            Some(sym_key) => sym_key.decrypt(&mut encrypted[..]), // Peer has already announce, use the symmetric key
-            None => {
-                match self.peers.iter().find(|p| p.addr.unwrap() == addr) {
-                    Some(p) => p.sym_key.as_ref().unwrap().decrypt(&mut encrypted[..]),
-                    None => self.encryption.decrypt(&mut encrypted[..]) // Peer hasn't announced yet, use the asymmetric key
-                }
-            }
-        }; 
-        let msg_type = num::FromPrimitive::from_u8(msg[0]);
+    /// Handle a frame from the client. An error means that the client sent something malformed, so it should be disconnected.
+    pub fn read_tcp_message(&mut self, addr: SocketAddr, frame: &[u8]) -> Result<(), ProtocolError> {
+        let key = match self.sym_keys.get_mut(&addr) {
+            Some((_, sym_key)) => Some(sym_key), // Peer has already exchanged keys, use the symmetric key
+            None => self.peers.iter_mut().find(|p| p.addr.unwrap() == addr).map(|p| p.sym_key.as_mut().unwrap())
+        };
+        let unencrypted = key.is_none();
+        let msg = framing::open_frame(frame, key);
+        // Peer hasn't exchanged keys yet, so only the unencrypted key exchange is accepted
+        if unencrypted && msg.as_ref().ok().and_then(|msg| msg.first().cloned()) != num::ToPrimitive::to_u8(&MsgType::KeyExchange) {
+            println!("Dropped unencrypted message from ({})", addr);
+            return Ok(());
+        }
+        let msg = msg?;
+        let (msg_type, body) = protocol::split_message(&msg)?;
 
         match msg_type {
-            Some(MsgType::AnnounceSecret) => {
-                let announcement: msg_types::AnnounceSecret = bincode::deserialize(&mut msg[1..]).unwrap();
-                self.on_secret_announce(addr, announcement);
+            Some(MsgType::KeyExchange) => {
+                self.on_key_exchange(addr, protocol::decode(body)?);
             }
             Some(MsgType::Announce) => {
-                let announcement: msg_types::AnnouncePublic = bincode::deserialize(&mut msg[1..]).unwrap();
-                self.on_announce(addr, announcement);
+                self.on_announce(addr, protocol::decode(body)?);
             }
             Some(MsgType::Call) => {
-                let mut call: msg_types::Call = bincode::deserialize(&mut msg[1..]).unwrap();
-                self.on_call(addr, &mut call);
+                self.on_call(addr, &mut protocol::decode(body)?);
             }
             Some(MsgType::CallResponse) => {
-                let call_response: msg_types::CallResponse = bincode::deserialize(&mut msg[1..]).unwrap();
-                self.on_call_response(addr, call_response);
+                self.on_call_response(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::Rekey) => {
+                self.on_rekey(addr, protocol::decode(body)?);
             }
-            _ => unreachable!()
+            // Newer clients can send messages which we don't know about yet
+            _ => println!("Ignored an unexpected message of type {} from ({})", msg[0], addr)
         }
-        
+        Ok(())
     }
 
-    /// After receiving the secret, wait for the public key to arrive
-    fn on_secret_announce(&mut self, addr: SocketAddr, announcement: AnnounceSecret) {
-        let secret = SymmetricEncryption::new_from_secret(&announcement.secret[..]);
-
-        self.sym_keys.insert(addr, secret);
+    /// After finishing the key exchange, wait for the public key to arrive
+    fn on_key_exchange(&mut self, addr: SocketAddr, msg: KeyExchange) {
+        let key_exchange = match self.key_exchanges.remove(&addr) {
+            Some(key_exchange) => key_exchange,
+            None => {
+                println!("Peer ({}) sent a key exchange which wasn't requested", addr);
+                return;
+            }
+        };
+        let sym_key = EphemeralKeyExchange::verify(&msg.public_key, &msg.ephemeral_key, Some(&key_exchange.public_key()), &msg.signature)
+        .and_then(|_| key_exchange.derive(&msg.ephemeral_key));
+        match sym_key {
+            Ok(sym_key) => {
+                self.sym_keys.insert(addr, (msg.public_key, sym_key));
+            }
+            Err(e) => println!("Key exchange with peer ({}) failed: {}", addr, e)
+        }
     }
 
     fn on_announce(&mut self, addr: SocketAddr, announcement: AnnouncePublic) {
+        let sym_key = match self.sym_keys.remove(&addr) {
+            Some((public_key, sym_key)) if public_key == announcement.public_key => sym_key,
+            _ => {
+                println!("Peer ({}) announced a public key which wasn't used for the key exchange", addr);
+                return;
+            }
+        };
+        if self.peers.iter().any(|p| p.public_key == announcement.public_key) {
+            println!("Peer ({}) announced a public key which is already in use", addr);
+            return;
+        }
+        // Clients remember the key first seen with each name, so a name can't be shared by two keys
+        if self.peers.iter().any(|p| p.name == announcement.name) {
+            println!("Peer ({}) announced the name {}, which is already in use", addr, announcement.name);
+            return;
+        }
+        if let Err(e) = Negotiated::new(Capabilities::empty(), announcement.version, announcement.capabilities) {
+            println!("Peer ({}) can't be announced: {}", addr, e);
+            return;
+        }
         let p = Peer {
             addr: Some(addr),
             udp_addr: None,
             public_key: announcement.public_key,
-            sym_key: Some(self.sym_keys.remove(&addr).unwrap())
+            name: announcement.name,
+            sym_key: Some(sym_key)
         };
-        println!("Received public key for peer ({}): {}", p.addr.unwrap(), p.public_key);
+        println!("Received public key for peer {} ({}): {}", p.name, p.addr.unwrap(), p.public_key);
+
+        let known_peers = self.peers.iter().map(|x| x.safe_clone()).collect::<Vec<_>>();
+        let known_addresses = self.peers.iter().map(|x| x.addr.unwrap()).collect::<Vec<_>>();
+        let new_peer = [p.safe_clone()].to_vec();
+        self.peers.push(p);
 
         // Notify the new client of the connections
-        let sock = self.tcp_connections.iter_mut().find(|(_, c)| c.peer_addr().unwrap() == addr).unwrap().1;
-        RendezvousServer::send_tcp_message(sock, MsgType::Announce, &self.peers.to_vec().iter_mut().map(|x| x.safe_clone()).collect::<Vec<_>>());
+        self.send_tcp_message(addr, MsgType::Announce, &known_peers);
         
         // Notify everyone else of the new connection
-        for c in self.tcp_connections.values_mut().filter(|c| c.peer_addr().unwrap() != addr) {
-            RendezvousServer::send_tcp_message(c, MsgType::Announce, &[p.safe_clone()].to_vec());
+        for other in known_addresses {
+            self.send_tcp_message(other, MsgType::Announce, &new_peer);
+        }
+    }
+
+    /// Answer the client's rekey, switching to the new key once the client starts using it
+    fn on_rekey(&mut self, addr: SocketAddr, msg: Rekey) {
+        if !self.peers.iter().any(|p| p.addr == Some(addr)) {
+            println!("Peer ({}) tried rekeying before announcing itself", addr);
+            return;
+        }
+        let rekey = EphemeralKeyExchange::new();
+        let ack = RekeyAck {
+            ephemeral_key: rekey.public_key()
+        };
+        match rekey.derive_rekey(&msg.ephemeral_key, false) {
+            Ok((send_secret, recv_secret)) => {
+                self.send_tcp_message(addr, MsgType::RekeyAck, &ack);
+                let peer = self.peers.iter_mut().find(|p| p.addr == Some(addr)).unwrap();
+                peer.sym_key.as_mut().unwrap().stage_rekey(send_secret, recv_secret);
+                println!("Answered a rekey from peer ({})", addr);
+            }
+            Err(e) => println!("Rekey with peer ({}) failed: {}", addr, e)
         }
-        self.peers.push(p); 
     }
 
     fn on_call(&mut self, addr: SocketAddr, call: &mut Call) {
-        if let Some(caller) = self.peers.iter().find(|x| x.addr.unwrap() == addr) {
-            if let Some(callee) = self.peers.iter().find(|x| x.public_key == call.callee) {
+        if let Some(caller) = self.peers.iter().find(|x| x.addr.unwrap() == addr).cloned() {
+            if let Some(callee) = self.peers.iter().find(|x| x.public_key == call.callee).cloned() {
                 if caller.udp_addr.is_none() || callee.udp_addr.is_none() {
-                    let caller_token = self.addresses.get(&caller.addr.unwrap()).unwrap();
-                    let mut caller_socket = self.tcp_connections.get_mut(caller_token).unwrap();
-                    RendezvousServer::send_tcp_message(&mut caller_socket, MsgType::CallResponse, &CallResponse{ 
+                    self.send_tcp_message(caller.addr.unwrap(), MsgType::CallResponse, &CallResponse{ 
                         call: call.clone(), 
                         response: false
                     });
@@ -94,11 +145,9 @@ impl RendezvousServer {
                     self.calls.push(req);
                     // Don't trust the client
                     call.caller = Some(caller.clone().public_key);
-                    let token = self.addresses.get(&callee.addr.unwrap()).unwrap();
-                    let mut callee_socket = self.tcp_connections.get_mut(token).unwrap();
                     call.udp_address = caller.udp_addr;
-                    RendezvousServer::send_tcp_message(&mut callee_socket, MsgType::Call, &call);
-                    println!("Routed a call from ({}; {}) to ({}; {})", addr, caller.public_key, callee_socket.peer_addr().unwrap(), callee.public_key);
+                    self.send_tcp_message(callee.addr.unwrap(), MsgType::Call, &call);
+                    println!("Routed a call from ({}; {}) to ({}; {})", addr, caller.public_key, callee.addr.unwrap(), callee.public_key);
                 }
             }
             else {
@@ -110,27 +159,37 @@ impl RendezvousServer {
         }
     }
 
-    fn on_call_response(&mut self, _: SocketAddr, call_response: CallResponse) {
+    fn on_call_response(&mut self, addr: SocketAddr, call_response: CallResponse) {
         let callee = call_response.call.callee;
-        let caller = call_response.call.caller.unwrap();
+        let caller = match call_response.call.caller {
+            Some(caller) => caller,
+            None => {
+                println!("Peer ({}) answered a call without a caller", addr);
+                return;
+            }
+        };
         match self.calls.iter().position(|x| x.callee.public_key == callee && x.caller.public_key == caller) {
             Some(index) => {
                 if call_response.response {
                     println!("Peer ({}) accepted the call request from ({})", callee, caller);
                     
-                    let caller_peer = self.peers.iter().find(|p| p.public_key == caller).unwrap();
-                    let callee_peer = self.peers.iter().find(|p| p.public_key == callee).unwrap().clone();
-                    let mut sock = self.tcp_connections.values_mut().find(|x| x.peer_addr().unwrap() == caller_peer.addr.unwrap()).unwrap();
-                    
-                    let msg = msg_types::CallResponse {
-                        call: Call {
-                            callee,
-                            caller: Some(caller),
-                            udp_address: Some(callee_peer.udp_addr.unwrap())
-                        },
-                        response: call_response.response,
-                    };
-                    RendezvousServer::send_tcp_message(&mut sock, MsgType::CallResponse, &msg);
+                    let caller_addr = self.peers.iter().find(|p| p.public_key == caller).and_then(|p| p.addr);
+                    let callee_udp_addr = self.peers.iter().find(|p| p.public_key == callee).and_then(|p| p.udp_addr);
+                    match (caller_addr, callee_udp_addr) {
+                        (Some(caller_addr), Some(callee_udp_addr)) => {
+                            let msg = msg_types::CallResponse {
+                                call: Call {
+                                    callee,
+                                    caller: Some(caller),
+                                    udp_address: Some(callee_udp_addr)
+                                },
+                                response: call_response.response,
+                            };
+                            self.send_tcp_message(caller_addr, MsgType::CallResponse, &msg);
+                        }
+                        // One of them has disconnected since the call was routed
+                        _ => println!("Couldn't forward the call response from ({}) to ({})", callee, caller)
+                    }
                 }
                 else {
                     println!("Peer ({}) denied the call request from ({})", callee, caller);
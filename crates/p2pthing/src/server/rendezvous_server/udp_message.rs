@@ -1,31 +1,70 @@
 use std::net::SocketAddr;
 
-use p2pthing_common::message_type::{MsgType, UdpPacket, msg_types};
+use p2pthing_common::{message_type::{MsgType, msg_types}, protocol::{self, ProtocolError}};
 
 use super::RendezvousServer;
 
+/// Length of the random challenge sent to clients announcing their UDP address
+const CHALLENGE_LENGTH: usize = 32;
+
 impl RendezvousServer {
-    pub fn read_udp_message(&mut self, _: usize, addr: SocketAddr, buf: &[u8]) {
-        let udp_packet: UdpPacket = bincode::deserialize(&buf).unwrap();
+    pub fn read_udp_message(&mut self, size: usize, addr: SocketAddr, buf: &[u8]) {
+        // Datagrams can be spoofed, so a malformed one is only dropped
+        if let Err(e) = self.handle_udp_message(addr, &buf[..size]) {
+            println!("Dropped a malformed message from ({}): {}", addr, e);
+        }
+    }
 
-        let buf = udp_packet.data;
-        let msg_type = buf[0];
-        let msg_type = num::FromPrimitive::from_u8(msg_type);
+    fn handle_udp_message(&mut self, addr: SocketAddr, buf: &[u8]) -> Result<(), ProtocolError> {
+        let udp_packet = protocol::decode_udp_packet(buf)?;
+        let (msg_type, body) = protocol::split_message(&udp_packet.data)?;
 
         match msg_type {
             Some(MsgType::Announce) => {
-                let announce: msg_types::AnnouncePublic = bincode::deserialize(&buf[1..]).unwrap();
-                match self.peers.iter_mut().find(|p| p.public_key == announce.public_key) {
-                    Some(p) => {
-                        p.udp_addr = Some(addr);
-                        println!("Associated UDP adress ({}) with peer: ({})", addr, p.public_key);
-                        self.send_udp_message(addr, MsgType::Announce, &());
-                    },
-                    None => {}
-                }
+                self.on_udp_announce(addr, protocol::decode(body)?);
+            }
+            Some(MsgType::AnnounceProof) => {
+                self.on_announce_proof(addr, protocol::decode(body)?);
             }
             Some(MsgType::KeepAlive) => {}
-            _ => unreachable!()
+            // Newer clients can send messages which we don't know about yet
+            _ => println!("Ignored an unexpected message of type {} from ({})", udp_packet.data[0], addr)
+        }
+        Ok(())
+    }
+
+    /// Challenge the announcing client to prove that it owns the key, instead of trusting the source address
+    fn on_udp_announce(&mut self, addr: SocketAddr, announce: msg_types::AnnouncePublic) {
+        if !self.peers.iter().any(|p| p.public_key == announce.public_key) {
+            return;
+        }
+        // Announcements are resent until accepted, so keep the challenge until it's answered
+        let challenge = match self.udp_challenges.get(&announce.public_key) {
+            Some((challenge_addr, challenge)) if *challenge_addr == addr => challenge.clone(),
+            _ => {
+                let challenge = rand::random::<[u8; CHALLENGE_LENGTH]>().to_vec();
+                self.udp_challenges.insert(announce.public_key, (addr, challenge.clone()));
+                challenge
+            }
+        };
+        self.send_udp_message(addr, MsgType::AnnounceChallenge, &msg_types::AnnounceChallenge {challenge,});
+    }
+
+    fn on_announce_proof(&mut self, addr: SocketAddr, proof: msg_types::AnnounceProof) {
+        let valid = match self.udp_challenges.get(&proof.public_key) {
+            Some((challenge_addr, challenge)) => *challenge_addr == addr && proof.public_key.verify_challenge(challenge, &proof.signature),
+            None => false
+        };
+        if !valid {
+            println!("Received an invalid announce proof from ({})", addr);
+            return;
+        }
+        self.udp_challenges.remove(&proof.public_key);
+
+        if let Some(p) = self.peers.iter_mut().find(|p| p.public_key == proof.public_key) {
+            p.udp_addr = Some(addr);
+            println!("Associated UDP adress ({}) with peer: ({})", addr, p.public_key);
+            self.send_udp_message(addr, MsgType::Announce, &());
         }
     }
-}
\ No newline at end of file
+}
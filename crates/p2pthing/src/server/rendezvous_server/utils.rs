@@ -1,19 +1,39 @@
-use std::{io::Write, net::SocketAddr};
+use std::{io, net::SocketAddr};
 
 use mio::net::TcpStream;
-use p2pthing_common::message_type::{MsgEncryption, MsgType, UdpPacket};
+use p2pthing_common::{framing, message_type::{MsgEncryption, MsgType, UdpPacket}};
 use serde::Serialize;
 
 use super::RendezvousServer;
 
 impl RendezvousServer {
-    pub fn send_tcp_message<T: ?Sized>(sock: &mut TcpStream, t: MsgType, msg: &T) where T: Serialize {
-        let t: u8 = num::ToPrimitive::to_u8(&t).unwrap();
-        let msg = &bincode::serialize(msg).unwrap()[..];
-        let msg_size = bincode::serialize(&msg.len()).unwrap();
-        let chained: &[u8] = &[&[t], &msg_size[..], &msg].concat()[..];
+    /// Send a message to an announced peer, encrypted with its session key.
+    /// If the connection has failed, the peer is disconnected once the current event has been handled
+    pub fn send_tcp_message<T: ?Sized>(&mut self, addr: SocketAddr, t: MsgType, msg: &T) where T: Serialize {
+        if let Err(e) = self.write_tcp_message(addr, t, msg) {
+            println!("Failed sending a message to ({}): {}", addr, e);
+            if !self.failed_peers.contains(&addr) {
+                self.failed_peers.push(addr);
+            }
+        }
+    }
+
+    fn write_tcp_message<T: ?Sized>(&mut self, addr: SocketAddr, t: MsgType, msg: &T) -> io::Result<()> where T: Serialize {
+        let key = match self.peers.iter_mut().find(|p| p.addr == Some(addr)).and_then(|p| p.sym_key.as_mut()) {
+            Some(key) => key,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "The peer hasn't announced itself yet"))
+        };
+        let tcp_connections = &mut self.tcp_connections;
+        let sock = match self.addresses.get(&addr).and_then(|token| tcp_connections.get_mut(token)) {
+            Some(sock) => sock,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "The connection has already been closed"))
+        };
+        framing::write_frame(sock, t, msg, Some(key))
+    }
 
-        sock.write_all(chained).unwrap();
+    /// Send a message unencrypted, this is only used before the key exchange is finished
+    pub fn send_raw_tcp_message<T: ?Sized>(sock: &mut TcpStream, t: MsgType, msg: &T) -> io::Result<()> where T: Serialize {
+        framing::write_frame(sock, t, msg, None)
     }
 
     pub fn send_udp_message<T: ?Sized>(&mut self, addr: SocketAddr, t: MsgType, msg: &T) where T: Serialize {
@@ -25,9 +45,12 @@ impl RendezvousServer {
             data: chained.to_vec(),
             reliable: false, //FIXME
             msg_id: self.next_msg_id,
+            stream: None,
+            fragment: None,
+            connection_id: None,
             upgraded: MsgEncryption::Unencrypted
         };
-        self.next_msg_id += 1;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1);
 
         let wrapped_data = &bincode::serialize(&packet).unwrap()[..];
         self.udp_listener.send_to(wrapped_data, addr).unwrap();
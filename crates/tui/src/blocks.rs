@@ -1,6 +1,6 @@
 use std::{io::Stdout, time::Duration};
 
-use p2pthing_common::{debug_message::DebugMessageType, message_type::Peer, ui::{CHOOSABLE_KBITS, CallStatus}};
+use p2pthing_common::{debug_message::DebugMessageType, message_type::Peer, ui::{CHOOSABLE_KBITS, CallPolicy, CallStatus}};
 use tui::{Frame, backend::CrosstermBackend, layout::{Constraint, Direction, Layout, Rect}, style::{Color, Modifier, Style}, symbols::DOT, text::{Span, Spans, Text}, widgets::{Block, BorderType, Borders, List, ListItem, ListState, Paragraph, Tabs, Wrap}};
 
 use crate::tui::{ActiveBlock, Tui};
@@ -146,7 +146,11 @@ impl Tui{
     }
 
     pub fn contact_list(&mut self, f: &mut Frame<CrosstermBackend<Stdout>>, area: Rect) {
-        let contact_list = List::new(self.peers.iter().map(|p| ListItem::new(p.get_public_key().to_string().clone())).collect::<Vec<ListItem>>())
+        let contact_list = List::new(self.peers.iter().map(|p| match (p.key_changed, p.call_policy) {
+            (true, _) => ListItem::new(format!("! {}", p.get_name())).style(Style::default().fg(Color::Red)),
+            (false, CallPolicy::Block) => ListItem::new(p.get_name().to_string()).style(Style::default().fg(Color::DarkGray)),
+            (false, _) => ListItem::new(p.get_name().to_string())
+        }).collect::<Vec<ListItem>>())
         .block(Block::default().title("Contacts").borders(Borders::ALL)
         .border_style(Style::default().fg(self.get_fg_color(ActiveBlock::ContactList))))
         .highlight_style(Style::default().add_modifier(Modifier::BOLD))
@@ -181,7 +185,13 @@ impl Tui{
         .split(area);
 
         let mut spans: Vec<Spans> = vec![];
-        spans.push(Spans::from(Span::from(format!("{}\n", p.get_public_key().to_string()))));
+        spans.push(Spans::from(Span::from(format!("{} ({})\n", p.get_name(), p.get_public_key().to_string()))));
+        if p.key_changed {
+            spans.push(Spans::from(Span::styled("Key changed! Press (i) to verify\n", Style::default().fg(Color::Red).add_modifier(Modifier::BOLD))));
+        }
+        if p.call_policy != CallPolicy::Ask {
+            spans.push(Spans::from(Span::styled(format!("Incoming calls: {}\n", p.call_policy), Style::default().fg(Color::Yellow))));
+        }
         if let Some((_, stats)) = self.conn_stats.iter().find(|(p1, _)| p1 == p.get_public_key()) {
             spans.push(Spans::from(vec![
                 Span::from("Sent: "),
@@ -225,10 +235,17 @@ impl Tui{
             let lines = textwrap::wrap(msg, area.width as usize);
             for line in lines {
                 chat_items.push(ListItem::new(format!("{}\n", line)).style(match m.received {
+                    _ if !m.verified || m.failed => Style::default().fg(Color::Red),
                     Some(false) => Style::default().fg(Color::DarkGray),
                     _ => Style::default()
                 }));
             }
+            if !m.verified {
+                chat_items.push(ListItem::new("(unverified: the signature is invalid)\n").style(Style::default().fg(Color::Red).add_modifier(Modifier::ITALIC)));
+            }
+            if m.failed {
+                chat_items.push(ListItem::new("(failed to deliver: the peer didn't acknowledge it)\n").style(Style::default().fg(Color::Red).add_modifier(Modifier::ITALIC)));
+            }
         }
         self.chat_messages_length = chat_items.len();
 
@@ -243,6 +260,7 @@ impl Tui{
                 CallStatus::PunchThroughInProgress=> Span::styled(public_key, Style::default().fg(Color::Blue)),
                 CallStatus::SentRequest => Span::styled(public_key, Style::default().fg(Color::Yellow)),
                 CallStatus::RequestFailed => Span::styled(public_key, Style::default().fg(Color::Red)),
+                CallStatus::Reconnecting => Span::styled(title_string, Style::default().fg(Color::Magenta)),
             }
             None => Span::styled(public_key, Style::default().fg(Color::DarkGray))
         };
@@ -1,4 +1,4 @@
-use std::{sync::{atomic::Ordering, mpsc::TryRecvError}, thread};
+use std::{mem, sync::{atomic::Ordering, mpsc::TryRecvError}, thread};
 
 use chrono::Utc;
 use crossterm::event::{Event, KeyCode, KeyModifiers, read};
@@ -8,14 +8,19 @@ use p2pthing_common::{debug_message::{DebugMessage, DebugMessageType}, message_t
 use crate::tui::{ActiveBlock, TabIndex, Tui};
 
 use super::{popup::PopupReturn, ui_peer::{ChatMessage, UIPeer}};
-use super::popup::call_popup::CallPopup;
+use super::popup::{call_popup::CallPopup, contact_info_popup::ContactInfoPopup};
 
 impl Tui {
     pub fn handle_interthread_events(&mut self) {
         for msg in self.ui_r.try_iter() {
             match msg {
                 InterthreadMessage::AnnounceResponse(msg) => {
-                    self.peers = msg.iter().map(UIPeer::from).collect();
+                    // Keep the already known peers, so their state isn't lost
+                    for p in msg.iter() {
+                        if !self.peers.iter().any(|peer| peer.get_public_key() == &p.public_key) {
+                            self.peers.push(UIPeer::from(p));
+                        }
+                    }
                     match self.contact_list_state.selected() {
                         None if self.peers.len() > 0 => self.contact_list_state.select(Some(0)),
                         _ => {}
@@ -35,12 +40,13 @@ impl Tui {
                     }
                 },
                 InterthreadMessage::Call(public_key) => {
-                    if self.active_popup.is_some() {
-                        unimplemented!("Need to display a new popup while one is still displayed");
-                    }
-                    self.active_popup = Some(Box::new(CallPopup::new(
+                    // The call has to be answered first, the displaced popup is shown again afterwards
+                    let displaced = mem::replace(&mut self.active_popup, Some(Box::new(CallPopup::new(
                         public_key.clone()
-                    )));
+                    ))));
+                    if let Some(popup) = displaced {
+                        self.queued_popups.push(popup);
+                    }
                     match self.calls.iter_mut().find(|c| c.public_key == public_key) {
                         Some(call) => call.status = CallStatus::SentRequest,
                         None => self.calls.push(CallStatusHolder{
@@ -67,9 +73,22 @@ impl Tui {
                 InterthreadMessage::PunchThroughSuccessfull(public_key) => {
                     match self.calls.iter_mut().find(|c| c.public_key == public_key) {
                         Some(call) => call.status = CallStatus::PunchThroughSuccessfull,
-                        None => unreachable!()
+                        // A closed connection came back
+                        None => self.calls.push(CallStatusHolder{
+                            status: CallStatus::PunchThroughSuccessfull, 
+                            public_key: public_key.clone()
+                        })
+                    }
+                }
+                InterthreadMessage::ConnectionLost(public_key) => {
+                    if let Some(call) = self.calls.iter_mut().find(|c| c.public_key == public_key) {
+                        call.status = CallStatus::Reconnecting;
                     }
                 }
+                // The peer can be called again
+                InterthreadMessage::ConnectionClosed(public_key) => {
+                    self.calls.retain(|c| c.public_key != public_key);
+                }
                 // FIXME: Duplicated code
                 InterthreadMessage::DebugMessage(msg, msg_type) => {
                     self.debug_messages.push(DebugMessage {
@@ -79,14 +98,16 @@ impl Tui {
                     });
                     self.debug_messages_state.select(Some(self.debug_messages.len() - 1));
                 },
-                InterthreadMessage::OnChatMessage(p, msg) => {
+                InterthreadMessage::OnChatMessage(p, msg, verified) => {
                     let ui_peer = self.peers.iter_mut().find(|peer| peer.get_public_key() == &p.public_key).unwrap();
                     ui_peer.chat_messages.push(ChatMessage {
                         author: p,
                         msg,
                         custom_id: None,
                         received: None,
-                        own: false
+                        failed: false,
+                        own: false,
+                        verified
                     });
                 },
                 InterthreadMessage::OnChatMessageReceived(custom_id) => {
@@ -99,6 +120,16 @@ impl Tui {
                         }
                     }
                 },
+                InterthreadMessage::OnChatMessageFailed(custom_id) => {
+                    for p in &mut self.peers {
+                        for msg in &mut p.chat_messages {
+                            if msg.own && msg.custom_id.unwrap() == custom_id {
+                                msg.failed = true;
+                                break;
+                            }
+                        }
+                    }
+                },
                 InterthreadMessage::AudioNewInputDevices(devices) => {
                     self.settings_inputs = devices;
                     self.settings_inputs_state.select(None); //FIXME
@@ -110,6 +141,16 @@ impl Tui {
                 InterthreadMessage::ConnectionStatistics(stats) => {
                     self.conn_stats = stats;
                 }
+                InterthreadMessage::PeerKeyChanged(p) => {
+                    if let Some(ui_peer) = self.peers.iter_mut().find(|peer| peer.get_public_key() == &p.public_key) {
+                        ui_peer.key_changed = true;
+                    }
+                }
+                InterthreadMessage::CallPolicy(public_key, policy) => {
+                    if let Some(ui_peer) = self.peers.iter_mut().find(|peer| peer.get_public_key() == &public_key) {
+                        ui_peer.call_policy = policy;
+                    }
+                }
                 _ => unreachable!()
             }
         }
@@ -144,11 +185,14 @@ impl Tui {
                     udp_addr: None,
                     sym_key: None,
                     public_key: self.own_public_key.clone().unwrap(),
+                    name: String::new(),
                 },
                 msg: peer.chat_input.get_string(),
                 custom_id: Some(self.next_msg_id),
                 received: Some(false),
-                own: true
+                failed: false,
+                own: true,
+                verified: true
             });
             self.next_msg_id += 1;
             peer.chat_input.clear();
@@ -166,8 +210,20 @@ impl Tui {
                 let i = self.calls.iter_mut().position(|c| c.public_key == p).unwrap();
                 self.calls.remove(i);
             }
+            PopupReturn::TrustKey(p) => {
+                if let Some(ui_peer) = self.peers.iter_mut().find(|peer| peer.get_public_key() == &p.public_key) {
+                    ui_peer.key_changed = false;
+                }
+                self.cm_s.as_ref().unwrap().send(InterthreadMessage::TrustPeerKey(p)).unwrap();
+            }
+            PopupReturn::SetCallPolicy(p, policy) => {
+                self.cm_s.as_ref().unwrap().send(InterthreadMessage::CallPolicy(p, policy)).unwrap();
+                // Keep the popup open, so the policies can be cycled through
+                return;
+            }
+            PopupReturn::Close => {}
         }
-        self.active_popup = None;
+        self.active_popup = self.queued_popups.pop();
     }
 
     pub fn handle_keyboard_mouse_events(&mut self) {
@@ -456,6 +512,16 @@ impl Tui {
                             }
                         }
                     }
+                    KeyCode::Char('i') | KeyCode::Char('I') => {
+                        if let Some(p) = self.contact_list_state.selected().and_then(|i| self.peers.get(i)) {
+                            self.active_popup = Some(Box::new(ContactInfoPopup::new(
+                                p.get_peer().clone(),
+                                self.own_public_key.as_ref().unwrap(),
+                                p.key_changed,
+                                p.call_policy
+                            )));
+                        }
+                    }
                     KeyCode::Up => self.active_block = ActiveBlock::Tabs,
                     KeyCode::Right if !self.is_active && self.contact_list_state.selected().is_some() => self.active_block = ActiveBlock::ChatMessages,
                     _ => {}
@@ -1,14 +1,18 @@
 use std::io::Stdout;
 
 use crossterm::event::{Event};
-use p2pthing_common::encryption::NetworkedPublicKey;
+use p2pthing_common::{encryption::NetworkedPublicKey, message_type::Peer, ui::CallPolicy};
 use tui::{Frame, backend::CrosstermBackend, layout::Rect};
 
 pub mod call_popup;
+pub mod contact_info_popup;
 
 pub enum PopupReturn {
     AcceptCall(NetworkedPublicKey),
-    DenyCall(NetworkedPublicKey)
+    DenyCall(NetworkedPublicKey),
+    TrustKey(Peer),
+    SetCallPolicy(NetworkedPublicKey, CallPolicy),
+    Close
 }
 
 pub trait Popup {
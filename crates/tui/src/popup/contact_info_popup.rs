@@ -0,0 +1,106 @@
+use std::io::Stdout;
+
+use crossterm::event::{Event, KeyCode};
+use p2pthing_common::{encryption::NetworkedPublicKey, message_type::Peer, ui::CallPolicy};
+use tui::{Frame, backend::CrosstermBackend, layout::{Alignment, Constraint, Direction, Layout, Margin, Rect}, style::{Color, Modifier, Style}, text::{Span, Spans}, widgets::{Block, BorderType, Borders, Clear, Paragraph, Wrap}};
+
+use super::{Popup, PopupReturn};
+
+/// Shows the fingerprints and the safety number of a contact, so they can be verified out of band.
+/// The contact's call policy can be changed here as well.
+pub struct ContactInfoPopup {
+    peer: Peer,
+    own_fingerprint: String,
+    safety_number: String,
+    key_changed: bool,
+    call_policy: CallPolicy
+}
+
+impl Popup for ContactInfoPopup {
+    fn draw(&mut self, f: &mut Frame<CrosstermBackend<Stdout>>, area: Rect) {
+        let popup_area = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(20), Constraint::Percentage(60), Constraint::Percentage(20)])
+        .split(area);
+        let popup_area = tui::layout::Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(20), Constraint::Percentage(60), Constraint::Percentage(20)])
+        .split(popup_area[1]);
+
+        let container = Block::default().borders(Borders::ALL).border_type(BorderType::Rounded).title(format!("Contact info - {}", self.peer.name));
+        f.render_widget(Clear, popup_area[1]);
+        f.render_widget(container, popup_area[1]);
+
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let mut spans = vec![];
+        if self.key_changed {
+            spans.push(Spans::from(Span::styled("WARNING: This contact's key has changed since you first saw it!", Style::default().fg(Color::Red).add_modifier(Modifier::BOLD))));
+            spans.push(Spans::from(""));
+        }
+        spans.push(Spans::from(Span::styled("Their fingerprint:", bold)));
+        spans.push(Spans::from(self.peer.public_key.fingerprint()));
+        spans.push(Spans::from(""));
+        spans.push(Spans::from(Span::styled("Your fingerprint:", bold)));
+        spans.push(Spans::from(self.own_fingerprint.clone()));
+        spans.push(Spans::from(""));
+        spans.push(Spans::from(Span::styled("Safety number:", bold)));
+        spans.push(Spans::from(self.safety_number.clone()));
+        spans.push(Spans::from(""));
+        spans.push(Spans::from("Compare the safety number with your contact through another channel."));
+        spans.push(Spans::from(""));
+        spans.push(Spans::from(vec![
+            Span::styled("Incoming calls: ", bold),
+            Span::from(self.call_policy.to_string())
+        ]));
+
+        let inside = Layout::default().
+        direction(Direction::Vertical).
+        constraints([Constraint::Percentage(90), Constraint::Percentage(10)])
+        .split( popup_area[1].inner(&Margin {vertical: 2, horizontal: 2}));
+
+        let label = Paragraph::new(spans).alignment(Alignment::Center).wrap(Wrap {trim: true});
+        f.render_widget(label, inside[0]);
+
+        let help = match self.key_changed {
+            true => "(T)rust the new key - (P)olicy - (Esc) Close",
+            false => "(P)olicy - (Esc) Close"
+        };
+        let help = Paragraph::new(help).alignment(Alignment::Center).style(Style::default().fg(Color::Yellow));
+        f.render_widget(help, inside[1]);
+    }
+
+    fn handle_event(&mut self, e: Event) -> Option<PopupReturn> {
+        match e {
+            Event::Key(e) => {
+                match e.code {
+                    KeyCode::Esc | KeyCode::Enter | KeyCode::Char('i') | KeyCode::Char('I') => {
+                        return Some(PopupReturn::Close);
+                    }
+                    KeyCode::Char('t') | KeyCode::Char('T') if self.key_changed => {
+                        return Some(PopupReturn::TrustKey(self.peer.clone()));
+                    }
+                    KeyCode::Char('p') | KeyCode::Char('P') => {
+                        self.call_policy = self.call_policy.next();
+                        return Some(PopupReturn::SetCallPolicy(self.peer.public_key.clone(), self.call_policy));
+                    }
+                    _ => {}
+                };
+            }
+            Event::Mouse(_) => {}
+            Event::Resize(_, _) => {}
+        };
+        None
+    }
+}
+
+impl ContactInfoPopup {
+    pub fn new(peer: Peer, own_public_key: &NetworkedPublicKey, key_changed: bool, call_policy: CallPolicy) -> Self {
+        ContactInfoPopup {
+            own_fingerprint: own_public_key.fingerprint(),
+            safety_number: own_public_key.safety_number(&peer.public_key),
+            peer,
+            key_changed,
+            call_policy
+        }
+    }
+}
@@ -60,6 +60,8 @@ pub struct Tui {
     pub(crate) calls: Vec<CallStatusHolder>,
     pub(crate) next_msg_id: u32,
     pub(crate) active_popup: Option<Box<dyn Popup>>,
+    /// Popups which were displaced by a more recent one, shown again once it's closed
+    pub(crate) queued_popups: Vec<Box<dyn Popup>>,
     pub(crate) conn_stats: Vec<(NetworkedPublicKey, Statistics)>,
     /// Whether the debug panel is visible above the chat messages
     pub(crate) debug_visible: bool
@@ -105,14 +107,15 @@ impl Tui {
             calls: vec![],
             next_msg_id: 0,
             active_popup: None,
+            queued_popups: vec![],
             conn_stats: vec![],
             debug_visible: false
         }
     }
 
-    pub fn on_chat_message(s: &Sender<InterthreadMessage>, peer: Peer, msg: String) {
+    pub fn on_chat_message(s: &Sender<InterthreadMessage>, peer: Peer, msg: String, verified: bool) {
         s.log_info(&format!("Received chat message from: ({})", peer.public_key));
-        s.send(InterthreadMessage::OnChatMessage(peer, msg)).unwrap();
+        s.send(InterthreadMessage::OnChatMessage(peer, msg, verified)).unwrap();
     }
 }
 
@@ -1,5 +1,5 @@
 
-use p2pthing_common::{encryption::NetworkedPublicKey, message_type::Peer};
+use p2pthing_common::{encryption::NetworkedPublicKey, message_type::Peer, ui::CallPolicy};
 
 use super::chat_input::ChatInput;
 
@@ -8,13 +8,21 @@ pub struct ChatMessage {
     pub msg: String,
     pub custom_id: Option<u32>,
     pub received: Option<bool>,
-    pub own: bool
+    /// The peer never acknowledged our message
+    pub failed: bool,
+    pub own: bool,
+    /// The author's signature is valid, our own messages are always verified
+    pub verified: bool
 }
 
 pub struct UIPeer {
     inner: Peer,
     pub chat_input: ChatInput,
-    pub chat_messages: Vec<ChatMessage>
+    pub chat_messages: Vec<ChatMessage>,
+    /// The peer's key is different from the one first seen with its name
+    pub key_changed: bool,
+    /// How the peer's incoming calls are handled
+    pub call_policy: CallPolicy
 }
 
 impl UIPeer {
@@ -22,13 +30,23 @@ impl UIPeer {
         UIPeer {
             inner: p.clone(),
             chat_input: ChatInput::new(),
-            chat_messages: vec![]
+            chat_messages: vec![],
+            key_changed: false,
+            call_policy: CallPolicy::Ask
         }
     }
 
     pub fn get_public_key(&self) -> &NetworkedPublicKey {
         &self.inner.public_key
     }
+
+    pub fn get_name(&self) -> &str {
+        &self.inner.name
+    }
+
+    pub fn get_peer(&self) -> &Peer {
+        &self.inner
+    }
 }
 
 impl PartialEq for UIPeer {